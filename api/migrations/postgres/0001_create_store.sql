@@ -0,0 +1,23 @@
+-- Values expire at `expires_at`, in milliseconds since the epoch, when set
+CREATE TABLE store_values (
+	key TEXT PRIMARY KEY,
+	value TEXT NOT NULL,
+	expires_at BIGINT
+);
+CREATE INDEX store_values_expires_at ON store_values (expires_at);
+
+-- Lists are read in insertion order
+CREATE TABLE store_list_values (
+	id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+	key TEXT NOT NULL,
+	value TEXT NOT NULL
+);
+CREATE INDEX store_list_values_key ON store_list_values (key, id);
+
+CREATE TABLE store_rate_limits (
+	key TEXT PRIMARY KEY,
+	tokens DOUBLE PRECISION NOT NULL,
+	updated_at BIGINT NOT NULL,
+	expires_at BIGINT NOT NULL
+);
+CREATE INDEX store_rate_limits_expires_at ON store_rate_limits (expires_at);
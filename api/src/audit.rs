@@ -0,0 +1,68 @@
+use serde_derive::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{
+	memory_store::{MemoryStore, StoreResult},
+	rate_limit,
+};
+
+/// Store key of the audit trail
+pub static AUDIT_LOG_KEY: &str = "audit:log";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+	ImpersonationStarted,
+	ImpersonationStopped,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditEvent {
+	pub action: AuditAction,
+	/// Email of the user performing the action
+	pub actor: String,
+	/// Email of the user the action was performed on
+	pub target: String,
+	/// Milliseconds since the Unix epoch
+	pub timestamp: u64,
+}
+
+impl AuditEvent {
+	pub fn new(action: AuditAction, actor: &str, target: &str) -> Self {
+		Self {
+			action,
+			actor: actor.to_string(),
+			target: target.to_string(),
+			timestamp: rate_limit::now_ms(),
+		}
+	}
+}
+
+/// Append the event to the audit trail kept in the store, and log it under the `audit` target
+pub async fn record(memory_store: &dyn MemoryStore, event: AuditEvent) -> StoreResult {
+	info!(
+		target: "audit",
+		action = ?event.action,
+		actor = %event.actor,
+		target_user = %event.target,
+		"Audit event recorded"
+	);
+	let value = serde_json::to_string(&event)?;
+	memory_store
+		.push_value(AUDIT_LOG_KEY, &value)
+		.await
+		.map_err(|e| {
+			error!(target: "audit", "Unable to persist audit event {:?}: {}", event, e);
+			e
+		})
+}
+
+/// Get every event of the audit trail, oldest first
+pub async fn events(memory_store: &dyn MemoryStore) -> StoreResult<Vec<AuditEvent>> {
+	memory_store
+		.list_values(AUDIT_LOG_KEY)
+		.await?
+		.iter()
+		.map(|value| Ok(serde_json::from_str(value)?))
+		.collect()
+}
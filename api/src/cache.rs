@@ -0,0 +1,157 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+	collections::HashMap,
+	future::Future,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+use tracing::warn;
+
+use crate::memory_store::{SharedMemoryStore, StoreError, StoreResult};
+
+/// Prefix of every cache key, keeping them apart from the sessions and users
+const CACHE_PREFIX: &str = "cache";
+
+/// Locks of the keys being computed, so that concurrent misses compute a value only once
+type InFlight = Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>;
+
+/// Typed key-value cache with TTLs, kept on the same backend as the sessions.
+///
+/// Values are stored as JSON under `cache:{namespace}:{key}`, so that they are shared by every
+/// instance of the API. Each service should work in its own namespace
+#[derive(Clone, Debug)]
+pub struct Cache {
+	store: SharedMemoryStore,
+	prefix: String,
+	in_flight: InFlight,
+}
+
+impl Cache {
+	pub fn new(store: SharedMemoryStore) -> Self {
+		Self {
+			store,
+			prefix: CACHE_PREFIX.to_string(),
+			in_flight: InFlight::default(),
+		}
+	}
+
+	/// Cache whose keys are kept apart from the ones of other namespaces
+	pub fn namespace(&self, name: &str) -> Self {
+		Self {
+			store: self.store.clone(),
+			prefix: format!("{}:{}", self.prefix, name),
+			in_flight: self.in_flight.clone(),
+		}
+	}
+
+	fn key(&self, key: &str) -> String {
+		format!("{}:{}", self.prefix, key)
+	}
+
+	pub async fn get<T: DeserializeOwned>(&self, key: &str) -> StoreResult<Option<T>> {
+		match self.store.get_value(&self.key(key)).await? {
+			Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+			None => Ok(None),
+		}
+	}
+
+	pub async fn set<T: Serialize>(&self, key: &str, value: &T, ttl: Duration) -> StoreResult {
+		let json = serde_json::to_string(value)?;
+		self.store.set_value(&self.key(key), &json, Some(ttl)).await
+	}
+
+	pub async fn delete(&self, key: &str) -> StoreResult {
+		self.store.delete_value(&self.key(key)).await
+	}
+
+	/// Atomically add `delta` to the counter at `key`, which starts from 0 and expires `ttl`
+	/// after its first increment. Counters can be read with `get::<i64>`
+	pub async fn increment(&self, key: &str, delta: i64, ttl: Duration) -> StoreResult<i64> {
+		self.store
+			.increment_value(&self.key(key), delta, Some(ttl))
+			.await
+	}
+
+	/// Get the value at `key`, computing and caching it on a miss.
+	///
+	/// Concurrent misses on the same key within this process wait for a single computation.
+	/// Values that cannot be decoded anymore, i.e. after their type changed, are computed again
+	pub async fn get_or_compute<T, E, F, Fut>(
+		&self,
+		key: &str,
+		ttl: Duration,
+		compute: F,
+	) -> Result<T, E>
+	where
+		T: Serialize + DeserializeOwned,
+		E: From<StoreError>,
+		F: FnOnce() -> Fut,
+		Fut: Future<Output = Result<T, E>>,
+	{
+		if let Some(value) = self.get_decodable(key).await? {
+			return Ok(value);
+		}
+
+		let entry = InFlightEntry::new(&self.in_flight, self.key(key));
+		let _guard = entry.lock.lock().await;
+		// Another caller may have computed the value while this one was waiting
+		match self.get_decodable(key).await {
+			Ok(Some(value)) => Ok(value),
+			Ok(None) => match compute().await {
+				Ok(value) => self
+					.set(key, &value, ttl)
+					.await
+					.map(|_| value)
+					.map_err(E::from),
+				Err(e) => Err(e),
+			},
+			Err(e) => Err(E::from(e)),
+		}
+	}
+
+	async fn get_decodable<T: DeserializeOwned>(&self, key: &str) -> StoreResult<Option<T>> {
+		match self.get(key).await {
+			Err(StoreError::Serialization(e)) => {
+				warn!(
+					"Ignoring cached value {} that cannot be decoded: {}",
+					key, e
+				);
+				Ok(None)
+			}
+			result => result,
+		}
+	}
+}
+
+/// Share of a caller in the lock of a key being computed. The last caller out forgets the
+/// lock, even when its future is dropped while waiting or computing
+struct InFlightEntry {
+	in_flight: InFlight,
+	key: String,
+	lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl InFlightEntry {
+	fn new(in_flight: &InFlight, key: String) -> Self {
+		let lock = in_flight
+			.lock()
+			.unwrap()
+			.entry(key.clone())
+			.or_default()
+			.clone();
+		Self {
+			in_flight: in_flight.clone(),
+			key,
+			lock,
+		}
+	}
+}
+
+impl Drop for InFlightEntry {
+	fn drop(&mut self) {
+		let mut in_flight = self.in_flight.lock().unwrap();
+		if Arc::strong_count(&self.lock) == 2 {
+			in_flight.remove(&self.key);
+		}
+	}
+}
@@ -0,0 +1,204 @@
+use aes_gcm::{
+	aead::{Aead, AeadCore, KeyInit, OsRng},
+	Aes256Gcm, Key, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use derive_more::{Display, Error};
+
+/// Size of the AES-GCM nonce prepended to every ciphertext
+const NONCE_LENGTH: usize = 12;
+
+#[derive(Debug, Display, Error, PartialEq)]
+pub enum CryptoError {
+	#[display(fmt = "Encryption keys must be 32 bytes encoded in base64")]
+	InvalidKey,
+	#[display(fmt = "Unable to decrypt the value")]
+	Decryption,
+	#[display(fmt = "Keys must be given as `id:base64 key`, with unique ids")]
+	InvalidKeyId,
+}
+
+/// Authenticated encryption of values kept in the store, with AES-256-GCM
+#[derive(Clone)]
+pub struct Cipher {
+	cipher: Aes256Gcm,
+}
+
+impl Cipher {
+	pub fn new(key: &[u8; 32]) -> Self {
+		Self {
+			cipher: Aes256Gcm::new(&Key::<Aes256Gcm>::from(*key)),
+		}
+	}
+
+	/// Build a cipher from a base64 encoded 32 bytes key
+	pub fn from_base64(key: &str) -> Result<Self, CryptoError> {
+		let key: [u8; 32] = STANDARD
+			.decode(key.trim())
+			.map_err(|_| CryptoError::InvalidKey)?
+			.try_into()
+			.map_err(|_| CryptoError::InvalidKey)?;
+		Ok(Self::new(&key))
+	}
+
+	/// Encrypt with a random nonce, returning the base64 encoded nonce and ciphertext
+	pub fn encrypt(&self, plaintext: &[u8]) -> String {
+		let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+		let mut payload = nonce.to_vec();
+		payload.extend(
+			self.cipher
+				.encrypt(&nonce, plaintext)
+				.expect("AES-GCM encryption cannot fail for in-memory buffers"),
+		);
+		STANDARD.encode(payload)
+	}
+
+	/// Decrypt a value produced by `encrypt`, failing if it was tampered with
+	pub fn decrypt(&self, value: &str) -> Result<Vec<u8>, CryptoError> {
+		let payload = STANDARD
+			.decode(value)
+			.map_err(|_| CryptoError::Decryption)?;
+		if payload.len() < NONCE_LENGTH {
+			return Err(CryptoError::Decryption);
+		}
+		let (nonce, ciphertext) = payload.split_at(NONCE_LENGTH);
+		let nonce: [u8; NONCE_LENGTH] = nonce.try_into().map_err(|_| CryptoError::Decryption)?;
+		self.cipher
+			.decrypt(&Nonce::from(nonce), ciphertext)
+			.map_err(|_| CryptoError::Decryption)
+	}
+}
+
+impl std::fmt::Debug for Cipher {
+	// Never print key material
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("Cipher")
+	}
+}
+
+/// Ciphers identified by a key id, which tags every value they encrypt.
+/// The first key encrypts, the others are only kept to decrypt values encrypted before a rotation
+#[derive(Clone, Debug)]
+pub struct Keyring {
+	keys: Vec<(String, Cipher)>,
+}
+
+impl Keyring {
+	/// Build a keyring from `id:base64 key` entries, the current key first
+	pub fn from_entries(entries: &[String]) -> Result<Self, CryptoError> {
+		let mut keys: Vec<(String, Cipher)> = vec![];
+		for entry in entries {
+			let (id, key) = entry.split_once(':').ok_or(CryptoError::InvalidKeyId)?;
+			let id = id.trim();
+			if id.is_empty() || keys.iter().any(|(known, _)| known == id) {
+				return Err(CryptoError::InvalidKeyId);
+			}
+			keys.push((id.to_string(), Cipher::from_base64(key)?));
+		}
+		if keys.is_empty() {
+			return Err(CryptoError::InvalidKeyId);
+		}
+		Ok(Self { keys })
+	}
+
+	/// Encrypt with the current key, returning `id:ciphertext`
+	pub fn encrypt(&self, plaintext: &[u8]) -> String {
+		let (id, cipher) = &self.keys[0];
+		format!("{}:{}", id, cipher.encrypt(plaintext))
+	}
+
+	/// Decrypt a value produced by `encrypt` with any key of the keyring
+	pub fn decrypt(&self, value: &str) -> Result<Vec<u8>, CryptoError> {
+		let (id, ciphertext) = value.split_once(':').ok_or(CryptoError::Decryption)?;
+		let (_, cipher) = self
+			.keys
+			.iter()
+			.find(|(known, _)| known == id)
+			.ok_or(CryptoError::Decryption)?;
+		cipher.decrypt(ciphertext)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_cipher_round_trip() {
+		let cipher = Cipher::new(&[7; 32]);
+		let encrypted = cipher.encrypt(b"secret");
+		assert_ne!(encrypted, cipher.encrypt(b"secret"));
+		assert_eq!(cipher.decrypt(&encrypted).unwrap(), b"secret".to_vec());
+	}
+
+	#[test]
+	fn test_cipher_rejects_tampering_and_wrong_keys() {
+		let cipher = Cipher::new(&[7; 32]);
+		let mut payload = STANDARD.decode(cipher.encrypt(b"secret")).unwrap();
+		let last = payload.len() - 1;
+		payload[last] ^= 1;
+		assert_eq!(
+			cipher.decrypt(&STANDARD.encode(payload)),
+			Err(CryptoError::Decryption)
+		);
+		assert_eq!(
+			Cipher::new(&[8; 32]).decrypt(&cipher.encrypt(b"secret")),
+			Err(CryptoError::Decryption)
+		);
+		assert_eq!(cipher.decrypt("short"), Err(CryptoError::Decryption));
+	}
+
+	#[test]
+	fn test_cipher_from_base64() {
+		assert!(Cipher::from_base64(&STANDARD.encode([1; 32])).is_ok());
+		assert_eq!(
+			Cipher::from_base64(&STANDARD.encode([1; 16])).err(),
+			Some(CryptoError::InvalidKey)
+		);
+		assert_eq!(
+			Cipher::from_base64("not base64").err(),
+			Some(CryptoError::InvalidKey)
+		);
+	}
+
+	fn keys(entries: &[(&str, u8)]) -> Vec<String> {
+		entries
+			.iter()
+			.map(|(id, key)| format!("{}:{}", id, STANDARD.encode([*key; 32])))
+			.collect()
+	}
+
+	#[test]
+	fn test_keyring_rotation() {
+		let old = Keyring::from_entries(&keys(&[("old", 1)])).unwrap();
+		let encrypted = old.encrypt(b"secret");
+		assert!(encrypted.starts_with("old:"));
+
+		let rotated = Keyring::from_entries(&keys(&[("new", 2), ("old", 1)])).unwrap();
+		assert_eq!(rotated.decrypt(&encrypted).unwrap(), b"secret".to_vec());
+		let encrypted = rotated.encrypt(b"secret");
+		assert!(encrypted.starts_with("new:"));
+		assert_eq!(old.decrypt(&encrypted), Err(CryptoError::Decryption));
+		assert_eq!(rotated.decrypt("{}"), Err(CryptoError::Decryption));
+	}
+
+	#[test]
+	fn test_keyring_from_invalid_entries() {
+		assert_eq!(
+			Keyring::from_entries(&[]).err(),
+			Some(CryptoError::InvalidKeyId)
+		);
+		assert_eq!(
+			Keyring::from_entries(&[STANDARD.encode([1; 32])]).err(),
+			Some(CryptoError::InvalidKeyId)
+		);
+		assert_eq!(
+			Keyring::from_entries(&keys(&[("a", 1), ("a", 2)])).err(),
+			Some(CryptoError::InvalidKeyId)
+		);
+		assert_eq!(
+			Keyring::from_entries(&["a:short".to_string()]).err(),
+			Some(CryptoError::InvalidKey)
+		);
+	}
+}
@@ -0,0 +1,177 @@
+use async_session::{async_trait, Session, SessionStore};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::{
+	config::RateLimit,
+	crypto::Keyring,
+	memory_store::{
+		session_key_from_cookie, stored_session_key, MemoryStore, SharedMemoryStore, StoreError,
+		StoreMetrics, StoreResult, Subscription,
+	},
+	rate_limit::RateLimitDecision,
+	session_schema,
+};
+
+/// Prefixes of the keys holding personal data besides sessions: user profiles and the tokens
+/// of their providers
+const SEALED_PREFIXES: &[&str] = &["oauth_token:", "user:"];
+
+fn is_sealed(key: &str) -> bool {
+	SEALED_PREFIXES.iter().any(|prefix| key.starts_with(prefix))
+}
+
+/// Store keeping the sessions encrypted at rest, on top of any other store.
+///
+/// Sessions hold emails and provider profiles, so their values are sealed with the keyring
+/// before reaching the backend, like the values of the keys in [SEALED_PREFIXES]. Every other
+/// key only holds hashes, counters or short lived codes and is passed through untouched
+#[derive(Clone, Debug)]
+pub struct EncryptedStore {
+	store: SharedMemoryStore,
+	keyring: Keyring,
+}
+
+impl EncryptedStore {
+	pub fn new(store: SharedMemoryStore, keyring: Keyring) -> Self {
+		Self { store, keyring }
+	}
+
+	fn decrypt(&self, value: &str) -> Option<String> {
+		self.keyring
+			.decrypt(value)
+			.ok()
+			.and_then(|json| String::from_utf8(json).ok())
+	}
+
+	fn seal(&self, key: &str, value: &str) -> String {
+		match is_sealed(key) {
+			true => self.keyring.encrypt(value.as_bytes()),
+			false => value.to_string(),
+		}
+	}
+
+	/// Values stored before encryption was enabled, or with a retired key, are read as absent
+	fn open(&self, key: &str, value: Option<String>) -> Option<String> {
+		let value = value?;
+		if !is_sealed(key) {
+			return Some(value);
+		}
+		let opened = self.decrypt(&value);
+		if opened.is_none() {
+			warn!("Ignoring value of {} that cannot be decrypted", key);
+		}
+		opened
+	}
+}
+
+#[async_trait]
+impl SessionStore for EncryptedStore {
+	async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+		let Some(key) = session_key_from_cookie(&cookie_value) else {
+			debug!("Ignoring malformed session cookie");
+			return Ok(None);
+		};
+		let Some(value) = self.store.get_value(&key).await? else {
+			return Ok(None);
+		};
+		// Sessions stored before encryption was enabled, or with a retired key, are logged out
+		let Some(json) = self.decrypt(&value) else {
+			warn!("Ignoring session {} that cannot be decrypted", key);
+			return Ok(None);
+		};
+		match session_schema::decode_session(&json)? {
+			Some(decoded) => Ok(Some(decoded.write_back(self).await?)),
+			None => Ok(None),
+		}
+	}
+
+	async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+		debug!("Store encrypted session {:?}", session);
+		let key = stored_session_key(&session).ok_or(StoreError::MissingUser)?;
+		let value = session_schema::encode_session(&session)?;
+		self.store
+			.set_value(
+				&key,
+				&self.keyring.encrypt(value.as_bytes()),
+				session.expires_in(),
+			)
+			.await?;
+		Ok(Some(value))
+	}
+
+	async fn destroy_session(&self, session: Session) -> async_session::Result {
+		Ok(self.store.destroy_session(session).await?)
+	}
+
+	async fn clear_store(&self) -> async_session::Result {
+		Ok(self.store.clear_store().await?)
+	}
+}
+
+#[async_trait]
+impl MemoryStore for EncryptedStore {
+	async fn take_rate_limit_token(
+		&self,
+		key: &str,
+		limit: RateLimit,
+	) -> StoreResult<RateLimitDecision> {
+		self.store.take_rate_limit_token(key, limit).await
+	}
+
+	async fn get_value(&self, key: &str) -> StoreResult<Option<String>> {
+		Ok(self.open(key, self.store.get_value(key).await?))
+	}
+
+	async fn set_value(&self, key: &str, value: &str, ttl: Option<Duration>) -> StoreResult {
+		self.store.set_value(key, &self.seal(key, value), ttl).await
+	}
+
+	async fn set_value_if_absent(
+		&self,
+		key: &str,
+		value: &str,
+		ttl: Option<Duration>,
+	) -> StoreResult<bool> {
+		self.store
+			.set_value_if_absent(key, &self.seal(key, value), ttl)
+			.await
+	}
+
+	async fn delete_value(&self, key: &str) -> StoreResult {
+		self.store.delete_value(key).await
+	}
+
+	async fn increment_value(
+		&self,
+		key: &str,
+		delta: i64,
+		ttl: Option<Duration>,
+	) -> StoreResult<i64> {
+		self.store.increment_value(key, delta, ttl).await
+	}
+
+	async fn take_value(&self, key: &str) -> StoreResult<Option<String>> {
+		Ok(self.open(key, self.store.take_value(key).await?))
+	}
+
+	async fn push_value(&self, key: &str, value: &str) -> StoreResult {
+		self.store.push_value(key, value).await
+	}
+
+	async fn list_values(&self, key: &str) -> StoreResult<Vec<String>> {
+		self.store.list_values(key).await
+	}
+
+	async fn publish(&self, channel: &str, message: &str) -> StoreResult {
+		self.store.publish(channel, message).await
+	}
+
+	async fn subscribe(&self, channel: &str) -> StoreResult<Subscription> {
+		self.store.subscribe(channel).await
+	}
+
+	fn metrics(&self) -> Option<StoreMetrics> {
+		self.store.metrics()
+	}
+}
@@ -3,11 +3,31 @@ use derive_more::{Display, Error};
 use hyper::StatusCode;
 use serde_json::json;
 
+use crate::memory_store::StoreError;
+
 #[derive(Debug, Display, Error)]
 /// The app's top level error type.
 pub enum AppError {
+	#[display(fmt = "Resource already exists: {}", resource)]
+	Conflict { resource: String },
+	#[display(fmt = "You are not allowed to perform this action.")]
+	Forbidden,
 	#[display(fmt = "An internal error occurred. Please try again later.")]
 	InternalError,
+	#[display(fmt = "Resource not found: {}", resource)]
+	NotFound { resource: String },
+	#[display(fmt = "The login with {} was cancelled.", provider)]
+	OAuthCancelled { provider: String },
+	#[display(fmt = "The login with {} failed: {}", provider, error)]
+	OAuthProviderError { provider: String, error: String },
+	#[display(fmt = "The user profile returned by {} is invalid.", provider)]
+	OAuthInvalidUserInfo { provider: String },
+	#[display(fmt = "{} did not answer in time.", provider)]
+	OAuthTimeout { provider: String },
+	#[display(fmt = "The service is temporarily unavailable. Please try again later.")]
+	ServiceUnavailable,
+	#[display(fmt = "Authentication is required.")]
+	Unauthorized,
 	#[display(fmt = "Validation error on field: {}", field)]
 	ValidationError { field: String },
 }
@@ -16,6 +36,19 @@ impl IntoResponse for AppError {
 	fn into_response(self) -> Response<BoxBody> {
 		let (status, error_message) = match self {
 			AppError::ValidationError { .. } => (StatusCode::BAD_REQUEST, "invalid request"),
+			AppError::Conflict { .. } => (StatusCode::CONFLICT, "already exists"),
+			AppError::Forbidden => (StatusCode::FORBIDDEN, "forbidden"),
+			AppError::NotFound { .. } => (StatusCode::NOT_FOUND, "not found"),
+			AppError::OAuthCancelled { .. } => (StatusCode::UNAUTHORIZED, "login cancelled"),
+			AppError::OAuthProviderError { .. } => (StatusCode::BAD_GATEWAY, "login failed"),
+			AppError::OAuthInvalidUserInfo { .. } => {
+				(StatusCode::BAD_GATEWAY, "invalid user profile")
+			}
+			AppError::OAuthTimeout { .. } => (StatusCode::GATEWAY_TIMEOUT, "provider timeout"),
+			AppError::ServiceUnavailable => {
+				(StatusCode::SERVICE_UNAVAILABLE, "service unavailable")
+			}
+			AppError::Unauthorized => (StatusCode::UNAUTHORIZED, "unauthorized"),
 			AppError::InternalError => (StatusCode::INTERNAL_SERVER_ERROR, "oops"),
 		};
 
@@ -26,3 +59,12 @@ impl IntoResponse for AppError {
 		(status, body).into_response()
 	}
 }
+
+impl From<StoreError> for AppError {
+	fn from(e: StoreError) -> Self {
+		match e {
+			StoreError::Unavailable(_) => AppError::ServiceUnavailable,
+			_ => AppError::InternalError,
+		}
+	}
+}
@@ -0,0 +1,410 @@
+use async_session::{async_trait, Session, SessionStore};
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex, MutexGuard, Weak},
+	time::{Duration, Instant},
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::debug;
+
+use crate::{
+	config::RateLimit,
+	memory_store::{
+		session_key_from_cookie, stored_session_key, MemoryStore, StoreError, StoreMessage,
+		StoreResult, Subscription,
+	},
+	rate_limit::{self, RateLimitDecision, TokenBucket},
+	session_schema,
+};
+
+/// Error returned by Redis when a command does not match the type of the key it targets
+pub(crate) const WRONG_TYPE: &str =
+	"WRONGTYPE Operation against a key holding the wrong kind of value";
+/// Error returned by Redis when incrementing a value that is not an integer
+pub(crate) const NOT_AN_INTEGER: &str = "ERR value is not an integer or out of range";
+
+/// The types of keys the Redis store uses
+#[derive(Clone, Debug)]
+enum Value {
+	String(String),
+	List(Vec<String>),
+	Bucket(TokenBucket),
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+	value: Value,
+	expires_at: Option<Instant>,
+}
+
+impl Entry {
+	fn is_expired(&self, now: Instant) -> bool {
+		self.expires_at.is_some_and(|expires_at| expires_at <= now)
+	}
+}
+
+type Entries = HashMap<String, Entry>;
+
+/// Subscribers of each channel
+type Channels = HashMap<String, Vec<mpsc::UnboundedSender<StoreMessage>>>;
+
+/// Store keeping everything in the process memory, for development and single node deployments.
+///
+/// Keys are laid out as in Redis and commands follow the semantics of their Redis counterparts,
+/// so both stores can be swapped without any other change. Expired keys are never returned,
+/// and are removed either when accessed or by the sweeper
+#[derive(Clone, Debug, Default)]
+pub struct InMemoryStore {
+	entries: Arc<Mutex<Entries>>,
+	/// Messages only reach the subscribers of this process, like Redis messages reach the
+	/// subscribers of every instance
+	channels: Arc<Mutex<Channels>>,
+}
+
+impl InMemoryStore {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Periodically remove the expired keys. The task stops once the store is dropped
+	pub fn spawn_sweeper(&self, interval: Duration) -> JoinHandle<()> {
+		let entries = Arc::downgrade(&self.entries);
+		tokio::spawn(async move {
+			let mut ticker = tokio::time::interval(interval);
+			ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+			loop {
+				ticker.tick().await;
+				let Some(entries) = Weak::upgrade(&entries) else {
+					break;
+				};
+				let removed = sweep(&mut lock(&entries));
+				if removed > 0 {
+					debug!("Swept {} expired keys", removed);
+				}
+			}
+		})
+	}
+
+	/// Number of keys currently stored, expired or not
+	pub fn len(&self) -> usize {
+		self.entries().len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	fn entries(&self) -> MutexGuard<'_, Entries> {
+		lock(&self.entries)
+	}
+}
+
+/// A panic while holding the lock cannot leave the map half updated, so poisoning is ignored
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+	mutex
+		.lock()
+		.unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Remove every expired key, returning how many were removed
+fn sweep(entries: &mut Entries) -> usize {
+	let now = Instant::now();
+	let before = entries.len();
+	entries.retain(|_, entry| !entry.is_expired(now));
+	before - entries.len()
+}
+
+/// Get the entry at `key`, removing it first if it has expired
+fn live<'a>(entries: &'a mut Entries, key: &str) -> Option<&'a mut Entry> {
+	if entries
+		.get(key)
+		.is_some_and(|entry| entry.is_expired(Instant::now()))
+	{
+		entries.remove(key);
+	}
+	entries.get_mut(key)
+}
+
+fn get_string(entries: &mut Entries, key: &str) -> StoreResult<Option<String>> {
+	match live(entries, key) {
+		Some(Entry {
+			value: Value::String(value),
+			..
+		}) => Ok(Some(value.clone())),
+		Some(_) => Err(StoreError::Command(WRONG_TYPE.to_string())),
+		None => Ok(None),
+	}
+}
+
+#[async_trait]
+impl SessionStore for InMemoryStore {
+	async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+		debug!("Load session from cookie value {}", cookie_value);
+		let Some(key) = session_key_from_cookie(&cookie_value) else {
+			debug!("Ignoring malformed session cookie");
+			return Ok(None);
+		};
+		let json = get_string(&mut self.entries(), &key)?;
+		match json {
+			Some(json) => match session_schema::decode_session(&json)? {
+				Some(decoded) => Ok(Some(decoded.write_back(self).await?)),
+				None => Ok(None),
+			},
+			None => Ok(None),
+		}
+	}
+
+	async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+		debug!("Store session {:?}", session);
+		let key = stored_session_key(&session).ok_or(StoreError::MissingUser)?;
+		let value = session_schema::encode_session(&session)?;
+		// Expired sessions would not load anyway, the backend forgets them on time
+		self.set_value(&key, &value, session.expires_in()).await?;
+		Ok(Some(value))
+	}
+
+	async fn destroy_session(&self, session: Session) -> async_session::Result {
+		debug!("Destroy session {:?}", session);
+		if let Some(key) = stored_session_key(&session) {
+			self.entries().remove(&key);
+		}
+		Ok(())
+	}
+
+	async fn clear_store(&self) -> async_session::Result {
+		debug!("Clear all sessions");
+		self.entries().retain(|key, _| !key.starts_with("session:"));
+		Ok(())
+	}
+}
+
+#[async_trait]
+impl MemoryStore for InMemoryStore {
+	async fn take_rate_limit_token(
+		&self,
+		key: &str,
+		limit: RateLimit,
+	) -> StoreResult<RateLimitDecision> {
+		let now = rate_limit::now_ms();
+		// Idle buckets expire once they would be full again, like with the Redis script
+		let ttl = match limit.per_minute {
+			0 => Duration::from_secs(60),
+			per_minute => {
+				Duration::from_millis((limit.burst as u64 * 60_000).div_ceil(per_minute as u64))
+			}
+		};
+		let mut entries = self.entries();
+		let mut bucket = match live(&mut entries, key) {
+			Some(Entry {
+				value: Value::Bucket(bucket),
+				..
+			}) => *bucket,
+			Some(_) => return Err(StoreError::Command(WRONG_TYPE.to_string())),
+			None => TokenBucket::full(limit, now),
+		};
+		let decision = bucket.take(limit, now);
+		entries.insert(
+			key.to_string(),
+			Entry {
+				value: Value::Bucket(bucket),
+				expires_at: Some(Instant::now() + ttl),
+			},
+		);
+		Ok(decision)
+	}
+
+	async fn get_value(&self, key: &str) -> StoreResult<Option<String>> {
+		get_string(&mut self.entries(), key)
+	}
+
+	async fn set_value(&self, key: &str, value: &str, ttl: Option<Duration>) -> StoreResult {
+		if ttl.is_some_and(|ttl| ttl.as_millis() == 0) {
+			return Err(StoreError::Command(
+				"invalid expire time in 'psetex' command".to_string(),
+			));
+		}
+		self.entries().insert(
+			key.to_string(),
+			Entry {
+				value: Value::String(value.to_string()),
+				expires_at: ttl.map(|ttl| Instant::now() + ttl),
+			},
+		);
+		Ok(())
+	}
+
+	async fn set_value_if_absent(
+		&self,
+		key: &str,
+		value: &str,
+		ttl: Option<Duration>,
+	) -> StoreResult<bool> {
+		if ttl.is_some_and(|ttl| ttl.as_millis() == 0) {
+			return Err(StoreError::Command(
+				"invalid expire time in 'set' command".to_string(),
+			));
+		}
+		let mut entries = self.entries();
+		if live(&mut entries, key).is_some() {
+			return Ok(false);
+		}
+		entries.insert(
+			key.to_string(),
+			Entry {
+				value: Value::String(value.to_string()),
+				expires_at: ttl.map(|ttl| Instant::now() + ttl),
+			},
+		);
+		Ok(true)
+	}
+
+	async fn delete_value(&self, key: &str) -> StoreResult {
+		self.entries().remove(key);
+		Ok(())
+	}
+
+	async fn increment_value(
+		&self,
+		key: &str,
+		delta: i64,
+		ttl: Option<Duration>,
+	) -> StoreResult<i64> {
+		let mut entries = self.entries();
+		let (current, expires_at) = match live(&mut entries, key) {
+			Some(Entry {
+				value: Value::String(value),
+				expires_at,
+			}) => (
+				value
+					.parse::<i64>()
+					.map_err(|_| StoreError::Command(NOT_AN_INTEGER.to_string()))?,
+				*expires_at,
+			),
+			Some(_) => return Err(StoreError::Command(WRONG_TYPE.to_string())),
+			None => (0, None),
+		};
+		let value = current
+			.checked_add(delta)
+			.ok_or_else(|| StoreError::Command(NOT_AN_INTEGER.to_string()))?;
+		let expires_at = expires_at.or_else(|| {
+			ttl.filter(|ttl| !ttl.is_zero())
+				.map(|ttl| Instant::now() + ttl)
+		});
+		entries.insert(
+			key.to_string(),
+			Entry {
+				value: Value::String(value.to_string()),
+				expires_at,
+			},
+		);
+		Ok(value)
+	}
+
+	async fn take_value(&self, key: &str) -> StoreResult<Option<String>> {
+		let mut entries = self.entries();
+		let value = get_string(&mut entries, key)?;
+		if value.is_some() {
+			entries.remove(key);
+		}
+		Ok(value)
+	}
+
+	async fn push_value(&self, key: &str, value: &str) -> StoreResult {
+		let mut entries = self.entries();
+		match live(&mut entries, key) {
+			Some(Entry {
+				value: Value::List(values),
+				..
+			}) => values.push(value.to_string()),
+			Some(_) => return Err(StoreError::Command(WRONG_TYPE.to_string())),
+			None => {
+				entries.insert(
+					key.to_string(),
+					Entry {
+						value: Value::List(vec![value.to_string()]),
+						expires_at: None,
+					},
+				);
+			}
+		}
+		Ok(())
+	}
+
+	async fn list_values(&self, key: &str) -> StoreResult<Vec<String>> {
+		match live(&mut self.entries(), key) {
+			Some(Entry {
+				value: Value::List(values),
+				..
+			}) => Ok(values.clone()),
+			Some(_) => Err(StoreError::Command(WRONG_TYPE.to_string())),
+			None => Ok(vec![]),
+		}
+	}
+
+	async fn publish(&self, channel: &str, message: &str) -> StoreResult {
+		let mut channels = lock(&self.channels);
+		if let Some(subscribers) = channels.get_mut(channel) {
+			// Dropped subscriptions are forgotten on the way
+			subscribers.retain(|subscriber| {
+				subscriber
+					.send(StoreMessage::Message(message.to_string()))
+					.is_ok()
+			});
+		}
+		Ok(())
+	}
+
+	async fn subscribe(&self, channel: &str) -> StoreResult<Subscription> {
+		let (sender, receiver) = mpsc::unbounded_channel();
+		lock(&self.channels)
+			.entry(channel.to_string())
+			.or_default()
+			.push(sender);
+		Ok(receiver)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_publish_subscribe() {
+		let store = InMemoryStore::new();
+		// Nobody listens yet
+		store.publish("channel", "lost").await.unwrap();
+
+		let mut first = store.subscribe("channel").await.unwrap();
+		let second = store.subscribe("channel").await.unwrap();
+		let mut other = store.subscribe("other").await.unwrap();
+		drop(second);
+		store.publish("channel", "message").await.unwrap();
+		assert_eq!(
+			first.try_recv(),
+			Ok(StoreMessage::Message("message".to_string()))
+		);
+		assert!(first.try_recv().is_err());
+		assert!(other.try_recv().is_err());
+		assert_eq!(store.channels.lock().unwrap()["channel"].len(), 1);
+	}
+
+	#[tokio::test]
+	async fn test_sweeper_removes_expired_keys() {
+		let store = InMemoryStore::new();
+		store
+			.set_value("short", "value", Some(Duration::from_millis(10)))
+			.await
+			.unwrap();
+		store.set_value("long", "value", None).await.unwrap();
+		let sweeper = store.spawn_sweeper(Duration::from_millis(10));
+
+		tokio::time::sleep(Duration::from_millis(50)).await;
+		assert_eq!(store.len(), 1);
+
+		drop(store);
+		tokio::time::timeout(Duration::from_secs(1), sweeper)
+			.await
+			.expect("The sweeper should stop with the store")
+			.unwrap();
+	}
+}
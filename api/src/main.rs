@@ -1,30 +1,53 @@
-use axum::{response::IntoResponse, routing::get, Router};
-use memory_store::MemoryStore;
+use axum::{
+	middleware::{from_fn, from_fn_with_state},
+	response::IntoResponse,
+	routing::get,
+	Router,
+};
+use mailer::Mailer;
+use memory_store::SharedMemoryStore;
 use ngrok::prelude::*;
 use services::auth::{MultiOAuthConfig, MultiOAuthProvider, OAuthConfig, User};
 use std::{net::SocketAddr, sync::Arc};
 use tokio::signal;
 use tower_http::trace::{self, TraceLayer};
-use tracing::{debug, info, Level};
+use tracing::{debug, info, warn, Level};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
+pub mod audit;
+pub mod cache;
 pub mod config;
+pub mod crypto;
+pub mod encrypted_store;
 pub mod errors;
 pub mod handlers;
+pub mod in_memory_store;
+pub mod mailer;
 pub mod memory_store;
 pub mod middleware;
+pub mod near_cache;
+pub mod rate_limit;
+pub mod redis_connection;
 pub mod services;
+pub mod session_schema;
+pub mod sql_store;
+pub mod tokens;
 
 pub struct AppState {
+	/// Shared state of the services, on the same backend as the sessions
+	pub cache: cache::Cache,
 	pub config: Arc<config::Config>,
-	pub memory_store: Arc<dyn MemoryStore>,
+	pub mailer: Arc<dyn Mailer>,
+	pub memory_store: SharedMemoryStore,
 	pub oauth_providers: Arc<MultiOAuthProvider>,
 }
 
 impl Clone for AppState {
 	fn clone(&self) -> Self {
 		Self {
+			cache: self.cache.clone(),
 			config: self.config.clone(),
+			mailer: self.mailer.clone(),
 			oauth_providers: self.oauth_providers.clone(),
 			memory_store: self.memory_store.clone(),
 		}
@@ -35,7 +58,22 @@ impl Clone for AppState {
 #[cfg(not(tarpaulin_include))]
 pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
 	// Load configuration variables
-	let config = Arc::new(config::Config::from_env(&config::SystemEnvironment));
+	let args: Vec<String> = std::env::args().skip(1).collect();
+	if args.first().map(String::as_str) == Some("config") {
+		check_config(&args[1..]);
+	}
+	let (config, report) = match config::Config::load(config::SystemEnvironment, &args) {
+		Ok(loaded) => loaded,
+		Err(config::ConfigError::Invalid(report)) => {
+			eprint!("{}", report);
+			std::process::exit(1);
+		}
+		Err(e) => {
+			eprintln!("{}", e);
+			std::process::exit(1);
+		}
+	};
+	let config = Arc::new(config);
 	let api_address: SocketAddr = config.api_address;
 
 	debug!("Setting up logging...");
@@ -52,9 +90,69 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
 		.with(filter)
 		.with(tracing_subscriber::fmt::layer())
 		.init();
+	for warning in &report.warnings {
+		warn!("Configuration: {}", warning);
+	}
 
 	debug!("Loading Memory Store...");
-	let memory_store = Arc::new(memory_store::RedisStore::new(config.redis_url.to_string()).await);
+	let memory_store = match config.session_store.kind {
+		config::SessionStoreKind::Memory => {
+			info!("Keeping sessions in memory, they will be lost on restart");
+			let store = in_memory_store::InMemoryStore::new();
+			store.spawn_sweeper(config.session_store.sweep_interval);
+			SharedMemoryStore::new(store)
+		}
+		config::SessionStoreKind::Redis if config.redis_pool.degraded_start => {
+			warn!("Starting before Redis is reached, requests needing it fail until then");
+			SharedMemoryStore::new(memory_store::RedisStore::connect_in_background(
+				config.redis_url.to_string(),
+				&config.redis_key_prefix,
+				&config.redis_topology,
+				&config.redis_pool,
+			))
+		}
+		config::SessionStoreKind::Redis => SharedMemoryStore::new(
+			memory_store::RedisStore::new(
+				config.redis_url.to_string(),
+				&config.redis_key_prefix,
+				&config.redis_topology,
+				&config.redis_pool,
+			)
+			.await?,
+		),
+		config::SessionStoreKind::Sql => {
+			let store = sql_store::SqlStore::new(&config.session_store.database_url).await?;
+			store.spawn_sweeper(config.session_store.sweep_interval);
+			SharedMemoryStore::new(store)
+		}
+	};
+	let memory_store = if config.session_store.encryption_keys.is_empty() {
+		memory_store
+	} else {
+		let keyring = crypto::Keyring::from_entries(&config.session_store.encryption_keys)?;
+		info!("Encrypting the stored sessions");
+		SharedMemoryStore::new(encrypted_store::EncryptedStore::new(memory_store, keyring))
+	};
+	let memory_store = match config.session_store.near_cache_size {
+		0 => memory_store,
+		size => {
+			info!(
+				"Caching up to {} sessions for {:?} in front of the store",
+				size, config.session_store.near_cache_ttl
+			);
+			SharedMemoryStore::new(
+				near_cache::NearCacheStore::new(
+					memory_store,
+					size,
+					config.session_store.near_cache_ttl,
+				)
+				.await?,
+			)
+		}
+	};
+
+	debug!("Loading mailer...");
+	let mailer: Arc<dyn Mailer> = Arc::from(mailer::from_config(&config.mailer)?);
 
 	debug!("Loading OAuth providers...");
 	let oauth_providers = Arc::new(MultiOAuthProvider::new(MultiOAuthConfig {
@@ -72,7 +170,9 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 	debug!("Loading routes and global state...");
 	let app_state = AppState {
+		cache: cache::Cache::new(memory_store.clone()),
 		config,
+		mailer,
 		memory_store,
 		oauth_providers,
 	};
@@ -80,10 +180,14 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
 		.route("/", get(index))
 		.route("/health", get(handlers::health))
 		.route("/protected", get(protected))
-		.nest("/auth", services::auth::routes())
+		.nest(
+			"/auth",
+			services::auth::routes().layer(from_fn_with_state(app_state.clone(), rate_limit::auth)),
+		)
+		.nest("/admin", services::admin::routes())
 		.nest("/hello", services::hello::routes())
 		.nest("/goodbye", services::goodbye::routes())
-		.with_state(app_state);
+		.with_state(app_state.clone());
 
 	debug!("Loading middlewares...");
 	let app = app
@@ -92,7 +196,8 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
 				.make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
 				.on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
 		)
-		.layer(middleware::cors(api_address));
+		.layer(middleware::cors(&app_state.config.cors))
+		.layer(from_fn(middleware::impersonation_banner));
 
 	debug!("Adding fallback service for handling routes for unknown paths...");
 	let app = app.fallback(handlers::not_found);
@@ -109,17 +214,16 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
 			.await?;
 		info!("Starting server with ngrok on {}...", listener.url());
 		axum::Server::builder(listener)
-			.serve(app.into_make_service())
+			.serve(app.into_make_service_with_connect_info::<SocketAddr>())
 			.with_graceful_shutdown(shutdown_signal())
 			.await
 			.unwrap();
 	} else {
 		// If NGROK_AUTHTOKEN is not provided, start normally with the address given in the config
 		debug!("Running server without ngrok...");
-		let addr = SocketAddr::from(api_address);
 		info!("Starting server on {}...", api_address);
-		hyper::Server::bind(&addr)
-			.serve(app.into_make_service())
+		hyper::Server::bind(&api_address)
+			.serve(app.into_make_service_with_connect_info::<SocketAddr>())
 			.with_graceful_shutdown(shutdown_signal())
 			.await
 			.unwrap();
@@ -127,6 +231,33 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
 	Ok(())
 }
 
+/// `sabi config check [--config <path>]`: print every problem of the configuration, exiting with
+/// an error if the API could not start with it
+#[cfg(not(tarpaulin_include))]
+fn check_config(args: &[String]) -> ! {
+	if args.first().map(String::as_str) != Some("check") {
+		eprintln!("Usage: sabi config check [--config <path>]");
+		std::process::exit(2);
+	}
+	match config::Config::load(config::SystemEnvironment, &args[1..]) {
+		Ok((_, report)) => {
+			print!("{}", report);
+			println!("Configuration is valid");
+			std::process::exit(0);
+		}
+		Err(config::ConfigError::Invalid(report)) => {
+			print!("{}", report);
+			println!(
+				"Configuration is invalid: {} error(s), {} warning(s)",
+				report.errors.len(),
+				report.warnings.len()
+			);
+		}
+		Err(e) => eprintln!("{}", e),
+	}
+	std::process::exit(1);
+}
+
 #[cfg(not(tarpaulin_include))]
 async fn shutdown_signal() {
 	let ctrl_c = async {
@@ -158,8 +289,8 @@ async fn shutdown_signal() {
 async fn index(user: Option<User>) -> impl IntoResponse {
 	match user {
 		Some(u) => format!(
-			"Hey {}! You're logged in!\nYou may now access `/protected`.\nLog out with `/auth/logout`.\nYour {:?}",
-			u.email, u
+			"Hey {}! You're logged in!\nYou may now access `/protected`.\nYour profile is available at `/auth/me`.\nLog out with `/auth/logout`.",
+			u.display_name()
 		),
 		None => {
 			"You're not logged in.\nVisit `/auth/discord` or `/auth/google` to do so.".to_string()
@@ -170,7 +301,7 @@ async fn index(user: Option<User>) -> impl IntoResponse {
 // Valid user session required. If there is none, redirect to the auth page
 async fn protected(user: User) -> impl IntoResponse {
 	format!(
-		"Welcome to the protected area :)\nHere's your info:\n{:?}",
-		user
+		"Welcome to the protected area, {} :)\nYour profile is available at `/auth/me`.",
+		user.display_name()
 	)
 }
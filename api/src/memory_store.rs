@@ -1,118 +1,948 @@
-use async_session::{async_trait, Result, Session};
-use redis::{AsyncCommands, Client};
-use serde_json::Value;
-use std::sync::Arc;
-use tracing::debug;
+use async_session::{async_trait, Session, SessionStore};
+use derive_more::{Display, Error};
+use futures_util::StreamExt;
+use redis::{
+	cluster_routing::{Route, RoutingInfo, SingleNodeRoutingInfo},
+	AsyncCommands, RedisError, RedisResult, Script,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+	fmt,
+	future::Future,
+	ops::Deref,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, OnceLock,
+	},
+	time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
 
-use crate::services::auth::User;
+use crate::{
+	config::{RateLimit, RedisPoolConfig, RedisTopologyConfig},
+	near_cache::NearCacheMetrics,
+	rate_limit::{self, RateLimitDecision},
+	redis_connection::{self, RedisConnection, RedisPool},
+	session_schema,
+};
 
-// TODO - These methods are implemented from async_session::SessionStore, but it causes problems with the Arc if we set the SessionStore trait
+// Token bucket stored as a hash, refilled lazily on every call.
+// KEYS[1] = bucket key, ARGV = burst, tokens per millisecond, current time in milliseconds
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+local burst = tonumber(ARGV[1])
+local refill_per_ms = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated_at')
+local tokens = tonumber(bucket[1]) or burst
+local updated_at = tonumber(bucket[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - updated_at) * refill_per_ms)
+local allowed = 0
+local retry_after = 0
+if tokens >= 1 then
+	tokens = tokens - 1
+	allowed = 1
+elseif refill_per_ms > 0 then
+	retry_after = math.ceil((1 - tokens) / refill_per_ms)
+else
+	retry_after = 60000
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated_at', now)
+if refill_per_ms > 0 then
+	redis.call('PEXPIRE', KEYS[1], math.ceil(burst / refill_per_ms))
+else
+	redis.call('PEXPIRE', KEYS[1], 60000)
+end
+return {allowed, retry_after}
+"#;
+
+// Counter incremented by ARGV[1], expiring after ARGV[2] milliseconds unless it already expires.
+// KEYS[1] = counter key
+const INCREMENT_SCRIPT: &str = r#"
+local value = redis.call('INCRBY', KEYS[1], ARGV[1])
+if tonumber(ARGV[2]) > 0 and redis.call('PTTL', KEYS[1]) == -1 then
+	redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+return value
+"#;
+
+/// Error of the stores whose backend cannot broadcast messages to the other instances
+const PUBSUB_UNSUPPORTED: &str = "Publish and subscribe are not supported by this store";
+
+#[derive(Debug, Display, Error)]
+pub enum StoreError {
+	/// The backend cannot be reached, the request may succeed later
+	#[display(fmt = "Store is unavailable: {}", _0)]
+	Unavailable(#[error(not(source))] String),
+	#[display(fmt = "Store command failed: {}", _0)]
+	Command(#[error(not(source))] String),
+	#[display(fmt = "Invalid data in store: {}", _0)]
+	Serialization(serde_json::Error),
+	#[display(fmt = "Sessions must contain a user to be stored")]
+	MissingUser,
+}
+
+impl From<RedisError> for StoreError {
+	fn from(e: RedisError) -> Self {
+		if e.is_io_error()
+			|| e.is_connection_refusal()
+			|| e.is_connection_dropped()
+			|| e.is_timeout()
+		{
+			return StoreError::Unavailable(e.to_string());
+		}
+		StoreError::Command(e.to_string())
+	}
+}
+
+impl From<sqlx::Error> for StoreError {
+	fn from(e: sqlx::Error) -> Self {
+		match e {
+			sqlx::Error::Io(_)
+			| sqlx::Error::Tls(_)
+			| sqlx::Error::PoolTimedOut
+			| sqlx::Error::PoolClosed
+			| sqlx::Error::WorkerCrashed => StoreError::Unavailable(e.to_string()),
+			_ => StoreError::Command(e.to_string()),
+		}
+	}
+}
+
+impl From<serde_json::Error> for StoreError {
+	fn from(e: serde_json::Error) -> Self {
+		StoreError::Serialization(e)
+	}
+}
+
+/// Session stores report their errors through `async_session::Error`,
+/// which keeps the original error when it was a [StoreError]
+impl From<async_session::Error> for StoreError {
+	fn from(e: async_session::Error) -> Self {
+		match e.downcast::<StoreError>() {
+			Ok(e) => e,
+			Err(e) => StoreError::Command(e.to_string()),
+		}
+	}
+}
+
+pub type StoreResult<T = ()> = std::result::Result<T, StoreError>;
+
+/// Counters describing how a store talks to its backend
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StoreMetrics {
+	/// Connections kept open to the backend
+	pub connections: usize,
+	/// Commands waiting for an answer
+	pub in_flight: u64,
+	/// Commands sent since the start
+	pub commands: u64,
+	/// Commands that failed, timeouts excluded
+	pub errors: u64,
+	/// Commands that did not get an answer in time
+	pub timeouts: u64,
+	/// Sessions served from the process memory, when the near cache is enabled
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub near_cache: Option<NearCacheMetrics>,
+}
+
+/// What the subscribers of a channel receive
+#[derive(Clone, Debug, PartialEq)]
+pub enum StoreMessage {
+	/// A message published on the channel
+	Message(String),
+	/// Messages may have been missed, i.e. while the connection to the backend was down
+	Lagged,
+}
+
+/// Messages published on a channel, until the receiver is dropped
+pub type Subscription = tokio::sync::mpsc::UnboundedReceiver<StoreMessage>;
+
+/// Object safe counterpart of [SessionStore], which cannot be used as a trait object because it
+/// requires `Clone`. Every session store implements it, reporting its errors as [StoreError]
 #[async_trait]
-pub trait MemoryStore: Send + Sync {
-	/// Get a session from the storage backend.
-	///
-	/// The input is expected to be the value of an identifying
-	/// cookie. This will then be parsed by the session middleware
-	/// into a session if possible
-	async fn load_session(&self, cookie_value: String) -> Result<Option<Session>>;
+pub trait DynSessionStore: fmt::Debug + Send + Sync {
+	async fn load_session(&self, cookie_value: String) -> StoreResult<Option<Session>>;
+
+	async fn store_session(&self, session: Session) -> StoreResult<Option<String>>;
+
+	async fn destroy_session(&self, session: Session) -> StoreResult;
+
+	async fn clear_store(&self) -> StoreResult;
+}
+
+#[async_trait]
+impl<T: SessionStore> DynSessionStore for T {
+	async fn load_session(&self, cookie_value: String) -> StoreResult<Option<Session>> {
+		Ok(SessionStore::load_session(self, cookie_value).await?)
+	}
+
+	async fn store_session(&self, session: Session) -> StoreResult<Option<String>> {
+		Ok(SessionStore::store_session(self, session).await?)
+	}
+
+	async fn destroy_session(&self, session: Session) -> StoreResult {
+		Ok(SessionStore::destroy_session(self, session).await?)
+	}
+
+	async fn clear_store(&self) -> StoreResult {
+		Ok(SessionStore::clear_store(self).await?)
+	}
+}
 
-	/// Store a session on the storage backend.
+/// Sessions, through [SessionStore], and every other short lived data of the application
+#[async_trait]
+pub trait MemoryStore: DynSessionStore {
+	/// Take a token from the bucket identified by `key`.
 	///
-	/// The return value is the value of the cookie to store for the
-	/// user that represents this session
-	async fn store_session(&self, session: Session) -> Result<Option<String>>;
+	/// Buckets start full and are refilled according to `limit`.
+	/// The bucket state must be shared by every instance using the store
+	async fn take_rate_limit_token(
+		&self,
+		key: &str,
+		limit: RateLimit,
+	) -> StoreResult<RateLimitDecision>;
+
+	/// Get the value stored at `key`, if any
+	async fn get_value(&self, key: &str) -> StoreResult<Option<String>>;
+
+	/// Store `value` at `key`, expiring it after `ttl` when given
+	async fn set_value(&self, key: &str, value: &str, ttl: Option<Duration>) -> StoreResult;
+
+	/// Atomically store `value` at `key` unless it already holds a value, returning whether it
+	/// was stored
+	async fn set_value_if_absent(
+		&self,
+		key: &str,
+		value: &str,
+		ttl: Option<Duration>,
+	) -> StoreResult<bool>;
+
+	/// Remove the value stored at `key`, if any
+	async fn delete_value(&self, key: &str) -> StoreResult;
 
-	/// Remove a session from the session store
-	async fn destroy_session(&self, session: Session) -> Result;
+	/// Atomically add `delta` to the integer stored at `key`, starting from 0, and return the result.
+	/// `ttl` only applies when the key does not expire yet, so that counters keep their window
+	async fn increment_value(
+		&self,
+		key: &str,
+		delta: i64,
+		ttl: Option<Duration>,
+	) -> StoreResult<i64>;
 
-	/// Empties the entire store, destroying all sessions
-	async fn clear_store(&self) -> Result;
+	/// Atomically get and remove the value stored at `key`, so that it can only be taken once
+	async fn take_value(&self, key: &str) -> StoreResult<Option<String>>;
+
+	/// Append `value` to the end of the list stored at `key`
+	async fn push_value(&self, key: &str, value: &str) -> StoreResult;
+
+	/// Get every value of the list stored at `key`, oldest first
+	async fn list_values(&self, key: &str) -> StoreResult<Vec<String>>;
+
+	/// Send `message` to the subscribers of `channel`, on every instance using the store
+	async fn publish(&self, _channel: &str, _message: &str) -> StoreResult {
+		Err(StoreError::Command(PUBSUB_UNSUPPORTED.to_string()))
+	}
+
+	/// Receive the messages published on `channel` from now on
+	async fn subscribe(&self, _channel: &str) -> StoreResult<Subscription> {
+		Err(StoreError::Command(PUBSUB_UNSUPPORTED.to_string()))
+	}
+
+	/// Connection metrics, for the stores that have a backend to connect to
+	fn metrics(&self) -> Option<StoreMetrics> {
+		None
+	}
 }
 
+/// Handle on the memory store of the application, cheap to clone and usable wherever
+/// the ecosystem expects a [SessionStore]
 #[derive(Clone, Debug)]
+pub struct SharedMemoryStore(Arc<dyn MemoryStore>);
+
+impl SharedMemoryStore {
+	pub fn new(store: impl MemoryStore + 'static) -> Self {
+		Self(Arc::new(store))
+	}
+}
+
+impl From<Arc<dyn MemoryStore>> for SharedMemoryStore {
+	fn from(store: Arc<dyn MemoryStore>) -> Self {
+		Self(store)
+	}
+}
+
+impl Deref for SharedMemoryStore {
+	type Target = dyn MemoryStore;
+
+	fn deref(&self) -> &Self::Target {
+		self.0.as_ref()
+	}
+}
+
+impl AsRef<dyn MemoryStore> for SharedMemoryStore {
+	fn as_ref(&self) -> &(dyn MemoryStore + 'static) {
+		self.0.as_ref()
+	}
+}
+
+#[async_trait]
+impl SessionStore for SharedMemoryStore {
+	async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+		Ok(self.0.load_session(cookie_value).await?)
+	}
+
+	async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+		Ok(self.0.store_session(session).await?)
+	}
+
+	async fn destroy_session(&self, session: Session) -> async_session::Result {
+		Ok(self.0.destroy_session(session).await?)
+	}
+
+	async fn clear_store(&self) -> async_session::Result {
+		Ok(self.0.clear_store().await?)
+	}
+}
+
+/// Redis Cluster hash tag of a user. Keys embedding it are kept on the same slot,
+/// so that the operations on a single user can span several keys
+pub fn user_hash_tag(email: &str) -> String {
+	format!("{{{}}}", email.to_lowercase())
+}
+
+pub(crate) fn session_key(email: &str, session_id: &str) -> String {
+	format!("session:{}:{}", user_hash_tag(email), session_id)
+}
+
+/// The part of the session user its key is derived from, which does not depend on how
+/// the rest of the user deserializes
+#[derive(Deserialize)]
+struct SessionOwner {
+	email: String,
+}
+
+/// Key of a stored session, which must belong to a user
+pub(crate) fn stored_session_key(session: &Session) -> Option<String> {
+	let owner = session.get::<SessionOwner>("user")?;
+	Some(session_key(&owner.email, session.id()))
+}
+
+/// Build the store key of the session a cookie refers to. The cookie holds the whole session,
+/// in the layout it was stored with
+pub(crate) fn session_key_from_cookie(cookie_value: &str) -> Option<String> {
+	let decoded = session_schema::decode_session(cookie_value).ok()??;
+	stored_session_key(&decoded.session)
+}
+
+/// Escape the characters SCAN patterns give a meaning to
+fn escape_pattern(value: &str) -> String {
+	let mut escaped = String::with_capacity(value.len());
+	for c in value.chars() {
+		if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+			escaped.push('\\');
+		}
+		escaped.push(c);
+	}
+	escaped
+}
+
+#[derive(Debug, Default)]
+struct RedisMetrics {
+	in_flight: AtomicU64,
+	commands: AtomicU64,
+	errors: AtomicU64,
+	timeouts: AtomicU64,
+}
+
+/// Counts a command as in flight until dropped, so that cancelled requests are accounted for
+struct InFlight<'a>(&'a AtomicU64);
+
+impl<'a> InFlight<'a> {
+	fn start(counter: &'a AtomicU64) -> Self {
+		counter.fetch_add(1, Ordering::Relaxed);
+		Self(counter)
+	}
+}
+
+impl Drop for InFlight<'_> {
+	fn drop(&mut self) {
+		self.0.fetch_sub(1, Ordering::Relaxed);
+	}
+}
+
+/// Delay before the first retry when Redis cannot be reached at startup, doubled on every attempt
+const STARTUP_BACKOFF: Duration = Duration::from_millis(100);
+const STARTUP_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Redis backed store, spreading commands over a few multiplexed connections.
+/// Lost connections are re-established in the background
+#[derive(Clone)]
 pub struct RedisStore {
-	redis_client: Arc<Client>,
+	/// Set once connected, commands fail as unavailable until then
+	pool: Arc<OnceLock<Arc<RedisPool>>>,
+	/// Prepended to every key, so that several applications can share a database
+	key_prefix: Arc<String>,
+	command_timeout: Duration,
+	metrics: Arc<RedisMetrics>,
+}
+
+impl fmt::Debug for RedisStore {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("RedisStore")
+			.field("connections", &self.connections())
+			.field("key_prefix", &self.key_prefix)
+			.field("command_timeout", &self.command_timeout)
+			.finish()
+	}
 }
 
 impl RedisStore {
-	pub async fn new(connection_url: String) -> Self {
-		let client = redis::Client::open(connection_url).unwrap();
+	/// Connect to Redis, retrying with backoff until `startup_timeout` elapsed
+	pub async fn new(
+		connection_url: String,
+		key_prefix: &str,
+		topology: &RedisTopologyConfig,
+		pool: &RedisPoolConfig,
+	) -> StoreResult<Self> {
+		let store = Self::disconnected(key_prefix, pool);
+		let deadline = Instant::now() + pool.startup_timeout;
+		store
+			.connect(&connection_url, topology, pool, Some(deadline))
+			.await?;
+		Ok(store)
+	}
 
-		// Test the connection
-		let mut con = client.get_async_connection().await.unwrap();
-		let _: () = con.set("test_key", "test_value").await.unwrap();
-		let result: String = con.get("test_key").await.unwrap();
-		println!("Redis test_key: {}", result);
+	/// Start without waiting for Redis, which keeps being retried in the background.
+	/// Commands fail as unavailable until the store is connected
+	pub fn connect_in_background(
+		connection_url: String,
+		key_prefix: &str,
+		topology: &RedisTopologyConfig,
+		pool: &RedisPoolConfig,
+	) -> Self {
+		let store = Self::disconnected(key_prefix, pool);
+		let background = store.clone();
+		let topology = topology.clone();
+		let pool = pool.clone();
+		tokio::spawn(async move {
+			// Without deadline, only a successful connection ends the retries
+			let _ = background
+				.connect(&connection_url, &topology, &pool, None)
+				.await;
+		});
+		store
+	}
 
+	fn disconnected(key_prefix: &str, pool: &RedisPoolConfig) -> Self {
 		Self {
-			redis_client: Arc::new(client),
+			pool: Arc::new(OnceLock::new()),
+			key_prefix: Arc::new(key_prefix.to_string()),
+			command_timeout: pool.command_timeout,
+			metrics: Arc::new(RedisMetrics::default()),
+		}
+	}
+
+	async fn connect(
+		&self,
+		connection_url: &str,
+		topology: &RedisTopologyConfig,
+		config: &RedisPoolConfig,
+		deadline: Option<Instant>,
+	) -> StoreResult {
+		let mut backoff = STARTUP_BACKOFF;
+		let mut attempt = 1;
+		loop {
+			match Self::open_pool(connection_url, topology, config).await {
+				Ok(pool) => {
+					info!(
+						"Redis answered on {} {:?} connections",
+						pool.len(),
+						topology.kind
+					);
+					let _ = self.pool.set(Arc::new(pool));
+					return Ok(());
+				}
+				Err(e) => {
+					let delay = match deadline {
+						Some(deadline) => {
+							backoff.min(deadline.saturating_duration_since(Instant::now()))
+						}
+						None => backoff,
+					};
+					if delay.is_zero() {
+						error!(
+							"Unable to connect to Redis after {} attempts: {}",
+							attempt, e
+						);
+						return Err(e);
+					}
+					warn!(
+						"Unable to connect to Redis on attempt {}, retrying in {:?}: {}",
+						attempt, delay, e
+					);
+					tokio::time::sleep(delay).await;
+					backoff = (backoff * 2).min(STARTUP_MAX_BACKOFF);
+					attempt += 1;
+				}
+			}
+		}
+	}
+
+	/// Open the connections and check that Redis answers, without writing anything
+	async fn open_pool(
+		connection_url: &str,
+		topology: &RedisTopologyConfig,
+		config: &RedisPoolConfig,
+	) -> StoreResult<RedisPool> {
+		let pool = RedisPool::connect(connection_url, topology, config).await?;
+		let mut con = pool.connection();
+		let ping = redis::cmd("PING");
+		tokio::time::timeout(config.command_timeout, ping.query_async::<_, ()>(&mut con))
+			.await
+			.map_err(|_| StoreError::Unavailable("no answer to PING".to_string()))??;
+		Ok(pool)
+	}
+
+	fn connection(&self) -> StoreResult<RedisConnection> {
+		match self.pool.get() {
+			Some(pool) => Ok(pool.connection()),
+			None => Err(StoreError::Unavailable(
+				"still connecting to Redis".to_string(),
+			)),
+		}
+	}
+
+	/// Key under which Redis holds the given store key
+	fn key(&self, key: &str) -> String {
+		format!("{}{}", self.key_prefix, key)
+	}
+
+	fn connections(&self) -> usize {
+		self.pool.get().map_or(0, |pool| pool.len())
+	}
+
+	fn check_master(&self) {
+		if let Some(pool) = self.pool.get() {
+			pool.check_master();
+		}
+	}
+
+	/// Forward the messages published on `channel` until the subscription is dropped,
+	/// subscribing again whenever the connection is lost
+	async fn forward_messages(self, channel: String, sender: mpsc::UnboundedSender<StoreMessage>) {
+		let mut backoff = STARTUP_BACKOFF;
+		// Whether messages may have been missed since the subscription was requested
+		let mut lagged = false;
+		loop {
+			let subscription = match self.pool.get() {
+				Some(pool) => pool.subscribe(&channel).await.map_err(StoreError::from),
+				None => Err(StoreError::Unavailable(
+					"still connecting to Redis".to_string(),
+				)),
+			};
+			match subscription {
+				Ok(pubsub) => {
+					backoff = STARTUP_BACKOFF;
+					if lagged && sender.send(StoreMessage::Lagged).is_err() {
+						return;
+					}
+					let mut messages = pubsub.into_on_message();
+					loop {
+						tokio::select! {
+							message = messages.next() => {
+								let Some(message) = message else {
+									break;
+								};
+								let Ok(payload) = message.get_payload::<String>() else {
+									continue;
+								};
+								if sender.send(StoreMessage::Message(payload)).is_err() {
+									return;
+								}
+							}
+							_ = sender.closed() => return,
+						}
+					}
+					warn!("Lost the subscription to {}, subscribing again", channel);
+				}
+				Err(e) => debug!("Unable to subscribe to {}: {}", channel, e),
+			}
+			lagged = true;
+			tokio::select! {
+				_ = tokio::time::sleep(backoff) => {}
+				_ = sender.closed() => return,
+			}
+			backoff = (backoff * 2).min(STARTUP_MAX_BACKOFF);
+		}
+	}
+
+	/// Wait for the answer to a command, keeping the metrics up to date
+	async fn run<T>(&self, command: impl Future<Output = RedisResult<T>>) -> StoreResult<T> {
+		self.metrics.commands.fetch_add(1, Ordering::Relaxed);
+		let _in_flight = InFlight::start(&self.metrics.in_flight);
+		match tokio::time::timeout(self.command_timeout, command).await {
+			Ok(Ok(value)) => Ok(value),
+			Ok(Err(e)) => {
+				self.metrics.errors.fetch_add(1, Ordering::Relaxed);
+				if redis_connection::is_server_failure(&e) {
+					self.check_master();
+				}
+				Err(e.into())
+			}
+			Err(_) => {
+				self.metrics.timeouts.fetch_add(1, Ordering::Relaxed);
+				self.check_master();
+				Err(StoreError::Unavailable(format!(
+					"no answer within {:?}",
+					self.command_timeout
+				)))
+			}
 		}
 	}
 }
 
 #[async_trait]
-impl MemoryStore for RedisStore {
+impl SessionStore for RedisStore {
 	async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
 		debug!("Load session from cookie value {}", cookie_value);
-		let json_value: Value = serde_json::from_str(&cookie_value).unwrap();
-		let session_id = json_value["id"].as_str().unwrap().to_string();
-		let user: User =
-			serde_json::from_str(json_value["data"]["user"].as_str().unwrap()).unwrap();
-		let key = format!("session:{}:{}", user.email, session_id);
-		let mut con = self.redis_client.get_async_connection().await.unwrap();
-		let session_json: Option<String> = con.get(&key).await.unwrap();
+		// Cookies come from clients, malformed or tampered ones are no session at all
+		let Some(key) = session_key_from_cookie(&cookie_value) else {
+			debug!("Ignoring malformed session cookie");
+			return Ok(None);
+		};
+		let mut con = self.connection()?;
+		let session_json: Option<String> = self.run(con.get(self.key(&key))).await?;
 		match session_json {
-			Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+			Some(json) => match session_schema::decode_session(&json)? {
+				Some(decoded) => Ok(Some(decoded.write_back(self).await?)),
+				None => Ok(None),
+			},
 			None => Ok(None),
 		}
 	}
 
 	async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
 		debug!("Store session {:?}", session);
-		let user = session.get::<User>("user").unwrap();
-		let key = format!("session:{}:{}", user.email, session.id());
-		let value = serde_json::to_string(&session)?;
-		let mut con = self.redis_client.get_async_connection().await.unwrap();
-		con.set::<_, _, ()>(&key, &value).await.unwrap();
+		let key = stored_session_key(&session).ok_or(StoreError::MissingUser)?;
+		let value = session_schema::encode_session(&session)?;
+		// Expired sessions would not load anyway, the backend forgets them on time
+		self.set_value(&key, &value, session.expires_in()).await?;
 		Ok(Some(value))
 	}
 
 	async fn destroy_session(&self, session: Session) -> async_session::Result {
 		debug!("Destroy session {:?}", session);
-		let user = session.get::<User>("user").unwrap();
-		let key = format!("session:{}:{}", user.email, session.id());
-		let mut con = self.redis_client.get_async_connection().await.unwrap();
-		con.del::<_, ()>(&key).await.unwrap();
-		Ok(())
+		// Sessions without user are never stored
+		let Some(key) = stored_session_key(&session) else {
+			return Ok(());
+		};
+		let key = self.key(&key);
+		let mut con = self.connection()?;
+		Ok(self.run(con.del::<_, ()>(&key)).await?)
 	}
 
 	async fn clear_store(&self) -> async_session::Result {
 		debug!("Clear all sessions");
-		let mut con = self.redis_client.get_async_connection().await.unwrap();
-		let mut cursor: usize = 0;
-		loop {
-			let res: (usize, Vec<String>) = redis::cmd("SCAN")
-				.arg(cursor)
-				.arg("MATCH")
-				.arg("session:*")
-				.query_async(&mut con)
-				.await
-				.unwrap();
-
-			cursor = res.0;
-			let keys: Vec<String> = res.1;
-
-			// Delete the keys
-			if !keys.is_empty() {
-				let _: () = con.del(keys).await.unwrap();
-			}
+		let mut con = self.connection()?;
+		// SCAN only walks the keys of the node it is sent to
+		let nodes = match &mut con {
+			RedisConnection::Cluster(cluster) => self
+				.run(redis_connection::cluster_masters(cluster))
+				.await?
+				.into_iter()
+				.map(Some)
+				.collect(),
+			RedisConnection::Server(_) => vec![None],
+		};
+		for node in nodes {
+			let mut cursor: usize = 0;
+			loop {
+				let (next_cursor, keys) = self.scan_sessions(&mut con, node, cursor).await?;
+				cursor = next_cursor;
+
+				// Delete the keys, one by one on a cluster where they may belong to different slots
+				if node.is_some() {
+					for key in keys {
+						self.run(con.del::<_, ()>(key)).await?;
+					}
+				} else if !keys.is_empty() {
+					self.run(con.del::<_, ()>(keys)).await?;
+				}
 
-			// If the cursor is 0, we have completed the iteration
-			if cursor == 0 {
-				break;
+				// If the cursor is 0, we have completed the iteration
+				if cursor == 0 {
+					break;
+				}
 			}
 		}
 
 		Ok(())
 	}
 }
+
+impl RedisStore {
+	/// Walk a page of the session keys, on the given master of a cluster
+	async fn scan_sessions(
+		&self,
+		con: &mut RedisConnection,
+		node: Option<Route>,
+		cursor: usize,
+	) -> StoreResult<(usize, Vec<String>)> {
+		let mut scan = redis::cmd("SCAN");
+		let pattern = format!("{}session:*", escape_pattern(&self.key_prefix));
+		scan.arg(cursor).arg("MATCH").arg(pattern);
+		match (con, node) {
+			(RedisConnection::Cluster(cluster), Some(node)) => {
+				let routing = RoutingInfo::SingleNode(SingleNodeRoutingInfo::SpecificNode(node));
+				let page = self.run(cluster.route_command(&scan, routing)).await?;
+				Ok(redis::from_redis_value(&page)?)
+			}
+			(con, _) => self.run(scan.query_async(con)).await,
+		}
+	}
+}
+
+#[async_trait]
+impl MemoryStore for RedisStore {
+	async fn take_rate_limit_token(
+		&self,
+		key: &str,
+		limit: RateLimit,
+	) -> StoreResult<RateLimitDecision> {
+		let mut con = self.connection()?;
+		let script = Script::new(TOKEN_BUCKET_SCRIPT);
+		let mut invocation = script.key(self.key(key));
+		invocation
+			.arg(limit.burst)
+			.arg(limit.per_minute as f64 / 60_000.0)
+			.arg(rate_limit::now_ms());
+		let (allowed, retry_after_ms): (u8, u64) =
+			self.run(invocation.invoke_async(&mut con)).await?;
+		Ok(RateLimitDecision {
+			allowed: allowed == 1,
+			retry_after: Duration::from_millis(retry_after_ms),
+		})
+	}
+
+	async fn get_value(&self, key: &str) -> StoreResult<Option<String>> {
+		let mut con = self.connection()?;
+		self.run(con.get(self.key(key))).await
+	}
+
+	async fn set_value(&self, key: &str, value: &str, ttl: Option<Duration>) -> StoreResult {
+		let mut con = self.connection()?;
+		match ttl {
+			Some(ttl) => {
+				self.run(con.pset_ex::<_, _, ()>(self.key(key), value, ttl.as_millis() as usize))
+					.await
+			}
+			None => self.run(con.set::<_, _, ()>(self.key(key), value)).await,
+		}
+	}
+
+	async fn set_value_if_absent(
+		&self,
+		key: &str,
+		value: &str,
+		ttl: Option<Duration>,
+	) -> StoreResult<bool> {
+		let mut con = self.connection()?;
+		let mut set = redis::cmd("SET");
+		set.arg(self.key(key)).arg(value).arg("NX");
+		if let Some(ttl) = ttl {
+			set.arg("PX").arg(ttl.as_millis() as u64);
+		}
+		let stored: Option<String> = self.run(set.query_async(&mut con)).await?;
+		Ok(stored.is_some())
+	}
+
+	async fn delete_value(&self, key: &str) -> StoreResult {
+		let mut con = self.connection()?;
+		self.run(con.del::<_, ()>(self.key(key))).await
+	}
+
+	async fn increment_value(
+		&self,
+		key: &str,
+		delta: i64,
+		ttl: Option<Duration>,
+	) -> StoreResult<i64> {
+		let mut con = self.connection()?;
+		let script = Script::new(INCREMENT_SCRIPT);
+		let mut invocation = script.key(self.key(key));
+		invocation
+			.arg(delta)
+			.arg(ttl.map_or(0, |ttl| ttl.as_millis() as u64));
+		self.run(invocation.invoke_async(&mut con)).await
+	}
+
+	async fn take_value(&self, key: &str) -> StoreResult<Option<String>> {
+		let mut con = self.connection()?;
+		self.run(con.get_del(self.key(key))).await
+	}
+
+	async fn push_value(&self, key: &str, value: &str) -> StoreResult {
+		let mut con = self.connection()?;
+		self.run(con.rpush::<_, _, ()>(self.key(key), value)).await
+	}
+
+	async fn list_values(&self, key: &str) -> StoreResult<Vec<String>> {
+		let mut con = self.connection()?;
+		self.run(con.lrange(self.key(key), 0, -1)).await
+	}
+
+	async fn publish(&self, channel: &str, message: &str) -> StoreResult {
+		let mut con = self.connection()?;
+		self.run(con.publish::<_, _, ()>(self.key(channel), message))
+			.await
+	}
+
+	/// Subscriptions have a connection of their own, which is opened in the background
+	/// once the store is connected
+	async fn subscribe(&self, channel: &str) -> StoreResult<Subscription> {
+		let (sender, receiver) = mpsc::unbounded_channel();
+		tokio::spawn(self.clone().forward_messages(self.key(channel), sender));
+		Ok(receiver)
+	}
+
+	fn metrics(&self) -> Option<StoreMetrics> {
+		Some(StoreMetrics {
+			connections: self.connections(),
+			in_flight: self.metrics.in_flight.load(Ordering::Relaxed),
+			commands: self.metrics.commands.load(Ordering::Relaxed),
+			errors: self.metrics.errors.load(Ordering::Relaxed),
+			timeouts: self.metrics.timeouts.load(Ordering::Relaxed),
+			near_cache: None,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_session_key_from_cookie() {
+		let mut session = Session::new();
+		session
+			.insert("user", serde_json::json!({ "email": "user@example.com" }))
+			.unwrap();
+		let cookie_value = serde_json::to_string(&session).unwrap();
+		assert_eq!(
+			session_key_from_cookie(&cookie_value),
+			Some(format!("session:{{user@example.com}}:{}", session.id()))
+		);
+	}
+
+	#[test]
+	fn test_session_key_from_malformed_cookie() {
+		for cookie_value in [
+			"garbage",
+			"{}",
+			r#"{"id": 1}"#,
+			r#"{"id": "abc", "data": {}}"#,
+			r#"{"id": "abc", "data": {"user": "not json"}}"#,
+			r#"{"id": "abc", "data": {"user": "{\"name\": \"no email\"}"}}"#,
+		] {
+			assert_eq!(
+				session_key_from_cookie(cookie_value),
+				None,
+				"{}",
+				cookie_value
+			);
+		}
+	}
+
+	#[test]
+	fn test_user_keys_share_a_slot() {
+		use redis::cluster_routing::get_slot;
+
+		let tag = user_hash_tag("User@Example.com");
+		assert_eq!(tag, "{user@example.com}");
+		let slot = get_slot(format!("user:{}", tag).as_bytes());
+		assert_eq!(
+			get_slot(session_key("user@example.com", "abc").as_bytes()),
+			slot
+		);
+		assert_eq!(get_slot(format!("credentials:{}", tag).as_bytes()), slot);
+	}
+
+	#[test]
+	fn test_store_error_through_session_store_error() {
+		let error: async_session::Error = StoreError::Unavailable("down".to_string()).into();
+		assert!(matches!(
+			StoreError::from(error),
+			StoreError::Unavailable(message) if message == "down"
+		));
+		let error = async_session::Error::msg("other");
+		assert!(matches!(StoreError::from(error), StoreError::Command(_)));
+	}
+
+	#[test]
+	fn test_escape_pattern() {
+		assert_eq!(escape_pattern("sabi:prod:"), "sabi:prod:");
+		assert_eq!(escape_pattern("a*b?[c]\\"), "a\\*b\\?\\[c\\]\\\\");
+	}
+
+	#[test]
+	fn test_in_flight_counter() {
+		let counter = AtomicU64::new(0);
+		{
+			let _first = InFlight::start(&counter);
+			let _second = InFlight::start(&counter);
+			assert_eq!(counter.load(Ordering::Relaxed), 2);
+		}
+		assert_eq!(counter.load(Ordering::Relaxed), 0);
+	}
+
+	/// Nothing listens on port 1, connections are refused right away
+	fn unreachable() -> (String, RedisTopologyConfig, RedisPoolConfig) {
+		let mut config = crate::config::Config::from_params("test".to_string());
+		config.redis_pool.size = 1;
+		config.redis_pool.reconnect_retries = 0;
+		config.redis_pool.startup_timeout = Duration::from_millis(300);
+		(
+			"redis://127.0.0.1:1/".to_string(),
+			config.redis_topology,
+			config.redis_pool,
+		)
+	}
+
+	#[tokio::test]
+	async fn test_startup_gives_up_after_its_deadline() {
+		let (url, topology, pool) = unreachable();
+		let started = Instant::now();
+		let result = RedisStore::new(url, "", &topology, &pool).await;
+		assert!(matches!(result, Err(StoreError::Unavailable(_))));
+		assert!(started.elapsed() >= Duration::from_millis(300));
+		assert!(started.elapsed() < Duration::from_secs(5));
+	}
+
+	#[tokio::test]
+	async fn test_degraded_start() {
+		let (url, topology, pool) = unreachable();
+		let store = RedisStore::connect_in_background(url, "", &topology, &pool);
+		assert!(matches!(
+			store.get_value("key").await,
+			Err(StoreError::Unavailable(_))
+		));
+		assert!(matches!(
+			DynSessionStore::load_session(&store, "garbage".to_string()).await,
+			Ok(None)
+		));
+		assert_eq!(store.metrics().unwrap().connections, 0);
+	}
+
+	#[tokio::test]
+	async fn test_subscriptions_wait_for_redis() {
+		let (url, topology, pool) = unreachable();
+		let store = RedisStore::connect_in_background(url, "", &topology, &pool);
+		assert!(matches!(
+			store.publish("channel", "message").await,
+			Err(StoreError::Unavailable(_))
+		));
+		let mut subscription = store.subscribe("channel").await.unwrap();
+		tokio::time::sleep(Duration::from_millis(50)).await;
+		assert!(subscription.try_recv().is_err());
+	}
+}
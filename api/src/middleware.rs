@@ -1,14 +1,39 @@
-use std::net::SocketAddr;
+use axum::{
+	http::{HeaderValue, Method, Request},
+	middleware::Next,
+	response::Response,
+};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::warn;
 
-use axum::http::{HeaderValue, Method};
-use tower_http::cors::CorsLayer;
+use crate::{
+	config::CorsConfig,
+	services::admin::{Impersonator, IMPERSONATION_HEADER},
+};
 
-pub fn cors(api_address: SocketAddr) -> CorsLayer {
+pub fn cors(config: &CorsConfig) -> CorsLayer {
+	let origins = config.allowed_origins.iter().filter_map(|origin| {
+		origin
+			.parse::<HeaderValue>()
+			.map_err(|_| warn!("Ignoring invalid CORS origin {}", origin))
+			.ok()
+	});
 	CorsLayer::new()
-		.allow_origin(
-			format!("http://{}", api_address)
-				.parse::<HeaderValue>()
-				.unwrap(),
-		)
+		.allow_origin(AllowOrigin::list(origins))
 		.allow_methods(vec![Method::GET, Method::POST])
 }
+
+/// Mark every response served to an impersonation session with the admin behind it. The
+/// session is the one the `User` extractor of the handler loaded
+pub async fn impersonation_banner<B>(mut request: Request<B>, next: Next<B>) -> Response {
+	let impersonator = Impersonator::default();
+	request.extensions_mut().insert(impersonator.clone());
+
+	let mut response = next.run(request).await;
+	if let Some(admin) = impersonator.get() {
+		if let Ok(value) = HeaderValue::from_str(admin) {
+			response.headers_mut().insert(IMPERSONATION_HEADER, value);
+		}
+	}
+	response
+}
@@ -0,0 +1,457 @@
+use async_session::{async_trait, Session, SessionStore};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+	collections::{BTreeMap, HashMap},
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, Mutex, MutexGuard, Weak,
+	},
+	time::{Duration, Instant},
+};
+use tracing::{debug, error};
+
+use crate::{
+	config::RateLimit,
+	memory_store::{
+		session_key_from_cookie, stored_session_key, MemoryStore, SharedMemoryStore, StoreMessage,
+		StoreMetrics, StoreResult, Subscription,
+	},
+	rate_limit::RateLimitDecision,
+};
+
+/// Channel the near caches of every instance announce the sessions and values that changed on
+const INVALIDATION_CHANNEL: &str = "near-cache:sessions";
+/// Invalidation of every cached session, as no session key can be `*`
+const INVALIDATE_ALL: &str = "*";
+/// Values cached along with the sessions, as they are read by every authenticated request.
+/// Their writes are announced like the session ones
+const CACHED_VALUE_PREFIXES: &[&str] = &["session_epoch:"];
+
+fn is_cached_value(key: &str) -> bool {
+	CACHED_VALUE_PREFIXES
+		.iter()
+		.any(|prefix| key.starts_with(prefix))
+}
+
+/// Counters of the near cache kept in front of the store
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct NearCacheMetrics {
+	/// Sessions and values currently cached
+	pub entries: usize,
+	/// Sessions and values loaded from the cache
+	pub hits: u64,
+	/// Sessions and values loaded from the store
+	pub misses: u64,
+	/// Invalidations received, from this instance or another one
+	pub invalidations: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+	hits: AtomicU64,
+	misses: AtomicU64,
+	invalidations: AtomicU64,
+}
+
+#[derive(Clone, Debug)]
+enum Cached {
+	Session(Session),
+	/// Value of a key matching `CACHED_VALUE_PREFIXES`, absent ones included
+	Value(Option<String>),
+}
+
+#[derive(Debug)]
+struct Entry {
+	cached: Cached,
+	cached_at: Instant,
+	/// Position of the entry in `Lru::recency`
+	used: u64,
+}
+
+/// Sessions and values by key, evicting the least recently used one when full
+#[derive(Debug, Default)]
+struct Lru {
+	entries: HashMap<String, Entry>,
+	/// Keys by last use, the least recently used first
+	recency: BTreeMap<u64, String>,
+	clock: u64,
+	/// Bumped by every invalidation, so that the loads racing with one do not cache what they read
+	generation: u64,
+}
+
+impl Lru {
+	/// Get the entry at `key`, unless it was cached more than `ttl` ago or expired meanwhile
+	fn get(&mut self, key: &str, ttl: Duration) -> Option<Cached> {
+		let entry = self.entries.get(key)?;
+		let expired = matches!(&entry.cached, Cached::Session(session) if session.is_expired());
+		if entry.cached_at.elapsed() >= ttl || expired {
+			self.remove(key);
+			return None;
+		}
+		let cached = entry.cached.clone();
+		self.touch(key);
+		Some(cached)
+	}
+
+	fn insert(&mut self, key: String, cached: Cached, capacity: usize) {
+		self.remove(&key);
+		while self.entries.len() >= capacity {
+			let Some((_, oldest)) = self.recency.pop_first() else {
+				break;
+			};
+			self.entries.remove(&oldest);
+		}
+		self.clock += 1;
+		self.recency.insert(self.clock, key.clone());
+		self.entries.insert(
+			key,
+			Entry {
+				cached,
+				cached_at: Instant::now(),
+				used: self.clock,
+			},
+		);
+	}
+
+	fn touch(&mut self, key: &str) {
+		if let Some(entry) = self.entries.get_mut(key) {
+			self.recency.remove(&entry.used);
+			self.clock += 1;
+			entry.used = self.clock;
+			self.recency.insert(self.clock, key.to_string());
+		}
+	}
+
+	fn remove(&mut self, key: &str) {
+		if let Some(entry) = self.entries.remove(key) {
+			self.recency.remove(&entry.used);
+		}
+	}
+
+	fn invalidate(&mut self, key: &str) {
+		self.generation += 1;
+		match key {
+			INVALIDATE_ALL => {
+				self.entries.clear();
+				self.recency.clear();
+			}
+			key => self.remove(key),
+		}
+	}
+}
+
+/// A panic while holding the lock cannot leave the cache half updated, so poisoning is ignored
+fn lock(entries: &Mutex<Lru>) -> MutexGuard<'_, Lru> {
+	entries
+		.lock()
+		.unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Store keeping the recently loaded sessions in the process memory, in front of any other store.
+///
+/// Cached sessions are served for at most `ttl`. Storing or destroying a session announces it to
+/// the near caches of every instance through the store, which evict it as soon as they hear of it.
+/// The keys of `CACHED_VALUE_PREFIXES` are cached the same way, every other key is passed through
+/// untouched
+#[derive(Clone, Debug)]
+pub struct NearCacheStore {
+	store: SharedMemoryStore,
+	entries: Arc<Mutex<Lru>>,
+	capacity: usize,
+	ttl: Duration,
+	counters: Arc<Counters>,
+}
+
+impl NearCacheStore {
+	/// Cache up to `capacity` sessions in front of the store, which must support publish and
+	/// subscribe to hear of the sessions changed by the other instances
+	pub async fn new(
+		store: SharedMemoryStore,
+		capacity: usize,
+		ttl: Duration,
+	) -> StoreResult<Self> {
+		let subscription = store.subscribe(INVALIDATION_CHANNEL).await?;
+		let near_cache = Self {
+			store,
+			entries: Arc::default(),
+			capacity: capacity.max(1),
+			ttl,
+			counters: Arc::default(),
+		};
+		tokio::spawn(evict_on_messages(
+			subscription,
+			Arc::downgrade(&near_cache.entries),
+			near_cache.counters.clone(),
+		));
+		Ok(near_cache)
+	}
+
+	/// Get the entry at `key` from the cache, or else the generation to cache it at once loaded
+	fn cached<T>(&self, key: &str, extract: impl FnOnce(Cached) -> Option<T>) -> Result<T, u64> {
+		let mut entries = lock(&self.entries);
+		match entries.get(key, self.ttl).and_then(extract) {
+			Some(cached) => {
+				self.counters.hits.fetch_add(1, Ordering::Relaxed);
+				Ok(cached)
+			}
+			None => {
+				self.counters.misses.fetch_add(1, Ordering::Relaxed);
+				Err(entries.generation)
+			}
+		}
+	}
+
+	/// Cache what was loaded, unless an invalidation happened since `generation`
+	fn cache(&self, key: String, cached: Cached, generation: u64) {
+		let mut entries = lock(&self.entries);
+		if entries.generation == generation {
+			entries.insert(key, cached, self.capacity);
+		}
+	}
+
+	/// Evict an entry here, then on every other instance; a failed publish is only logged
+	/// since the write itself already succeeded
+	async fn invalidate(&self, key: &str) {
+		lock(&self.entries).invalidate(key);
+		if let Err(e) = self.store.publish(INVALIDATION_CHANNEL, key).await {
+			error!(
+				"Unable to publish the invalidation of {}, other instances may serve it for {:?}: {}",
+				key, self.ttl, e
+			);
+		}
+	}
+
+	/// Invalidate the value at `key` if it is one of the cached values
+	async fn invalidate_value(&self, key: &str) {
+		if is_cached_value(key) {
+			self.invalidate(key).await;
+		}
+	}
+}
+
+/// Apply the invalidations published by every instance. The task stops once the cache is dropped
+async fn evict_on_messages(
+	mut subscription: Subscription,
+	entries: Weak<Mutex<Lru>>,
+	counters: Arc<Counters>,
+) {
+	while let Some(message) = subscription.recv().await {
+		let Some(entries) = entries.upgrade() else {
+			break;
+		};
+		counters.invalidations.fetch_add(1, Ordering::Relaxed);
+		match message {
+			StoreMessage::Message(key) => lock(&entries).invalidate(&key),
+			// Nothing cached can be trusted once invalidations were missed
+			StoreMessage::Lagged => {
+				debug!("Invalidations may have been missed, clearing the near cache");
+				lock(&entries).invalidate(INVALIDATE_ALL)
+			}
+		}
+	}
+}
+
+#[async_trait]
+impl SessionStore for NearCacheStore {
+	async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+		let Some(key) = session_key_from_cookie(&cookie_value) else {
+			debug!("Ignoring malformed session cookie");
+			return Ok(None);
+		};
+		let cached = self.cached(&key, |cached| match cached {
+			Cached::Session(session) => Some(session),
+			Cached::Value(_) => None,
+		});
+		let generation = match cached {
+			Ok(session) => return Ok(Some(session)),
+			Err(generation) => generation,
+		};
+
+		let session = self.store.load_session(cookie_value).await?;
+		if let Some(session) = &session {
+			self.cache(key, Cached::Session(session.clone()), generation);
+		}
+		Ok(session)
+	}
+
+	async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+		let key = stored_session_key(&session);
+		let cookie_value = self.store.store_session(session).await?;
+		if let Some(key) = key {
+			self.invalidate(&key).await;
+		}
+		Ok(cookie_value)
+	}
+
+	async fn destroy_session(&self, session: Session) -> async_session::Result {
+		let key = stored_session_key(&session);
+		self.store.destroy_session(session).await?;
+		if let Some(key) = key {
+			self.invalidate(&key).await;
+		}
+		Ok(())
+	}
+
+	async fn clear_store(&self) -> async_session::Result {
+		self.store.clear_store().await?;
+		self.invalidate(INVALIDATE_ALL).await;
+		Ok(())
+	}
+}
+
+#[async_trait]
+impl MemoryStore for NearCacheStore {
+	async fn take_rate_limit_token(
+		&self,
+		key: &str,
+		limit: RateLimit,
+	) -> StoreResult<RateLimitDecision> {
+		self.store.take_rate_limit_token(key, limit).await
+	}
+
+	async fn get_value(&self, key: &str) -> StoreResult<Option<String>> {
+		if !is_cached_value(key) {
+			return self.store.get_value(key).await;
+		}
+		let cached = self.cached(key, |cached| match cached {
+			Cached::Value(value) => Some(value),
+			Cached::Session(_) => None,
+		});
+		let generation = match cached {
+			Ok(value) => return Ok(value),
+			Err(generation) => generation,
+		};
+		let value = self.store.get_value(key).await?;
+		self.cache(key.to_string(), Cached::Value(value.clone()), generation);
+		Ok(value)
+	}
+
+	async fn set_value(&self, key: &str, value: &str, ttl: Option<Duration>) -> StoreResult {
+		self.store.set_value(key, value, ttl).await?;
+		self.invalidate_value(key).await;
+		Ok(())
+	}
+
+	async fn set_value_if_absent(
+		&self,
+		key: &str,
+		value: &str,
+		ttl: Option<Duration>,
+	) -> StoreResult<bool> {
+		let set = self.store.set_value_if_absent(key, value, ttl).await?;
+		if set {
+			self.invalidate_value(key).await;
+		}
+		Ok(set)
+	}
+
+	async fn delete_value(&self, key: &str) -> StoreResult {
+		self.store.delete_value(key).await?;
+		self.invalidate_value(key).await;
+		Ok(())
+	}
+
+	async fn increment_value(
+		&self,
+		key: &str,
+		delta: i64,
+		ttl: Option<Duration>,
+	) -> StoreResult<i64> {
+		let value = self.store.increment_value(key, delta, ttl).await?;
+		self.invalidate_value(key).await;
+		Ok(value)
+	}
+
+	async fn take_value(&self, key: &str) -> StoreResult<Option<String>> {
+		let value = self.store.take_value(key).await?;
+		self.invalidate_value(key).await;
+		Ok(value)
+	}
+
+	async fn push_value(&self, key: &str, value: &str) -> StoreResult {
+		self.store.push_value(key, value).await
+	}
+
+	async fn list_values(&self, key: &str) -> StoreResult<Vec<String>> {
+		self.store.list_values(key).await
+	}
+
+	async fn publish(&self, channel: &str, message: &str) -> StoreResult {
+		self.store.publish(channel, message).await
+	}
+
+	async fn subscribe(&self, channel: &str) -> StoreResult<Subscription> {
+		self.store.subscribe(channel).await
+	}
+
+	fn metrics(&self) -> Option<StoreMetrics> {
+		let mut metrics = self.store.metrics().unwrap_or_default();
+		metrics.near_cache = Some(NearCacheMetrics {
+			entries: lock(&self.entries).entries.len(),
+			hits: self.counters.hits.load(Ordering::Relaxed),
+			misses: self.counters.misses.load(Ordering::Relaxed),
+			invalidations: self.counters.invalidations.load(Ordering::Relaxed),
+		});
+		Some(metrics)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn session() -> Cached {
+		Cached::Session(Session::new())
+	}
+
+	#[test]
+	fn test_lru_evicts_the_least_recently_used() {
+		let mut lru = Lru::default();
+		let ttl = Duration::from_secs(60);
+		lru.insert("a".to_string(), session(), 2);
+		lru.insert("b".to_string(), session(), 2);
+		assert!(lru.get("a", ttl).is_some());
+
+		lru.insert("c".to_string(), session(), 2);
+		assert!(lru.get("b", ttl).is_none());
+		assert!(lru.get("a", ttl).is_some());
+		assert!(lru.get("c", ttl).is_some());
+		assert_eq!(lru.entries.len(), lru.recency.len());
+	}
+
+	#[test]
+	fn test_lru_ttl_and_invalidation() {
+		let mut lru = Lru::default();
+		lru.insert("a".to_string(), session(), 10);
+		assert!(lru.get("a", Duration::ZERO).is_none());
+		assert!(lru.entries.is_empty() && lru.recency.is_empty());
+
+		lru.insert("a".to_string(), session(), 10);
+		lru.insert("b".to_string(), session(), 10);
+		lru.invalidate("a");
+		assert_eq!(lru.generation, 1);
+		assert!(lru.get("a", Duration::from_secs(60)).is_none());
+		lru.invalidate(INVALIDATE_ALL);
+		assert_eq!(lru.generation, 2);
+		assert!(lru.entries.is_empty() && lru.recency.is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_lagged_subscriptions_clear_the_cache() {
+		let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+		let entries = Arc::new(Mutex::new(Lru::default()));
+		lock(&entries).insert("a".to_string(), session(), 10);
+		let counters = Arc::new(Counters::default());
+		let task = tokio::spawn(evict_on_messages(
+			receiver,
+			Arc::downgrade(&entries),
+			counters.clone(),
+		));
+
+		sender.send(StoreMessage::Lagged).unwrap();
+		drop(sender);
+		task.await.unwrap();
+		assert!(lock(&entries).entries.is_empty());
+		assert_eq!(counters.invalidations.load(Ordering::Relaxed), 1);
+	}
+}
@@ -0,0 +1,158 @@
+use std::{
+	net::SocketAddr,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+	extract::{ConnectInfo, MatchedPath, State},
+	http::{header::RETRY_AFTER, HeaderValue, Request, StatusCode},
+	middleware::Next,
+	response::{IntoResponse, Response},
+	Json,
+};
+use serde_json::json;
+use tracing::{debug, error};
+
+use crate::{config::RateLimit, errors::AppError, AppState};
+
+/// The outcome of trying to take a token from a bucket
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimitDecision {
+	pub allowed: bool,
+	/// How long the client should wait before a token is available again
+	pub retry_after: Duration,
+}
+
+/// In-process token bucket, mirroring the script used by the Redis store
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TokenBucket {
+	pub tokens: f64,
+	pub updated_at_ms: u64,
+}
+
+impl TokenBucket {
+	pub fn full(limit: RateLimit, now_ms: u64) -> Self {
+		Self {
+			tokens: limit.burst as f64,
+			updated_at_ms: now_ms,
+		}
+	}
+
+	/// Refill the bucket for the time elapsed since the last update and try to take a token
+	pub fn take(&mut self, limit: RateLimit, now_ms: u64) -> RateLimitDecision {
+		let refill_per_ms = limit.per_minute as f64 / 60_000.0;
+		let elapsed_ms = now_ms.saturating_sub(self.updated_at_ms) as f64;
+		self.tokens = (self.tokens + elapsed_ms * refill_per_ms).min(limit.burst as f64);
+		self.updated_at_ms = now_ms;
+
+		if self.tokens >= 1.0 {
+			self.tokens -= 1.0;
+			return RateLimitDecision {
+				allowed: true,
+				retry_after: Duration::ZERO,
+			};
+		}
+		let retry_after = if refill_per_ms > 0.0 {
+			Duration::from_millis(((1.0 - self.tokens) / refill_per_ms).ceil() as u64)
+		} else {
+			Duration::from_secs(60)
+		};
+		RateLimitDecision {
+			allowed: false,
+			retry_after,
+		}
+	}
+}
+
+pub fn now_ms() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_millis() as u64)
+		.unwrap_or_default()
+}
+
+/// Rate limit the `/auth` routes per client IP and per route, with the thresholds of their group
+pub async fn auth<B>(
+	State(app_state): State<AppState>,
+	request: Request<B>,
+	next: Next<B>,
+) -> Response {
+	// Paths are relative to the `/auth` nest
+	let (group, limit) = if request.uri().path().ends_with("/authorized") {
+		("auth_callback", app_state.config.rate_limit.auth_callback)
+	} else {
+		("auth_login", app_state.config.rate_limit.auth_login)
+	};
+	// Clients cannot be told apart without their address, so none is let through
+	let Some(ConnectInfo(client_addr)) = request.extensions().get::<ConnectInfo<SocketAddr>>()
+	else {
+		error!("Unable to rate limit a request without the address of its client");
+		return AppError::InternalError.into_response();
+	};
+	// Unmatched paths share a bucket, so that clients cannot make up new ones
+	let route = request
+		.extensions()
+		.get::<MatchedPath>()
+		.map_or("unmatched", MatchedPath::as_str);
+	let key = format!("ratelimit:{}:{}:{}", group, route, client_addr.ip());
+
+	match app_state
+		.memory_store
+		.take_rate_limit_token(&key, limit)
+		.await
+	{
+		Ok(decision) if !decision.allowed => {
+			debug!("Rate limit exceeded for {}", key);
+			// Round up so that clients never retry too early
+			let retry_after = decision.retry_after.as_millis().div_ceil(1000).max(1);
+			let mut response = (
+				StatusCode::TOO_MANY_REQUESTS,
+				Json(json!({ "error": "too many requests" })),
+			)
+				.into_response();
+			response
+				.headers_mut()
+				.insert(RETRY_AFTER, HeaderValue::from(retry_after as u64));
+			response
+		}
+		Ok(_) => next.run(request).await,
+		Err(e) => {
+			// Do not lock users out if the store is unavailable
+			error!("Unable to check rate limit for {}: {}", key, e);
+			next.run(request).await
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const LIMIT: RateLimit = RateLimit {
+		burst: 2,
+		per_minute: 60,
+	};
+
+	#[test]
+	fn test_token_bucket_allows_burst() {
+		let mut bucket = TokenBucket::full(LIMIT, 0);
+		assert!(bucket.take(LIMIT, 0).allowed);
+		assert!(bucket.take(LIMIT, 0).allowed);
+		let decision = bucket.take(LIMIT, 0);
+		assert!(!decision.allowed);
+		assert_eq!(decision.retry_after, Duration::from_secs(1));
+	}
+
+	#[test]
+	fn test_token_bucket_refills() {
+		let mut bucket = TokenBucket::full(LIMIT, 0);
+		assert!(bucket.take(LIMIT, 0).allowed);
+		assert!(bucket.take(LIMIT, 0).allowed);
+		assert!(!bucket.take(LIMIT, 500).allowed);
+		assert!(bucket.take(LIMIT, 1000).allowed);
+		// Refilling never goes above the burst size
+		let mut bucket = TokenBucket::full(LIMIT, 0);
+		bucket.take(LIMIT, 60_000);
+		assert_eq!(bucket.tokens, 1.0);
+	}
+}
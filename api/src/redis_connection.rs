@@ -0,0 +1,305 @@
+use redis::{
+	aio::{ConnectionLike, ConnectionManager, PubSub},
+	cluster::ClusterClient,
+	cluster_async::ClusterConnection,
+	cluster_routing::{Route, RoutingInfo, SingleNodeRoutingInfo, SlotAddr},
+	sentinel::{Sentinel, SentinelNodeConnectionInfo},
+	Client, Cmd, ErrorKind, IntoConnectionInfo, Pipeline, RedisError, RedisFuture, RedisResult,
+	Value,
+};
+use std::{
+	future::Future,
+	sync::{
+		atomic::{AtomicBool, AtomicUsize, Ordering},
+		Arc, Mutex, RwLock,
+	},
+	time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+
+use crate::config::{RedisPoolConfig, RedisTopologyConfig, RedisTopologyKind};
+
+/// Minimum time between two questions to the sentinels, so that an outage does not flood them
+const MASTER_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Connection to the Redis deployment, whatever its topology
+#[derive(Clone)]
+pub enum RedisConnection {
+	/// A standalone server, or the master found through Sentinel. Reconnects by itself
+	Server(ConnectionManager),
+	/// Sends every command to the node owning its key, following the slot migrations
+	Cluster(ClusterConnection),
+}
+
+impl ConnectionLike for RedisConnection {
+	fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+		match self {
+			RedisConnection::Server(connection) => connection.req_packed_command(cmd),
+			RedisConnection::Cluster(connection) => connection.req_packed_command(cmd),
+		}
+	}
+
+	fn req_packed_commands<'a>(
+		&'a mut self,
+		cmd: &'a Pipeline,
+		offset: usize,
+		count: usize,
+	) -> RedisFuture<'a, Vec<Value>> {
+		match self {
+			RedisConnection::Server(connection) => {
+				connection.req_packed_commands(cmd, offset, count)
+			}
+			RedisConnection::Cluster(connection) => {
+				connection.req_packed_commands(cmd, offset, count)
+			}
+		}
+	}
+
+	fn get_db(&self) -> i64 {
+		match self {
+			RedisConnection::Server(connection) => connection.get_db(),
+			RedisConnection::Cluster(connection) => connection.get_db(),
+		}
+	}
+}
+
+/// Master of a Sentinel deployment, looked up again when the connections to it fail
+struct SentinelMaster {
+	sentinel: tokio::sync::Mutex<Sentinel>,
+	name: String,
+	node_info: SentinelNodeConnectionInfo,
+	/// Address of the master the connections are open to
+	address: Mutex<String>,
+	/// When the sentinels were last asked for the master
+	checked_at: Mutex<Instant>,
+	checking: AtomicBool,
+}
+
+impl SentinelMaster {
+	async fn discover(&self) -> RedisResult<Client> {
+		self.sentinel
+			.lock()
+			.await
+			.async_master_for(&self.name, Some(&self.node_info))
+			.await
+	}
+}
+
+/// Connections to the Redis deployment, used in turn.
+/// With Sentinel, the connections follow the master when it fails over
+pub struct RedisPool {
+	connections: RwLock<Vec<RedisConnection>>,
+	next_connection: AtomicUsize,
+	config: RedisPoolConfig,
+	sentinel: Option<SentinelMaster>,
+	/// Server the subscriptions connect to, the master with Sentinel
+	pubsub_client: Mutex<Client>,
+}
+
+impl RedisPool {
+	pub async fn connect(
+		url: &str,
+		topology: &RedisTopologyConfig,
+		config: &RedisPoolConfig,
+	) -> RedisResult<Self> {
+		let mut sentinel = None;
+		let pubsub_client;
+		let connections = match topology.kind {
+			RedisTopologyKind::Standalone => {
+				pubsub_client = Client::open(url)?;
+				connect_server(pubsub_client.clone(), config).await?
+			}
+			RedisTopologyKind::Sentinel => {
+				let master = SentinelMaster {
+					sentinel: tokio::sync::Mutex::new(Sentinel::build(
+						topology.sentinel_urls.to_vec(),
+					)?),
+					name: topology.sentinel_master.to_string(),
+					node_info: SentinelNodeConnectionInfo {
+						tls_mode: None,
+						// Credentials and database of the master
+						redis_connection_info: Some(url.into_connection_info()?.redis),
+					},
+					address: Mutex::new(String::new()),
+					checked_at: Mutex::new(Instant::now()),
+					checking: AtomicBool::new(false),
+				};
+				let client = with_timeout(config.connect_timeout, master.discover()).await?;
+				let address = client.get_connection_info().addr.to_string();
+				info!("Sentinels reported {} as master {}", address, master.name);
+				*master.address.lock().unwrap() = address;
+				sentinel = Some(master);
+				pubsub_client = client.clone();
+				connect_server(client, config).await?
+			}
+			RedisTopologyKind::Cluster => {
+				// Nodes forward the published messages to each other, any of them will do
+				let node = topology.cluster_urls.first().ok_or_else(|| {
+					RedisError::from((ErrorKind::InvalidClientConfig, "No cluster node given"))
+				})?;
+				pubsub_client = Client::open(node.as_str())?;
+				connect_cluster(topology.cluster_urls.to_vec(), config).await?
+			}
+		};
+		Ok(Self {
+			connections: RwLock::new(connections),
+			next_connection: AtomicUsize::new(0),
+			config: config.clone(),
+			sentinel,
+			pubsub_client: Mutex::new(pubsub_client),
+		})
+	}
+
+	/// Pick the next connection of the pool, in turn
+	pub fn connection(&self) -> RedisConnection {
+		let connections = self.connections.read().unwrap();
+		let index = self.next_connection.fetch_add(1, Ordering::Relaxed) % connections.len();
+		connections[index].clone()
+	}
+
+	/// Number of connections kept open
+	pub fn len(&self) -> usize {
+		self.connections.read().unwrap().len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Open a connection of its own receiving the messages published on `channel`
+	pub async fn subscribe(&self, channel: &str) -> RedisResult<PubSub> {
+		let client = self.pubsub_client.lock().unwrap().clone();
+		let connect = client.get_async_connection();
+		let mut pubsub = with_timeout(self.config.connect_timeout, connect)
+			.await?
+			.into_pubsub();
+		with_timeout(self.config.connect_timeout, pubsub.subscribe(channel)).await?;
+		Ok(pubsub)
+	}
+
+	/// Ask the sentinels in the background whether the master moved, after a command failed
+	/// in a way a failover would explain. Does nothing outside of Sentinel deployments
+	pub fn check_master(self: &Arc<Self>) {
+		let Some(master) = &self.sentinel else {
+			return;
+		};
+		{
+			let mut checked_at = master.checked_at.lock().unwrap();
+			if checked_at.elapsed() < MASTER_CHECK_INTERVAL
+				|| master.checking.swap(true, Ordering::AcqRel)
+			{
+				return;
+			}
+			*checked_at = Instant::now();
+		}
+		let pool = self.clone();
+		tokio::spawn(async move {
+			if let Some(master) = &pool.sentinel {
+				if let Err(e) = pool.follow_master(master).await {
+					warn!("Unable to look up the Redis master: {}", e);
+				}
+				master.checking.store(false, Ordering::Release);
+			}
+		});
+	}
+
+	async fn follow_master(&self, master: &SentinelMaster) -> RedisResult<()> {
+		let client = with_timeout(self.config.connect_timeout, master.discover()).await?;
+		let address = client.get_connection_info().addr.to_string();
+		let previous = master.address.lock().unwrap().clone();
+		if address == previous {
+			debug!("Redis master {} did not move", address);
+			return Ok(());
+		}
+		let connections = connect_server(client.clone(), &self.config).await?;
+		*self.connections.write().unwrap() = connections;
+		*self.pubsub_client.lock().unwrap() = client;
+		*master.address.lock().unwrap() = address.clone();
+		warn!("Redis master moved from {} to {}", previous, address);
+		Ok(())
+	}
+}
+
+/// Whether a command failed because of the server it was sent to, rather than the command itself
+pub fn is_server_failure(e: &RedisError) -> bool {
+	e.kind() == ErrorKind::ReadOnly
+		|| e.is_io_error()
+		|| e.is_connection_refusal()
+		|| e.is_connection_dropped()
+		|| e.is_timeout()
+}
+
+/// One route to each master of the cluster, to run a command on all of them
+pub async fn cluster_masters(connection: &mut ClusterConnection) -> RedisResult<Vec<Route>> {
+	let mut cluster_slots = redis::cmd("CLUSTER");
+	cluster_slots.arg("SLOTS");
+	let slots = connection
+		.route_command(
+			&cluster_slots,
+			RoutingInfo::SingleNode(SingleNodeRoutingInfo::Random),
+		)
+		.await?;
+	// Each slot range is `[start, end, [host, port, ..], replicas..]`
+	let slots: Vec<Vec<Value>> = redis::from_redis_value(&slots)?;
+	let mut masters: Vec<(String, u16)> = vec![];
+	let mut routes = vec![];
+	for range in slots {
+		let (Some(start), Some(node)) = (range.first(), range.get(2)) else {
+			continue;
+		};
+		let start: u16 = redis::from_redis_value(start)?;
+		let node: Vec<Value> = redis::from_redis_value(node)?;
+		let (Some(host), Some(port)) = (node.first(), node.get(1)) else {
+			continue;
+		};
+		let master = (
+			redis::from_redis_value::<String>(host)?,
+			redis::from_redis_value::<u16>(port)?,
+		);
+		if !masters.contains(&master) {
+			masters.push(master);
+			routes.push(Route::new(start, SlotAddr::Master));
+		}
+	}
+	Ok(routes)
+}
+
+async fn connect_server(
+	client: Client,
+	config: &RedisPoolConfig,
+) -> RedisResult<Vec<RedisConnection>> {
+	let mut connections = Vec::with_capacity(config.size);
+	for _ in 0..config.size {
+		// Retries are spaced by rand(0 .. 100ms * 2 ^ attempt)
+		let connect =
+			ConnectionManager::new_with_backoff(client.clone(), 2, 100, config.reconnect_retries);
+		let connection = with_timeout(config.connect_timeout, connect).await?;
+		connections.push(RedisConnection::Server(connection));
+	}
+	Ok(connections)
+}
+
+async fn connect_cluster(
+	urls: Vec<String>,
+	config: &RedisPoolConfig,
+) -> RedisResult<Vec<RedisConnection>> {
+	let client = ClusterClient::builder(urls)
+		.retries(config.reconnect_retries as u32)
+		.build()?;
+	let mut connections = Vec::with_capacity(config.size);
+	for _ in 0..config.size {
+		let connection =
+			with_timeout(config.connect_timeout, client.get_async_connection()).await?;
+		connections.push(RedisConnection::Cluster(connection));
+	}
+	Ok(connections)
+}
+
+async fn with_timeout<T>(
+	timeout: Duration,
+	connect: impl Future<Output = RedisResult<T>>,
+) -> RedisResult<T> {
+	tokio::time::timeout(timeout, connect)
+		.await
+		.map_err(|_| RedisError::from((ErrorKind::IoError, "Timed out connecting to Redis")))?
+}
@@ -0,0 +1,73 @@
+use async_session::async_trait;
+use axum::{
+	extract::{FromRef, FromRequestParts},
+	response::{IntoResponse, Response},
+};
+use http::request::Parts;
+use serde_derive::{Deserialize, Serialize};
+use std::sync::{Arc, OnceLock};
+
+use crate::{errors::AppError, memory_store::StoreMetrics, services::auth::User, AppState};
+
+/// Session key holding the admin who started an impersonation
+pub static IMPERSONATOR_KEY: &str = "impersonator";
+
+/// Response header marking the responses served while impersonating
+pub static IMPERSONATION_HEADER: &str = "x-impersonated-by";
+
+/// Admin behind the session of the request, recorded by the `User` extractor for the
+/// impersonation banner, which thus does not load the session again
+#[derive(Clone, Debug, Default)]
+pub struct Impersonator(Arc<OnceLock<String>>);
+
+impl Impersonator {
+	pub fn set(&self, email: String) {
+		let _ = self.0.set(email);
+	}
+
+	pub fn get(&self) -> Option<&str> {
+		self.0.get().map(String::as_str)
+	}
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ImpersonateRequest {
+	pub email: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ImpersonationResponse {
+	/// Email of the user the session now resolves to
+	pub user: String,
+	/// Email of the admin behind the session, while impersonating
+	pub impersonator: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MetricsResponse {
+	/// Absent for stores without a backend to connect to
+	pub memory_store: Option<StoreMetrics>,
+}
+
+/// A logged in user listed in the configured admin emails
+pub struct AdminUser(pub User);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AdminUser
+where
+	AppState: FromRef<S>,
+	S: Send + Sync,
+{
+	type Rejection = Response;
+
+	async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+		let user = User::from_request_parts(parts, state)
+			.await
+			.map_err(IntoResponse::into_response)?;
+		let config = <AppState>::from_ref(state).config;
+		if !config.admin_emails.contains(&user.email.to_lowercase()) {
+			return Err(AppError::Forbidden.into_response());
+		}
+		Ok(AdminUser(user))
+	}
+}
@@ -0,0 +1,154 @@
+use async_session::Session;
+use axum::{
+	extract::State,
+	response::IntoResponse,
+	routing::{get, post},
+	Json, Router, TypedHeader,
+};
+use tracing::{debug, error, info};
+
+use crate::{
+	audit::{self, AuditAction, AuditEvent},
+	errors::AppError,
+	memory_store::StoreError,
+	services::auth::{load_session_from_cookies, load_user, store_session_with_cookie, User},
+	AppState,
+};
+
+use super::{
+	AdminUser, ImpersonateRequest, ImpersonationResponse, MetricsResponse, IMPERSONATOR_KEY,
+};
+
+pub fn routes() -> Router<AppState> {
+	// /admin
+	Router::new()
+		.route("/impersonate", post(start_impersonation))
+		.route("/impersonate/stop", post(stop_impersonation))
+		.route("/metrics", get(metrics))
+}
+
+fn store_error(e: StoreError) -> AppError {
+	error!("Impersonation store error: {}", e);
+	AppError::from(e)
+}
+
+// Replace the admin session with one resolving to the target user
+async fn start_impersonation(
+	State(app_state): State<AppState>,
+	AdminUser(admin): AdminUser,
+	TypedHeader(cookies): TypedHeader<headers::Cookie>,
+	Json(request_body): Json<ImpersonateRequest>,
+) -> Result<impl IntoResponse, AppError> {
+	let memory_store = app_state.memory_store;
+	let admin_session = load_session_from_cookies(memory_store.as_ref(), &cookies)
+		.await
+		.map_err(store_error)?
+		.ok_or(AppError::Forbidden)?;
+	if admin_session.get::<User>(IMPERSONATOR_KEY).is_some() {
+		// Impersonations cannot be chained, the admin has to stop the current one first
+		return Err(AppError::Forbidden);
+	}
+	if request_body.email.eq_ignore_ascii_case(&admin.email) {
+		return Err(AppError::ValidationError {
+			field: "email".to_string(),
+		});
+	}
+	let target = load_user(memory_store.as_ref(), &request_body.email)
+		.await
+		.map_err(store_error)?
+		.ok_or(AppError::NotFound {
+			resource: "user".to_string(),
+		})?;
+
+	// Impersonating without leaving a trace is not allowed
+	audit::record(
+		memory_store.as_ref(),
+		AuditEvent::new(
+			AuditAction::ImpersonationStarted,
+			&admin.email,
+			&target.email,
+		),
+	)
+	.await
+	.map_err(|_| AppError::InternalError)?;
+	info!("{} started impersonating {}", admin.email, target.email);
+
+	debug!("Replace the admin session with the impersonation session");
+	memory_store
+		.destroy_session(admin_session)
+		.await
+		.map_err(store_error)?;
+	let mut session = Session::new();
+	session.insert("user", &target).unwrap();
+	session.insert(IMPERSONATOR_KEY, &admin).unwrap();
+	let headers =
+		store_session_with_cookie(memory_store.as_ref(), &app_state.config.cookies, session)
+			.await
+			.map_err(store_error)?;
+
+	Ok((
+		headers,
+		Json(ImpersonationResponse {
+			user: target.email,
+			impersonator: Some(admin.email),
+		}),
+	))
+}
+
+// Return the admin to their own account. The session resolves to the target user,
+// so the admin identity is taken from the session instead of the `AdminUser` extractor
+async fn stop_impersonation(
+	State(app_state): State<AppState>,
+	TypedHeader(cookies): TypedHeader<headers::Cookie>,
+) -> Result<impl IntoResponse, AppError> {
+	let memory_store = app_state.memory_store;
+	let not_impersonating = || AppError::NotFound {
+		resource: "impersonation".to_string(),
+	};
+	let session = load_session_from_cookies(memory_store.as_ref(), &cookies)
+		.await
+		.map_err(store_error)?
+		.ok_or_else(not_impersonating)?;
+	let admin = session
+		.get::<User>(IMPERSONATOR_KEY)
+		.ok_or_else(not_impersonating)?;
+	let target = session.get::<User>("user").ok_or_else(not_impersonating)?;
+
+	audit::record(
+		memory_store.as_ref(),
+		AuditEvent::new(
+			AuditAction::ImpersonationStopped,
+			&admin.email,
+			&target.email,
+		),
+	)
+	.await
+	.map_err(|_| AppError::InternalError)?;
+	info!("{} stopped impersonating {}", admin.email, target.email);
+
+	debug!("Replace the impersonation session with a new admin session");
+	memory_store
+		.destroy_session(session)
+		.await
+		.map_err(store_error)?;
+	let mut session = Session::new();
+	session.insert("user", &admin).unwrap();
+	let headers =
+		store_session_with_cookie(memory_store.as_ref(), &app_state.config.cookies, session)
+			.await
+			.map_err(store_error)?;
+
+	Ok((
+		headers,
+		Json(ImpersonationResponse {
+			user: admin.email,
+			impersonator: None,
+		}),
+	))
+}
+
+async fn metrics(State(app_state): State<AppState>, _admin: AdminUser) -> impl IntoResponse {
+	Json(MetricsResponse {
+		memory_store: app_state.memory_store.metrics(),
+	})
+}
@@ -0,0 +1,5 @@
+mod admin_dto;
+mod admin_routes;
+
+pub use admin_dto::*;
+pub use admin_routes::*;
@@ -0,0 +1,203 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+	memory_store::{user_hash_tag, MemoryStore, StoreResult},
+	rate_limit,
+	tokens::{generate_token, hash_token},
+};
+
+use super::{load_user, User};
+
+/// Permission an API token can be limited to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+	#[serde(rename = "goodbye:write")]
+	GoodbyeWrite,
+	#[serde(rename = "hello:write")]
+	HelloWrite,
+}
+
+impl Scope {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Scope::GoodbyeWrite => "goodbye:write",
+			Scope::HelloWrite => "hello:write",
+		}
+	}
+}
+
+impl fmt::Display for Scope {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.as_str())
+	}
+}
+
+impl FromStr for Scope {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"goodbye:write" => Ok(Scope::GoodbyeWrite),
+			"hello:write" => Ok(Scope::HelloWrite),
+			_ => Err(format!("unknown scope: {}", s)),
+		}
+	}
+}
+
+/// Bearer token granting API access on behalf of a user, stored under the hash of its secret
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiToken {
+	pub id: String,
+	pub name: String,
+	pub email: String,
+	/// Scopes the token is limited to. Tokens without scopes, such as the ones issued to
+	/// devices, are granted every scope
+	pub scopes: Option<Vec<Scope>>,
+	/// Milliseconds since the Unix epoch
+	pub created_at: u64,
+	/// Milliseconds since the Unix epoch, tokens without expiry are valid until revoked
+	pub expires_at: Option<u64>,
+}
+
+impl ApiToken {
+	fn is_expired(&self) -> bool {
+		self.expires_at
+			.is_some_and(|expires_at| expires_at <= rate_limit::now_ms())
+	}
+}
+
+/// Parameters of a token to issue
+pub struct NewApiToken<'a> {
+	pub email: &'a str,
+	pub name: &'a str,
+	pub scopes: Option<Vec<Scope>>,
+	pub ttl: Option<Duration>,
+}
+
+fn api_token_key(token_hash: &str) -> String {
+	format!("api_token:{}", token_hash)
+}
+
+/// Hashes of the tokens issued to a user, to list and revoke them. The list is only ever
+/// appended to so that concurrent issuing cannot lose tokens, hashes of revoked and expired
+/// tokens are skipped when reading it
+fn api_token_index_key(email: &str) -> String {
+	format!("api_tokens:{}", user_hash_tag(email))
+}
+
+async fn load_api_token(
+	memory_store: &dyn MemoryStore,
+	token_hash: &str,
+) -> StoreResult<Option<ApiToken>> {
+	match memory_store.get_value(&api_token_key(token_hash)).await? {
+		Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+		None => Ok(None),
+	}
+}
+
+/// Issue a new API token for the user, returning it along with its secret. Only the hash of
+/// the secret is stored
+pub async fn issue_api_token(
+	memory_store: &dyn MemoryStore,
+	new_token: NewApiToken<'_>,
+) -> StoreResult<(ApiToken, String)> {
+	let token = generate_token();
+	let token_hash = hash_token(&token);
+	let now = rate_limit::now_ms();
+	let api_token = ApiToken {
+		id: generate_token()[..16].to_string(),
+		name: new_token.name.to_string(),
+		email: new_token.email.to_string(),
+		scopes: new_token.scopes,
+		created_at: now,
+		expires_at: new_token.ttl.map(|ttl| now + ttl.as_millis() as u64),
+	};
+	memory_store
+		.set_value(
+			&api_token_key(&token_hash),
+			&serde_json::to_string(&api_token)?,
+			new_token.ttl,
+		)
+		.await?;
+
+	memory_store
+		.push_value(&api_token_index_key(new_token.email), &token_hash)
+		.await?;
+	Ok((api_token, token))
+}
+
+/// Find the user an API token was issued for along with the token, if the token is valid
+pub async fn resolve_api_token(
+	memory_store: &dyn MemoryStore,
+	token: &str,
+) -> StoreResult<Option<(User, ApiToken)>> {
+	let api_token = match load_api_token(memory_store, &hash_token(token)).await? {
+		Some(api_token) if !api_token.is_expired() => api_token,
+		_ => return Ok(None),
+	};
+	Ok(load_user(memory_store, &api_token.email)
+		.await?
+		.map(|user| (user, api_token)))
+}
+
+/// Get the valid tokens issued to a user, oldest first
+pub async fn list_api_tokens(
+	memory_store: &dyn MemoryStore,
+	email: &str,
+) -> StoreResult<Vec<ApiToken>> {
+	let mut api_tokens = vec![];
+	for token_hash in memory_store
+		.list_values(&api_token_index_key(email))
+		.await?
+	{
+		if let Some(api_token) = load_api_token(memory_store, &token_hash).await? {
+			if !api_token.is_expired() {
+				api_tokens.push(api_token);
+			}
+		}
+	}
+	Ok(api_tokens)
+}
+
+/// Revoke one of the tokens of a user by its id, returning whether it existed
+pub async fn revoke_api_token(
+	memory_store: &dyn MemoryStore,
+	email: &str,
+	id: &str,
+) -> StoreResult<bool> {
+	for token_hash in memory_store
+		.list_values(&api_token_index_key(email))
+		.await?
+	{
+		let Some(api_token) = load_api_token(memory_store, &token_hash).await? else {
+			continue;
+		};
+		if api_token.id == id {
+			// Taking the token makes sure that only one of concurrent revocations succeeds
+			return Ok(memory_store
+				.take_value(&api_token_key(&token_hash))
+				.await?
+				.is_some());
+		}
+	}
+	Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_scope_round_trip() {
+		for scope in [Scope::GoodbyeWrite, Scope::HelloWrite] {
+			assert_eq!(scope.as_str().parse::<Scope>(), Ok(scope));
+			assert_eq!(
+				serde_json::to_string(&scope).unwrap(),
+				format!("\"{}\"", scope)
+			);
+		}
+		assert!("admin".parse::<Scope>().is_err());
+	}
+}
@@ -1,29 +1,92 @@
 use async_session::async_trait;
 use axum::{
-	extract::{rejection::TypedHeaderRejectionReason, FromRef, FromRequestParts, TypedHeader},
+	extract::{FromRef, FromRequestParts, TypedHeader},
+	http::StatusCode,
 	response::{IntoResponse, Redirect, Response},
-	RequestPartsExt,
+	Json, RequestPartsExt,
 };
-use http::{header, request::Parts};
+use headers::{authorization::Bearer, Authorization};
+use http::{header::AUTHORIZATION, request::Parts};
 use serde_derive::{Deserialize, Serialize};
-use tracing::debug;
+use serde_json::json;
+use tracing::{debug, error};
 
-use crate::AppState;
+use crate::{
+	errors::AppError,
+	memory_store::StoreError,
+	services::admin::{Impersonator, IMPERSONATOR_KEY},
+	tokens::hash_token,
+	AppState,
+};
+
+use super::{resolve_api_token, session_epoch, ApiToken, Scope};
 
 pub static COOKIE_NAME: &str = "SESSION";
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Session key flagging sessions that still have to pass the two factor step
+pub static TWO_FACTOR_PENDING_KEY: &str = "two_factor_pending";
+
+/// Session key holding the session epoch of the user when the session was created
+pub static SESSION_EPOCH_KEY: &str = "session_epoch";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct User {
 	pub email: String,
 	#[serde(default)] // Allows null values in the attribute when the discord object is not there
 	pub discord: Option<DiscordUser>,
 	#[serde(default)] // Allows null values in the attribute when the google object is not there
 	pub google: Option<GoogleUser>,
+	#[serde(default)] // Allows null values in the attribute when the user has no local account
+	pub local: Option<LocalUser>,
+}
+
+impl User {
+	/// Opaque and stable identifier. Users are keyed by their email, so it is derived from it
+	pub fn id(&self) -> String {
+		hash_token(&self.email.to_lowercase())[..32].to_string()
+	}
+
+	/// The name of the first linked provider having one, falling back to the email
+	pub fn display_name(&self) -> String {
+		self.google
+			.as_ref()
+			.map(|google| google.name.clone())
+			.or_else(|| {
+				self.discord
+					.as_ref()
+					.map(|discord| discord.username.clone())
+			})
+			.or_else(|| self.local.as_ref().map(|local| local.username.clone()))
+			.unwrap_or_else(|| self.email.clone())
+	}
+
+	pub fn avatar_url(&self) -> Option<String> {
+		self.discord
+			.as_ref()
+			.map(DiscordUser::avatar_url)
+			.or_else(|| {
+				self.google
+					.as_ref()
+					.and_then(|google| google.picture.clone())
+			})
+	}
+
+	pub fn providers(&self) -> Vec<String> {
+		[
+			("discord", self.discord.is_some()),
+			("google", self.google.is_some()),
+			("local", self.local.is_some()),
+		]
+		.iter()
+		.filter(|(_, linked)| *linked)
+		.map(|(provider, _)| provider.to_string())
+		.collect()
+	}
 }
 
 // The user data we'll get back from Discord.
 // https://discord.com/developers/docs/resources/user#user-object-user-structure
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DiscordUser {
 	pub id: String,
 	#[serde(default)]
@@ -33,17 +96,203 @@ pub struct DiscordUser {
 	pub email: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// The user data we'll get back from Google.
+// https://www.googleapis.com/oauth2/v2/userinfo
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GoogleUser {
+	// Missing from the profiles stored before it was requested
+	#[serde(default)]
+	pub id: Option<String>,
 	pub email: String,
 	pub name: String,
+	#[serde(default)]
+	pub picture: Option<String>,
+}
+
+impl DiscordUser {
+	// https://discord.com/developers/docs/reference#image-formatting
+	pub fn avatar_url(&self) -> String {
+		match &self.avatar {
+			Some(hash) => {
+				let extension = if hash.starts_with("a_") { "gif" } else { "png" };
+				format!(
+					"https://cdn.discordapp.com/avatars/{}/{}.{}",
+					self.id, hash, extension
+				)
+			}
+			None => {
+				// Users migrated to the new username system have a "0" discriminator
+				let index = if self.discriminator == "0" {
+					self.id.parse::<u64>().map(|id| (id >> 22) % 6)
+				} else {
+					self.discriminator.parse::<u64>().map(|d| d % 5)
+				};
+				format!(
+					"https://cdn.discordapp.com/embed/avatars/{}.png",
+					index.unwrap_or_default()
+				)
+			}
+		}
+	}
+}
+
+// The user data of a local account, whose credentials are kept in the user store
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LocalUser {
+	pub username: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LocalRegisterRequest {
+	pub email: String,
+	pub username: String,
+	pub password: String,
+}
+
+/// Registration waiting for the owner of the email to follow the verification link
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PendingRegistration {
+	pub email: String,
+	pub username: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LocalVerifyRequest {
+	pub token: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LocalLoginRequest {
+	pub email: String,
+	pub password: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PasswordChangeRequest {
+	pub current_password: String,
+	pub new_password: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MagicLinkRequest {
+	pub email: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MagicLinkVerifyRequest {
+	pub token: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TwoFactorCodeRequest {
+	/// Code from the authenticator app, or a recovery code
+	pub code: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TwoFactorEnrollResponse {
+	/// Base32 encoded secret, for authenticator apps that cannot scan QR codes
+	pub secret: String,
+	/// `otpauth://` provisioning URI, to be rendered as a QR code
+	pub otpauth_uri: String,
+	/// Single use codes to log in without the authenticator app. They are only shown once
+	pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DeviceAuthorizationResponse {
+	pub device_code: String,
+	pub user_code: String,
+	pub verification_uri: String,
+	pub verification_uri_complete: String,
+	/// Seconds until the codes expire
+	pub expires_in: u64,
+	/// Minimum number of seconds between two token requests
+	pub interval: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DeviceUserCodeRequest {
+	pub user_code: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DeviceTokenRequest {
+	pub device_code: String,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DeviceTokenResponse {
+	pub access_token: String,
+	pub token_type: String,
+	/// Seconds until the access token expires
+	pub expires_in: u64,
+}
+
+/// Body of `GET /auth/me`. Fields may be added to this shape, but never renamed or removed
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ProfileResponse {
+	pub id: String,
+	pub email: String,
+	pub display_name: String,
+	/// Discord avatar, or Google picture for users without Discord
+	pub avatar_url: Option<String>,
+	/// Linked login providers among `discord`, `google` and `local`
+	pub providers: Vec<String>,
+	pub session: SessionInfo,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ApiTokenCreateRequest {
+	pub name: String,
+	pub scopes: Vec<String>,
+	/// Seconds until the token expires, it is valid until revoked when absent
+	pub expires_in: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ApiTokenCreatedResponse {
+	#[serde(flatten)]
+	pub api_token: ApiToken,
+	/// Secret of the token. It is only shown once
+	pub token: String,
+}
+
+/// Errors of the device access token endpoint, as defined in RFC 8628 section 3.5
+#[derive(Debug, PartialEq)]
+pub enum DeviceTokenError {
+	AuthorizationPending,
+	SlowDown,
+	AccessDenied,
+	ExpiredToken,
+	ServerError,
+}
+
+impl IntoResponse for DeviceTokenError {
+	fn into_response(self) -> Response {
+		let (status, error) = match self {
+			DeviceTokenError::AuthorizationPending => {
+				(StatusCode::BAD_REQUEST, "authorization_pending")
+			}
+			DeviceTokenError::SlowDown => (StatusCode::BAD_REQUEST, "slow_down"),
+			DeviceTokenError::AccessDenied => (StatusCode::BAD_REQUEST, "access_denied"),
+			DeviceTokenError::ExpiredToken => (StatusCode::BAD_REQUEST, "expired_token"),
+			DeviceTokenError::ServerError => (StatusCode::INTERNAL_SERVER_ERROR, "server_error"),
+		};
+		(status, Json(json!({ "error": error }))).into_response()
+	}
+}
+
+// Query of the provider redirects to the callbacks. They carry an error instead of a code
+// when the login did not go through.
+// https://www.rfc-editor.org/rfc/rfc6749#section-4.1.2
 #[derive(Debug, Deserialize)]
 #[allow(dead_code)]
 pub struct OAuthRequest {
-	pub code: String,
-	pub state: String,
+	pub code: Option<String>,
+	pub state: Option<String>,
+	pub error: Option<String>,
+	pub error_description: Option<String>,
 }
 
 pub struct DiscordAuthRedirect;
@@ -54,42 +303,230 @@ impl IntoResponse for DiscordAuthRedirect {
 	}
 }
 
+/// Why the `User` extractor rejected a request
+#[derive(Debug)]
+pub enum AuthRejection {
+	/// No valid session or token, redirect to the auth page
+	LoginRequired,
+	/// The credentials could not be checked
+	Store(AppError),
+}
+
+impl IntoResponse for AuthRejection {
+	fn into_response(self) -> Response {
+		match self {
+			AuthRejection::LoginRequired => DiscordAuthRedirect.into_response(),
+			AuthRejection::Store(e) => e.into_response(),
+		}
+	}
+}
+
+impl From<StoreError> for AuthRejection {
+	fn from(e: StoreError) -> Self {
+		error!("Unable to authenticate request: {}", e);
+		AuthRejection::Store(AppError::from(e))
+	}
+}
+
 #[async_trait]
 impl<S> FromRequestParts<S> for User
 where
 	AppState: FromRef<S>,
 	S: Send + Sync,
 {
-	// If anything goes wrong or no session is found, redirect to the auth page
-	type Rejection = DiscordAuthRedirect;
+	type Rejection = AuthRejection;
 
 	async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
 		debug!("Analyzing request in User middleware {:?}", parts);
 		let memory_store = <AppState>::from_ref(state).memory_store;
 
+		// API tokens are only accepted by handlers opting in through `GrantedScopes`
+		if parts.headers.contains_key(AUTHORIZATION) {
+			return match parts.extensions.get::<TokenUser>() {
+				Some(TokenUser(user)) => Ok(user.clone()),
+				None => {
+					debug!("Route does not accept API tokens");
+					Err(AuthRejection::LoginRequired)
+				}
+			};
+		}
+
+		// Missing and malformed cookies alike mean that there is no session
 		let cookies = parts
 			.extract::<TypedHeader<headers::Cookie>>()
 			.await
-			.map_err(|e| match *e.name() {
-				header::COOKIE => match e.reason() {
-					TypedHeaderRejectionReason::Missing => DiscordAuthRedirect,
-					_ => panic!("unexpected error getting Cookie header(s): {}", e),
-				},
-				_ => panic!("unexpected error getting cookies: {}", e),
-			})?;
+			.map_err(|_| AuthRejection::LoginRequired)?;
 		debug!("Loaded cookies {:?}", cookies);
-		let session_cookie = cookies.get(COOKIE_NAME).ok_or(DiscordAuthRedirect)?;
+		let session_cookie = cookies
+			.get(COOKIE_NAME)
+			.ok_or(AuthRejection::LoginRequired)?;
 
 		debug!("Loaded session cookie {:?}", session_cookie);
 		let session = memory_store
 			.load_session(session_cookie.to_string())
-			.await
-			.unwrap()
-			.ok_or(DiscordAuthRedirect)?;
+			.await?
+			.ok_or(AuthRejection::LoginRequired)?;
 
 		debug!("Loaded session {:?}", session);
-		let user = session.get::<User>("user").ok_or(DiscordAuthRedirect)?;
+		if session.get::<bool>(TWO_FACTOR_PENDING_KEY).unwrap_or(false) {
+			debug!("Session is waiting for the two factor step");
+			return Err(AuthRejection::LoginRequired);
+		}
+		let user = session
+			.get::<User>("user")
+			.ok_or(AuthRejection::LoginRequired)?;
+		// The near cache serves the current epoch along with the session, when enabled
+		let epoch = session.get::<u64>(SESSION_EPOCH_KEY).unwrap_or(0);
+		if epoch < session_epoch(memory_store.as_ref(), &user.email).await? {
+			debug!("Session was ended by a later password change");
+			return Err(AuthRejection::LoginRequired);
+		}
+		let impersonated_by = session
+			.get::<User>(IMPERSONATOR_KEY)
+			.map(|impersonator| impersonator.email);
+		if let (Some(admin), Some(impersonator)) =
+			(&impersonated_by, parts.extensions.get::<Impersonator>())
+		{
+			impersonator.set(admin.clone());
+		}
+		parts.extensions.insert(SessionInfo::Cookie {
+			expires_at: session
+				.expiry()
+				.map(|expiry| expiry.timestamp_millis() as u64),
+			impersonated_by,
+		});
 
 		Ok(user)
 	}
 }
+
+/// User an API token was issued for, recorded by `GrantedScopes` for the `User` extractor
+#[derive(Clone)]
+struct TokenUser(User);
+
+/// Resolve the bearer token of the request, if it has one, recording its user and session info
+async fn resolve_bearer(parts: &mut Parts, app_state: &AppState) -> Result<(), AuthRejection> {
+	if parts.extensions.get::<TokenUser>().is_some() {
+		return Ok(());
+	}
+	// API clients such as the CLI authenticate with a bearer token instead of a session
+	let Ok(TypedHeader(Authorization(bearer))) =
+		parts.extract::<TypedHeader<Authorization<Bearer>>>().await
+	else {
+		return Ok(());
+	};
+	debug!("Resolving API token");
+	let (user, api_token) = resolve_api_token(app_state.memory_store.as_ref(), bearer.token())
+		.await?
+		.ok_or(AuthRejection::LoginRequired)?;
+	parts.extensions.insert(SessionInfo::ApiToken {
+		token_id: api_token.id,
+		name: api_token.name,
+		scopes: api_token.scopes,
+		expires_at: api_token.expires_at,
+	});
+	parts.extensions.insert(TokenUser(user));
+	Ok(())
+}
+
+/// How the current request was authenticated, recorded by the `User` and `GrantedScopes`
+/// extractors
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum SessionInfo {
+	Cookie {
+		/// Milliseconds since the Unix epoch, sessions without expiry last until logout
+		expires_at: Option<u64>,
+		/// Email of the admin impersonating the user
+		impersonated_by: Option<String>,
+	},
+	ApiToken {
+		token_id: String,
+		name: String,
+		/// Every scope is granted when absent
+		scopes: Option<Vec<Scope>>,
+		/// Milliseconds since the Unix epoch, tokens without expiry last until revoked
+		expires_at: Option<u64>,
+	},
+}
+
+impl SessionInfo {
+	/// Admins impersonating a user must not create credentials or change security settings
+	/// that outlive the impersonation
+	pub fn ensure_not_impersonated(&self) -> Result<(), AppError> {
+		if let SessionInfo::Cookie {
+			impersonated_by: Some(admin),
+			..
+		} = self
+		{
+			debug!("Not allowed while {} is impersonating the user", admin);
+			return Err(AppError::Forbidden);
+		}
+		Ok(())
+	}
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for SessionInfo
+where
+	AppState: FromRef<S>,
+	S: Send + Sync,
+{
+	type Rejection = Response;
+
+	async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+		if parts.extensions.get::<SessionInfo>().is_none() {
+			User::from_request_parts(parts, state)
+				.await
+				.map_err(IntoResponse::into_response)?;
+		}
+		parts
+			.extensions
+			.get::<SessionInfo>()
+			.cloned()
+			.ok_or_else(|| AppError::Unauthorized.into_response())
+	}
+}
+
+/// Scopes granted to the current request. Sessions and unscoped API tokens are granted every
+/// scope. Handlers accepting API tokens extract it before `User`, which otherwise only accepts
+/// sessions
+#[derive(Clone, Debug, PartialEq)]
+pub struct GrantedScopes(pub Option<Vec<Scope>>);
+
+impl GrantedScopes {
+	pub fn is_unrestricted(&self) -> bool {
+		self.0.is_none()
+	}
+
+	pub fn allows(&self, scope: Scope) -> bool {
+		self.0.as_ref().is_none_or(|scopes| scopes.contains(&scope))
+	}
+
+	pub fn require(&self, scope: Scope) -> Result<(), AppError> {
+		if !self.allows(scope) {
+			debug!("Request is missing the {} scope", scope);
+			return Err(AppError::Forbidden);
+		}
+		Ok(())
+	}
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for GrantedScopes
+where
+	AppState: FromRef<S>,
+	S: Send + Sync,
+{
+	type Rejection = Response;
+
+	async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+		resolve_bearer(parts, &AppState::from_ref(state))
+			.await
+			.map_err(IntoResponse::into_response)?;
+		match SessionInfo::from_request_parts(parts, state).await? {
+			SessionInfo::Cookie { .. } => Ok(GrantedScopes(None)),
+			SessionInfo::ApiToken { scopes, .. } => Ok(GrantedScopes(scopes)),
+		}
+	}
+}
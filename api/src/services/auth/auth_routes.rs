@@ -1,16 +1,30 @@
-use crate::{services::auth::GoogleUser, AppState};
+use std::time::Duration;
 
-use super::{auth_dto::DiscordUser, OAuthRequest, User, COOKIE_NAME};
-use async_session::Session;
+use crate::{errors::AppError, memory_store::StoreError, services::auth::GoogleUser, AppState};
+
+use super::{
+	auth_dto::DiscordUser, device_routes::device_routes, email_routes::email_routes,
+	local_routes::local_routes, login_session, save_provider_token, save_user,
+	store_session_with_cookie, take_provider_token, token_routes::token_routes,
+	two_factor_routes::two_factor_routes, AuthRejection, GrantedScopes, OAuthRequest,
+	ProfileResponse, ProviderToken, ProviderType, SessionInfo, User, COOKIE_NAME,
+};
 use axum::{
 	extract::{Query, State},
-	http::{header::SET_COOKIE, HeaderMap},
+	http::HeaderMap,
 	response::{IntoResponse, Redirect},
 	routing::get,
-	Router, TypedHeader,
+	Json, Router, TypedHeader,
 };
-use oauth2::{reqwest::async_http_client, AuthorizationCode, CsrfToken, Scope, TokenResponse};
-use tracing::debug;
+use oauth2::{
+	basic::BasicTokenResponse, reqwest::async_http_client, AuthorizationCode, CsrfToken,
+	RequestTokenError, Scope, TokenResponse,
+};
+use serde::de::DeserializeOwned;
+use tracing::{debug, error, info, warn};
+
+/// How long to wait for the providers during the callbacks
+const PROVIDER_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub fn routes() -> Router<AppState> {
 	// /auth
@@ -20,13 +34,17 @@ pub fn routes() -> Router<AppState> {
 		.route("/google", get(google_login))
 		.route("/google/authorized", get(google_authorized))
 		.route("/logout", get(logout))
+		.route("/me", get(me))
+		.nest("/device", device_routes())
+		.nest("/email", email_routes())
+		.nest("/local", local_routes())
+		.nest("/tokens", token_routes())
+		.nest("/2fa", two_factor_routes())
 }
 
 // To be called when requesting a login to Discord
 async fn discord_login(State(app_state): State<AppState>) -> impl IntoResponse {
-	let oauth_client = app_state
-		.oauth_providers
-		.client(crate::services::auth::ProviderType::Discord);
+	let oauth_client = app_state.oauth_providers.client(ProviderType::Discord);
 	let (auth_url, _csrf_token) = oauth_client
 		.authorize_url(CsrfToken::new_random)
 		.add_scope(Scope::new("identify".to_string()))
@@ -42,60 +60,35 @@ async fn discord_login(State(app_state): State<AppState>) -> impl IntoResponse {
 async fn discord_authorized(
 	Query(query): Query<OAuthRequest>,
 	State(app_state): State<AppState>,
-) -> impl IntoResponse {
-	let memory_store = app_state.memory_store;
-	let oauth_client = app_state
-		.oauth_providers
-		.client(crate::services::auth::ProviderType::Discord);
-
-	debug!("Get auth token from oauth client with the given exchange code");
-	let token = oauth_client
-		.exchange_code(AuthorizationCode::new(query.code.clone()))
-		.request_async(async_http_client)
-		.await
-		.unwrap();
+) -> Result<impl IntoResponse, AppError> {
+	let provider = ProviderType::Discord;
+	let token = exchange_code(&app_state, provider, query).await?;
 
 	// Fetch user data from discord
 	debug!("Fetch user data from discord");
-	let client = reqwest::Client::new();
-	let discord_user: DiscordUser = client
+	let discord_user: DiscordUser = fetch_user_info(
+		provider,
 		// https://discord.com/developers/docs/resources/user#get-current-user
-		.get("https://discordapp.com/api/users/@me")
-		.bearer_auth(token.access_token().secret())
-		.send()
-		.await
-		.unwrap()
-		.json::<DiscordUser>()
-		.await
-		.unwrap();
+		"https://discordapp.com/api/users/@me",
+		&token,
+	)
+	.await?;
 	let user = User {
 		email: discord_user.email.clone(),
 		discord: Some(discord_user),
 		google: None,
+		local: None,
 	};
 
-	debug!(
-		"Create a new session for user {} and data {:?}",
-		user.email, user
-	);
-	let mut session = Session::new();
-	session.insert("user", &user).unwrap();
-
-	debug!("Store session and get corresponding cookie");
-	let cookie = memory_store.store_session(session).await.unwrap().unwrap();
+	let headers = finish_login(&app_state, provider, &user, &token).await?;
 
 	debug!("Set the cookie and redirect");
-	let cookie = format!("{}={}; SameSite=Lax; Path=/", COOKIE_NAME, cookie);
-	let mut headers = HeaderMap::new();
-	headers.insert(SET_COOKIE, cookie.parse().unwrap());
-	(headers, Redirect::to("/"))
+	Ok((headers, Redirect::to("/")))
 }
 
 // To be called when requesting a login to Google
 async fn google_login(State(app_state): State<AppState>) -> impl IntoResponse {
-	let oauth_client = app_state
-		.oauth_providers
-		.client(crate::services::auth::ProviderType::Google);
+	let oauth_client = app_state.oauth_providers.client(ProviderType::Google);
 	let (auth_url, _csrf_token) = oauth_client
 		.authorize_url(CsrfToken::new_random)
 		.add_scope(Scope::new(
@@ -114,80 +107,241 @@ async fn google_login(State(app_state): State<AppState>) -> impl IntoResponse {
 async fn google_authorized(
 	Query(query): Query<OAuthRequest>,
 	State(app_state): State<AppState>,
-) -> impl IntoResponse {
-	let memory_store = app_state.memory_store;
-	let oauth_client = app_state
-		.oauth_providers
-		.client(crate::services::auth::ProviderType::Google);
-	let mut headers = HeaderMap::new();
-
-	debug!("Get auth token from oauth client with the given exchange code");
-	let token_result = oauth_client
-		.exchange_code(AuthorizationCode::new(query.code.clone()))
-		.request_async(async_http_client)
-		.await;
-	if let Err(e) = token_result {
-		println!("Access token request error: {:?}", e);
-		return (headers, Redirect::to("/"));
-	}
-	let token = token_result.unwrap();
+) -> Result<impl IntoResponse, AppError> {
+	let provider = ProviderType::Google;
+	let token = exchange_code(&app_state, provider, query).await?;
 
 	// Fetch user data from google
 	debug!("Fetch user data from google");
-	let client = reqwest::Client::new();
-	let user_info_response = client
-		// https://discord.com/developers/docs/resources/user#get-current-user
-		.get("https://www.googleapis.com/oauth2/v2/userinfo")
-		.bearer_auth(token.access_token().secret())
-		.send()
-		.await;
-	if let Err(e) = user_info_response {
-		println!("User info request error: {:?}", e);
-		return (headers, Redirect::to("/"));
+	let google_user: GoogleUser = fetch_user_info(
+		provider,
+		"https://www.googleapis.com/oauth2/v2/userinfo",
+		&token,
+	)
+	.await?;
+	let user = User {
+		email: google_user.email.clone(),
+		discord: None,
+		google: Some(google_user),
+		local: None,
+	};
+
+	let headers = finish_login(&app_state, provider, &user, &token).await?;
+
+	debug!("Set the cookie and redirect");
+	Ok((headers, Redirect::to("/")))
+}
+
+/// Check the answer of the provider and exchange its code for tokens
+async fn exchange_code(
+	app_state: &AppState,
+	provider: ProviderType,
+	query: OAuthRequest,
+) -> Result<BasicTokenResponse, AppError> {
+	if let Some(error) = query.error {
+		// https://www.rfc-editor.org/rfc/rfc6749#section-4.1.2.1
+		if error == "access_denied" {
+			info!("The user cancelled the login with {}", provider);
+			return Err(AppError::OAuthCancelled {
+				provider: provider.to_string(),
+			});
+		}
+		warn!(
+			"{} redirected with error {}: {}",
+			provider,
+			error,
+			query.error_description.unwrap_or_default()
+		);
+		return Err(AppError::OAuthProviderError {
+			provider: provider.to_string(),
+			error,
+		});
 	}
+	let code = query.code.ok_or_else(|| AppError::ValidationError {
+		field: "code".to_string(),
+	})?;
 
-	let user_info = user_info_response
-		.unwrap()
-		.json::<GoogleUser>()
+	debug!("Get auth token from oauth client with the given exchange code");
+	let oauth_client = app_state.oauth_providers.client(provider);
+	let exchange = oauth_client
+		.exchange_code(AuthorizationCode::new(code))
+		.request_async(async_http_client);
+	let token_result = tokio::time::timeout(PROVIDER_TIMEOUT, exchange)
 		.await
-		.unwrap();
+		.map_err(|_| {
+			warn!("Token exchange with {} timed out", provider);
+			AppError::OAuthTimeout {
+				provider: provider.to_string(),
+			}
+		})?;
+	token_result.map_err(|e| {
+		let error = match &e {
+			RequestTokenError::ServerResponse(response) => response.error().to_string(),
+			RequestTokenError::Request(_) => "request failed".to_string(),
+			RequestTokenError::Parse(_, _) | RequestTokenError::Other(_) => {
+				"invalid token response".to_string()
+			}
+		};
+		warn!("Token exchange with {} failed: {:?}", provider, e);
+		AppError::OAuthProviderError {
+			provider: provider.to_string(),
+			error,
+		}
+	})
+}
 
-	let user = User {
-		email: user_info.email.clone(),
-		discord: None,
-		google: Some(user_info),
+/// Fetch the profile of the user who just logged in with the provider
+async fn fetch_user_info<T: DeserializeOwned>(
+	provider: ProviderType,
+	url: &str,
+	token: &BasicTokenResponse,
+) -> Result<T, AppError> {
+	let request_error = |e: reqwest::Error| {
+		warn!("User info request to {} failed: {}", provider, e);
+		if e.is_timeout() {
+			return AppError::OAuthTimeout {
+				provider: provider.to_string(),
+			};
+		}
+		AppError::OAuthProviderError {
+			provider: provider.to_string(),
+			error: e
+				.status()
+				.map(|status| status.to_string())
+				.unwrap_or_else(|| "request failed".to_string()),
+		}
 	};
+	let client = reqwest::Client::builder()
+		.timeout(PROVIDER_TIMEOUT)
+		.build()
+		.map_err(request_error)?;
+	let body = client
+		.get(url)
+		.bearer_auth(token.access_token().secret())
+		.send()
+		.await
+		.and_then(|response| response.error_for_status())
+		.map_err(request_error)?
+		.bytes()
+		.await
+		.map_err(request_error)?;
+	serde_json::from_slice(&body).map_err(|e| {
+		warn!("Invalid user info returned by {}: {}", provider, e);
+		AppError::OAuthInvalidUserInfo {
+			provider: provider.to_string(),
+		}
+	})
+}
 
-	debug!("Create a new session filled with user {:?}", user);
-	let mut session = Session::new();
-	session.insert("user", &user).unwrap();
+/// Save the user, log them in and keep the provider tokens for the logout
+async fn finish_login(
+	app_state: &AppState,
+	provider: ProviderType,
+	user: &User,
+	token: &BasicTokenResponse,
+) -> Result<HeaderMap, AppError> {
+	let memory_store = app_state.memory_store.as_ref();
+	let store_error = |e: StoreError| {
+		error!("Unable to log in {} with {}: {}", user.email, provider, e);
+		AppError::from(e)
+	};
 
-	debug!("Store session and get corresponding cookie");
-	let cookie = memory_store.store_session(session).await.unwrap().unwrap();
+	debug!("Keep the user profile up to date in the user store");
+	save_user(memory_store, user).await.map_err(store_error)?;
 
-	debug!("Set the cookie and redirect");
-	let cookie = format!("{}={}; SameSite=Lax; Path=/", COOKIE_NAME, cookie);
-	headers.insert(SET_COOKIE, cookie.parse().unwrap());
-	(headers, Redirect::to("/"))
+	debug!("Create a new session for user {}", user.email);
+	let session = login_session(memory_store, user)
+		.await
+		.map_err(store_error)?;
+	let provider_token = ProviderToken {
+		provider,
+		access_token: token.access_token().secret().to_string(),
+		refresh_token: token.refresh_token().map(|t| t.secret().to_string()),
+	};
+	save_provider_token(memory_store, &session, &provider_token)
+		.await
+		.map_err(store_error)?;
+	store_session_with_cookie(memory_store, &app_state.config.cookies, session)
+		.await
+		.map_err(store_error)
 }
 
 async fn logout(
 	State(app_state): State<AppState>,
 	TypedHeader(cookies): TypedHeader<headers::Cookie>,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse, AppError> {
 	let memory_store = app_state.memory_store;
+	let store_error = |e: StoreError| {
+		error!("Unable to log out: {}", e);
+		AppError::from(e)
+	};
 	let cookie = match cookies.get(COOKIE_NAME) {
 		Some(cookie) => cookie,
 		// No cookie set, just redirect
-		None => return Redirect::to("/"),
+		None => return Ok(Redirect::to("/")),
 	};
-	let session = match memory_store.load_session(cookie.to_string()).await.unwrap() {
+	let session = match memory_store
+		.load_session(cookie.to_string())
+		.await
+		.map_err(store_error)?
+	{
 		Some(s) => s,
 		// No session active, just redirect
-		None => return Redirect::to("/"),
+		None => return Ok(Redirect::to("/")),
 	};
 
-	// Session was active, destroy it and redirect
-	memory_store.destroy_session(session).await.unwrap();
-	Redirect::to("/")
+	// Session was active, destroy it along with its provider tokens
+	let provider_token = take_provider_token(memory_store.as_ref(), &session)
+		.await
+		.unwrap_or_else(|e| {
+			warn!("Unable to load provider tokens for logout: {}", e);
+			None
+		});
+	memory_store
+		.destroy_session(session)
+		.await
+		.map_err(store_error)?;
+
+	// Revocation happens in the background, so that a slow or failing provider never holds
+	// back the user logging out
+	if let Some(provider_token) = provider_token {
+		debug!("Revoke {:?} tokens", provider_token.provider);
+		let oauth_providers = app_state.oauth_providers.clone();
+		tokio::spawn(async move {
+			let revocation = oauth_providers.revoke(&provider_token);
+			match tokio::time::timeout(PROVIDER_TIMEOUT, revocation).await {
+				Ok(Ok(())) => {}
+				Ok(Err(e)) => warn!(
+					"Unable to revoke {:?} tokens on logout: {}",
+					provider_token.provider, e
+				),
+				Err(_) => warn!(
+					"Revoking {:?} tokens on logout timed out",
+					provider_token.provider
+				),
+			}
+		});
+	}
+	Ok(Redirect::to("/"))
+}
+
+// Profile of the logged in user. Unlike pages, API clients get a 401 instead of a redirect
+async fn me(
+	_scopes: Option<GrantedScopes>,
+	user: Result<User, AuthRejection>,
+	session: Option<SessionInfo>,
+) -> Result<Json<ProfileResponse>, AppError> {
+	let user = user.map_err(|rejection| match rejection {
+		AuthRejection::LoginRequired => AppError::Unauthorized,
+		AuthRejection::Store(e) => e,
+	})?;
+	let session = session.ok_or(AppError::Unauthorized)?;
+	Ok(Json(ProfileResponse {
+		id: user.id(),
+		display_name: user.display_name(),
+		avatar_url: user.avatar_url(),
+		providers: user.providers(),
+		email: user.email,
+		session,
+	}))
 }
@@ -0,0 +1,355 @@
+use std::time::Duration;
+
+use axum::{
+	extract::{Query, State},
+	response::IntoResponse,
+	routing::{get, post},
+	Json, Router,
+};
+use rand::{rngs::OsRng, Rng};
+use serde_derive::{Deserialize, Serialize};
+use tracing::{debug, error, info};
+
+use crate::{
+	errors::AppError,
+	memory_store::{MemoryStore, StoreError, StoreResult},
+	rate_limit,
+	tokens::{generate_token, hash_token},
+	AppState,
+};
+
+use super::{
+	issue_api_token, DeviceAuthorizationResponse, DeviceTokenError, DeviceTokenRequest,
+	DeviceTokenResponse, DeviceUserCodeRequest, NewApiToken, SessionInfo, User,
+};
+
+/// Characters of the user codes. Vowels are left out so that codes never spell words,
+/// as recommended by RFC 8628 section 6.1
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+const USER_CODE_LENGTH: usize = 8;
+
+pub fn device_routes() -> Router<AppState> {
+	// /auth/device
+	Router::new()
+		.route("/", post(authorize))
+		.route("/verify", get(verify))
+		.route("/approve", post(approve))
+		.route("/deny", post(deny))
+		.route("/token", post(token))
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "status")]
+enum DeviceAuthorizationStatus {
+	Pending,
+	Approved { email: String },
+	Denied,
+}
+
+/// A pending device authorization, stored under the hash of its device code
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct DeviceAuthorization {
+	user_code: String,
+	#[serde(flatten)]
+	status: DeviceAuthorizationStatus,
+	/// Milliseconds since the Unix epoch
+	expires_at: u64,
+}
+
+impl DeviceAuthorization {
+	fn remaining_ttl(&self) -> Option<Duration> {
+		let remaining = self.expires_at.saturating_sub(rate_limit::now_ms());
+		(remaining > 0).then(|| Duration::from_millis(remaining))
+	}
+}
+
+fn device_code_key(device_code_hash: &str) -> String {
+	format!("device_code:{}", device_code_hash)
+}
+
+/// Last poll of a device, kept apart so that polling never rewrites the authorization
+fn device_poll_key(device_code_hash: &str) -> String {
+	format!("device_poll:{}", device_code_hash)
+}
+
+/// Claimed by the first decision on an authorization, so that it is only decided once
+fn device_decision_key(device_code_hash: &str) -> String {
+	format!("device_decision:{}", device_code_hash)
+}
+
+fn user_code_key(user_code: &str) -> String {
+	format!("device_user_code:{}", user_code)
+}
+
+fn generate_user_code() -> String {
+	let mut rng = OsRng;
+	(0..USER_CODE_LENGTH)
+		.map(|_| USER_CODE_ALPHABET[rng.gen_range(0..USER_CODE_ALPHABET.len())] as char)
+		.collect()
+}
+
+/// Users may type codes in lowercase and with or without the dash
+fn normalize_user_code(user_code: &str) -> String {
+	user_code
+		.chars()
+		.filter(|c| c.is_ascii_alphabetic())
+		.collect::<String>()
+		.to_uppercase()
+}
+
+fn format_user_code(user_code: &str) -> String {
+	format!("{}-{}", &user_code[..4], &user_code[4..])
+}
+
+fn store_error(e: StoreError) -> AppError {
+	error!("Device authorization store error: {}", e);
+	AppError::from(e)
+}
+
+async fn load_authorization(
+	memory_store: &dyn MemoryStore,
+	device_code_hash: &str,
+) -> StoreResult<Option<DeviceAuthorization>> {
+	match memory_store
+		.get_value(&device_code_key(device_code_hash))
+		.await?
+	{
+		Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+		None => Ok(None),
+	}
+}
+
+async fn save_authorization(
+	memory_store: &dyn MemoryStore,
+	device_code_hash: &str,
+	authorization: &DeviceAuthorization,
+) -> StoreResult {
+	// Saving an expired authorization is a no-op, it is gone already
+	if let Some(ttl) = authorization.remaining_ttl() {
+		memory_store
+			.set_value(
+				&device_code_key(device_code_hash),
+				&serde_json::to_string(authorization)?,
+				Some(ttl),
+			)
+			.await?;
+	}
+	Ok(())
+}
+
+/// Find the authorization a user code refers to, returning the hash of its device code
+async fn find_by_user_code(
+	memory_store: &dyn MemoryStore,
+	user_code: &str,
+) -> Result<(String, DeviceAuthorization), AppError> {
+	let not_found = || AppError::NotFound {
+		resource: "device authorization".to_string(),
+	};
+	let device_code_hash = memory_store
+		.get_value(&user_code_key(&normalize_user_code(user_code)))
+		.await
+		.map_err(store_error)?
+		.ok_or_else(not_found)?;
+	let authorization = load_authorization(memory_store, &device_code_hash)
+		.await
+		.map_err(store_error)?
+		.ok_or_else(not_found)?;
+	Ok((device_code_hash, authorization))
+}
+
+// Start a device authorization. Called by the CLI, which shows the user code to the user
+async fn authorize(State(app_state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+	let memory_store = app_state.memory_store.as_ref();
+	let device_flow = &app_state.config.device_flow;
+	let device_code = generate_token();
+	let device_code_hash = hash_token(&device_code);
+	let user_code = generate_user_code();
+
+	let authorization = DeviceAuthorization {
+		user_code: user_code.clone(),
+		status: DeviceAuthorizationStatus::Pending,
+		expires_at: rate_limit::now_ms() + device_flow.code_ttl.as_millis() as u64,
+	};
+	save_authorization(memory_store, &device_code_hash, &authorization)
+		.await
+		.map_err(store_error)?;
+	memory_store
+		.set_value(
+			&user_code_key(&user_code),
+			&device_code_hash,
+			Some(device_flow.code_ttl),
+		)
+		.await
+		.map_err(store_error)?;
+
+	let verification_uri = format!(
+		"{}/auth/device/verify",
+		app_state.config.public_url.trim_end_matches('/')
+	);
+	let user_code = format_user_code(&user_code);
+	debug!("Device authorization started for user code {}", user_code);
+	Ok(Json(DeviceAuthorizationResponse {
+		device_code,
+		verification_uri_complete: format!("{}?user_code={}", verification_uri, user_code),
+		verification_uri,
+		user_code,
+		expires_in: device_flow.code_ttl.as_secs(),
+		interval: device_flow.poll_interval.as_secs(),
+	}))
+}
+
+// Show the pending authorization a user code refers to, to a logged in user
+async fn verify(
+	State(app_state): State<AppState>,
+	_user: User,
+	Query(query): Query<DeviceUserCodeRequest>,
+) -> Result<impl IntoResponse, AppError> {
+	let (_, authorization) =
+		find_by_user_code(app_state.memory_store.as_ref(), &query.user_code).await?;
+	// Decided authorizations are not reachable by their user code anymore
+	let expires_in = authorization.remaining_ttl().unwrap_or_default().as_secs();
+	Ok(Json(serde_json::json!({
+		"user_code": format_user_code(&authorization.user_code),
+		"status": "pending",
+		"expires_in": expires_in,
+	})))
+}
+
+async fn decide(
+	app_state: &AppState,
+	user: &User,
+	user_code: &str,
+	status: DeviceAuthorizationStatus,
+) -> Result<(), AppError> {
+	let memory_store = app_state.memory_store.as_ref();
+	let (device_code_hash, mut authorization) = find_by_user_code(memory_store, user_code).await?;
+	let already_decided = || AppError::Conflict {
+		resource: "device authorization".to_string(),
+	};
+	if authorization.status != DeviceAuthorizationStatus::Pending {
+		return Err(already_decided());
+	}
+	// Concurrent decisions both see a pending authorization, only the first to claim it wins
+	let ttl = authorization.remaining_ttl().ok_or_else(already_decided)?;
+	let claimed = memory_store
+		.set_value_if_absent(&device_decision_key(&device_code_hash), "", Some(ttl))
+		.await
+		.map_err(store_error)?;
+	if !claimed {
+		return Err(already_decided());
+	}
+	authorization.status = status;
+	save_authorization(memory_store, &device_code_hash, &authorization)
+		.await
+		.map_err(store_error)?;
+	// User codes are short, do not leave them around to be guessed once decided
+	memory_store
+		.delete_value(&user_code_key(&authorization.user_code))
+		.await
+		.map_err(store_error)?;
+	info!(
+		"Device authorization {} {:?} by {}",
+		format_user_code(&authorization.user_code),
+		authorization.status,
+		user.email
+	);
+	Ok(())
+}
+
+async fn approve(
+	State(app_state): State<AppState>,
+	user: User,
+	session_info: SessionInfo,
+	Json(request_body): Json<DeviceUserCodeRequest>,
+) -> Result<impl IntoResponse, AppError> {
+	session_info.ensure_not_impersonated()?;
+	let status = DeviceAuthorizationStatus::Approved {
+		email: user.email.clone(),
+	};
+	decide(&app_state, &user, &request_body.user_code, status).await?;
+	Ok(Json(serde_json::json!({ "status": "approved" })))
+}
+
+async fn deny(
+	State(app_state): State<AppState>,
+	user: User,
+	Json(request_body): Json<DeviceUserCodeRequest>,
+) -> Result<impl IntoResponse, AppError> {
+	let status = DeviceAuthorizationStatus::Denied;
+	decide(&app_state, &user, &request_body.user_code, status).await?;
+	Ok(Json(serde_json::json!({ "status": "denied" })))
+}
+
+// Polled by the CLI until the user approves or denies the authorization
+async fn token(
+	State(app_state): State<AppState>,
+	Json(request_body): Json<DeviceTokenRequest>,
+) -> Result<Json<DeviceTokenResponse>, DeviceTokenError> {
+	let memory_store = app_state.memory_store.as_ref();
+	let server_error = |e: StoreError| {
+		error!("Device token store error: {}", e);
+		DeviceTokenError::ServerError
+	};
+	let device_code_hash = hash_token(&request_body.device_code);
+	let authorization = load_authorization(memory_store, &device_code_hash)
+		.await
+		.map_err(server_error)?
+		.ok_or(DeviceTokenError::ExpiredToken)?;
+
+	match authorization.status {
+		DeviceAuthorizationStatus::Pending => {
+			let now = rate_limit::now_ms();
+			let poll_key = device_poll_key(&device_code_hash);
+			let last_polled_at = memory_store
+				.get_value(&poll_key)
+				.await
+				.map_err(server_error)?
+				.and_then(|last_polled_at| last_polled_at.parse::<u64>().ok());
+			let polled_too_soon = last_polled_at.is_some_and(|last_polled_at| {
+				now.saturating_sub(last_polled_at)
+					< app_state.config.device_flow.poll_interval.as_millis() as u64
+			});
+			if let Some(ttl) = authorization.remaining_ttl() {
+				memory_store
+					.set_value(&poll_key, &now.to_string(), Some(ttl))
+					.await
+					.map_err(server_error)?;
+			}
+			if polled_too_soon {
+				return Err(DeviceTokenError::SlowDown);
+			}
+			Err(DeviceTokenError::AuthorizationPending)
+		}
+		DeviceAuthorizationStatus::Denied => {
+			memory_store
+				.delete_value(&device_code_key(&device_code_hash))
+				.await
+				.map_err(server_error)?;
+			Err(DeviceTokenError::AccessDenied)
+		}
+		DeviceAuthorizationStatus::Approved { email } => {
+			// Taking the authorization guarantees a single token per device code
+			memory_store
+				.take_value(&device_code_key(&device_code_hash))
+				.await
+				.map_err(server_error)?
+				.ok_or(DeviceTokenError::ExpiredToken)?;
+			let ttl = app_state.config.api_token_ttl;
+			let new_token = NewApiToken {
+				email: &email,
+				name: "device",
+				scopes: None,
+				ttl: Some(ttl),
+			};
+			let (_, access_token) = issue_api_token(memory_store, new_token)
+				.await
+				.map_err(server_error)?;
+			info!("API token issued to a device for {}", email);
+			Ok(Json(DeviceTokenResponse {
+				access_token,
+				token_type: "Bearer".to_string(),
+				expires_in: ttl.as_secs(),
+			}))
+		}
+	}
+}
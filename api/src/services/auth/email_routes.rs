@@ -0,0 +1,132 @@
+use axum::{
+	extract::{Query, State},
+	http::StatusCode,
+	response::{IntoResponse, Redirect},
+	routing::{get, post},
+	Json, Router,
+};
+use tracing::{debug, error};
+
+use crate::{
+	errors::AppError,
+	mailer::{Email, MailerError},
+	memory_store::StoreError,
+	tokens::{generate_token, hash_token},
+	AppState,
+};
+
+use super::{
+	load_user, login_session, save_user, store_session_with_cookie, MagicLinkRequest,
+	MagicLinkVerifyRequest, User,
+};
+
+pub fn email_routes() -> Router<AppState> {
+	// /auth/email
+	Router::new()
+		.route("/", post(send_magic_link))
+		.route("/verify", get(verify_magic_link))
+}
+
+fn login_token_key(token_hash: &str) -> String {
+	format!("login_token:{}", token_hash)
+}
+
+// Email a single use login link. Only the hash of its token is kept in the store
+async fn send_magic_link(
+	State(app_state): State<AppState>,
+	Json(request_body): Json<MagicLinkRequest>,
+) -> Result<impl IntoResponse, AppError> {
+	let email = request_body.email.trim().to_lowercase();
+	if !email.contains('@') {
+		return Err(AppError::ValidationError {
+			field: "email".to_string(),
+		});
+	}
+
+	let token = generate_token();
+	app_state
+		.memory_store
+		.set_value(
+			&login_token_key(&hash_token(&token)),
+			&email,
+			Some(app_state.config.magic_link_ttl),
+		)
+		.await
+		.map_err(|e| {
+			error!("Unable to store login token: {}", e);
+			AppError::from(e)
+		})?;
+
+	let link = format!(
+		"{}/auth/email/verify?token={}",
+		app_state.config.public_url.trim_end_matches('/'),
+		token
+	);
+	let email = Email {
+		to: email,
+		subject: "Your sabi login link".to_string(),
+		body: format!(
+			"Follow this link to log in:\n\n{}\n\nIt expires in {} minutes and can only be used once.",
+			link,
+			app_state.config.magic_link_ttl.as_secs().div_ceil(60)
+		),
+	};
+	app_state.mailer.send(&email).await.map_err(|e| match e {
+		// The address passed the check above but not the one of the mailer
+		MailerError::InvalidRecipient(_) => AppError::ValidationError {
+			field: "email".to_string(),
+		},
+		e => {
+			error!("Unable to send login link to {}: {}", email.to, e);
+			AppError::InternalError
+		}
+	})?;
+
+	debug!("Login link sent to {}", email.to);
+	Ok((
+		StatusCode::ACCEPTED,
+		Json(serde_json::json!({ "status": "sent" })),
+	))
+}
+
+// Redeem a login link, creating the user the first time they log in
+async fn verify_magic_link(
+	State(app_state): State<AppState>,
+	Query(query): Query<MagicLinkVerifyRequest>,
+) -> Result<impl IntoResponse, AppError> {
+	let memory_store = app_state.memory_store.as_ref();
+	let store_error = |e: StoreError| {
+		error!("Unable to redeem login token: {}", e);
+		AppError::from(e)
+	};
+
+	// Taking the token removes it, so a link cannot be replayed
+	let email = memory_store
+		.take_value(&login_token_key(&hash_token(&query.token)))
+		.await
+		.map_err(store_error)?
+		.ok_or(AppError::Unauthorized)?;
+
+	let user = match load_user(memory_store, &email).await.map_err(store_error)? {
+		Some(user) => user,
+		None => {
+			let user = User {
+				email,
+				discord: None,
+				google: None,
+				local: None,
+			};
+			save_user(memory_store, &user).await.map_err(store_error)?;
+			user
+		}
+	};
+
+	debug!("Create a new session for {} from a login link", user.email);
+	let session = login_session(memory_store, &user)
+		.await
+		.map_err(store_error)?;
+	let headers = store_session_with_cookie(memory_store, &app_state.config.cookies, session)
+		.await
+		.map_err(store_error)?;
+	Ok((headers, Redirect::to("/")))
+}
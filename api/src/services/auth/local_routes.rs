@@ -0,0 +1,335 @@
+use axum::{
+	extract::{Query, State},
+	http::StatusCode,
+	response::{IntoResponse, Redirect},
+	routing::{get, post},
+	Json, Router, TypedHeader,
+};
+use tracing::{debug, error};
+
+use crate::{
+	errors::AppError,
+	mailer::Email,
+	memory_store::StoreError,
+	tokens::{generate_token, hash_token},
+	AppState,
+};
+
+use super::{
+	create_credentials, end_sessions, hash_password, load_credentials, load_session_from_cookies,
+	load_user, login_session, save_credentials, save_user, store_session_with_cookie,
+	verify_password, LocalCredentials, LocalLoginRequest, LocalRegisterRequest, LocalUser,
+	LocalVerifyRequest, PasswordChangeRequest, PendingRegistration, SessionInfo, User,
+	MAX_PASSWORD_LENGTH, MIN_PASSWORD_LENGTH, SESSION_EPOCH_KEY,
+};
+
+pub fn local_routes() -> Router<AppState> {
+	// /auth/local
+	Router::new()
+		.route("/register", post(register))
+		.route("/verify", get(verify))
+		.route("/login", post(login))
+		.route("/password", post(change_password))
+}
+
+fn registration_token_key(token_hash: &str) -> String {
+	format!("registration_token:{}", token_hash)
+}
+
+fn ensure_enabled(app_state: &AppState) -> Result<(), AppError> {
+	if !app_state.config.local_auth_enabled {
+		return Err(AppError::NotFound {
+			resource: "local auth".to_string(),
+		});
+	}
+	Ok(())
+}
+
+fn validate_password(password: &str, field: &str) -> Result<(), AppError> {
+	let length = password.chars().count();
+	if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&length) {
+		return Err(AppError::ValidationError {
+			field: field.to_string(),
+		});
+	}
+	Ok(())
+}
+
+/// Refuse passwords longer than any registered one before hashing them, as hashing time grows
+/// with the length
+fn ensure_not_too_long(password: &str) -> Result<(), AppError> {
+	if password.chars().count() > MAX_PASSWORD_LENGTH {
+		return Err(AppError::Unauthorized);
+	}
+	Ok(())
+}
+
+fn store_error(e: StoreError) -> AppError {
+	error!("User store error: {}", e);
+	AppError::from(e)
+}
+
+async fn start_session(app_state: &AppState, user: &User) -> Result<impl IntoResponse, AppError> {
+	debug!("Create a new session for local user {}", user.email);
+	let memory_store = app_state.memory_store.as_ref();
+	let session = login_session(memory_store, user)
+		.await
+		.map_err(store_error)?;
+	let headers = store_session_with_cookie(memory_store, &app_state.config.cookies, session)
+		.await
+		.map_err(store_error)?;
+	Ok((headers, Redirect::to("/")))
+}
+
+// Register a local account. Its credentials only become usable once the owner of the email
+// follows the link sent to it, so that nobody can claim an email they do not own
+async fn register(
+	State(app_state): State<AppState>,
+	Json(request_body): Json<LocalRegisterRequest>,
+) -> Result<impl IntoResponse, AppError> {
+	ensure_enabled(&app_state)?;
+	let email = request_body.email.trim().to_lowercase();
+	if !email.contains('@') {
+		return Err(AppError::ValidationError {
+			field: "email".to_string(),
+		});
+	}
+	if request_body.username.trim().is_empty() {
+		return Err(AppError::ValidationError {
+			field: "username".to_string(),
+		});
+	}
+	validate_password(&request_body.password, "password")?;
+
+	// Hashed before anything is looked up, so that response times do not reveal which emails
+	// have an account
+	let password_hash = hash_password(request_body.password).await.map_err(|e| {
+		error!("Unable to hash password: {}", e);
+		AppError::InternalError
+	})?;
+	let memory_store = app_state.memory_store.as_ref();
+	let credentials = LocalCredentials {
+		password_hash,
+		verified: false,
+	};
+	let ttl = app_state.config.magic_link_ttl;
+	// Never attach a password to an account created through another provider
+	let exists = load_user(memory_store, &email)
+		.await
+		.map_err(store_error)?
+		.is_some()
+		|| !create_credentials(memory_store, &email, &credentials, ttl)
+			.await
+			.map_err(store_error)?;
+	if exists {
+		// Answered like a new registration, only the owner of the email learns about it
+		send_registration_email(&app_state, already_registered_email(email)).await?;
+		return Ok(verification_sent());
+	}
+
+	let token = generate_token();
+	let registration = PendingRegistration {
+		email: email.clone(),
+		username: request_body.username.trim().to_string(),
+	};
+	let value = serde_json::to_string(&registration).map_err(StoreError::from);
+	memory_store
+		.set_value(
+			&registration_token_key(&hash_token(&token)),
+			&value.map_err(store_error)?,
+			Some(ttl),
+		)
+		.await
+		.map_err(store_error)?;
+
+	let link = format!(
+		"{}/auth/local/verify?token={}",
+		app_state.config.public_url.trim_end_matches('/'),
+		token
+	);
+	let email = Email {
+		to: email,
+		subject: "Confirm your sabi account".to_string(),
+		body: format!(
+			"Follow this link to confirm your account:\n\n{}\n\nIt expires in {} minutes. If you did not register, ignore this email.",
+			link,
+			ttl.as_secs().div_ceil(60)
+		),
+	};
+	send_registration_email(&app_state, email).await?;
+	Ok(verification_sent())
+}
+
+fn verification_sent() -> impl IntoResponse {
+	(
+		StatusCode::ACCEPTED,
+		Json(serde_json::json!({ "status": "verification_sent" })),
+	)
+}
+
+/// Notice sent instead of a verification link when the email already has an account,
+/// or a registration waiting for verification
+fn already_registered_email(email: String) -> Email {
+	Email {
+		to: email,
+		subject: "Your sabi account".to_string(),
+		body: "Someone tried to register a sabi account with this email, which already has one or is waiting for its confirmation. If it was not you, ignore this email.".to_string(),
+	}
+}
+
+async fn send_registration_email(app_state: &AppState, email: Email) -> Result<(), AppError> {
+	app_state.mailer.send(&email).await.map_err(|e| {
+		error!("Unable to send registration email to {}: {}", email.to, e);
+		AppError::InternalError
+	})?;
+	debug!("Registration email sent to {}", email.to);
+	Ok(())
+}
+
+// Confirm a registration from the link sent to its email, activating its credentials
+async fn verify(
+	State(app_state): State<AppState>,
+	Query(query): Query<LocalVerifyRequest>,
+) -> Result<impl IntoResponse, AppError> {
+	ensure_enabled(&app_state)?;
+	let memory_store = app_state.memory_store.as_ref();
+
+	// Taking the token removes it, so a link cannot be replayed
+	let registration = memory_store
+		.take_value(&registration_token_key(&hash_token(&query.token)))
+		.await
+		.map_err(store_error)?
+		.ok_or(AppError::Unauthorized)?;
+	let registration: PendingRegistration =
+		serde_json::from_str(&registration).map_err(|e| store_error(StoreError::from(e)))?;
+	let mut credentials = load_credentials(memory_store, &registration.email)
+		.await
+		.map_err(store_error)?
+		.ok_or(AppError::Unauthorized)?;
+	credentials.verified = true;
+	save_credentials(memory_store, &registration.email, &credentials)
+		.await
+		.map_err(store_error)?;
+
+	// The email may have logged in through a provider meanwhile, its profile is kept
+	let mut user = load_user(memory_store, &registration.email)
+		.await
+		.map_err(store_error)?
+		.unwrap_or_else(|| User {
+			email: registration.email.clone(),
+			discord: None,
+			google: None,
+			local: None,
+		});
+	user.local.get_or_insert(LocalUser {
+		username: registration.username,
+	});
+	save_user(memory_store, &user).await.map_err(store_error)?;
+	debug!("Local account of {} verified", user.email);
+
+	start_session(&app_state, &user).await
+}
+
+async fn login(
+	State(app_state): State<AppState>,
+	Json(request_body): Json<LocalLoginRequest>,
+) -> Result<impl IntoResponse, AppError> {
+	ensure_enabled(&app_state)?;
+	ensure_not_too_long(&request_body.password)?;
+	let email = request_body.email.trim().to_lowercase();
+	let memory_store = app_state.memory_store.as_ref();
+
+	let credentials = load_credentials(memory_store, &email)
+		.await
+		.map_err(store_error)?;
+	let verified = match credentials {
+		// Unverified credentials are checked all the same, not to reveal pending registrations
+		Some(credentials) => {
+			verify_password(request_body.password, credentials.password_hash).await
+				&& credentials.verified
+		}
+		None => {
+			// Spend the same time as a real verification, so that response times
+			// do not reveal which emails have an account
+			let _ = hash_password(request_body.password).await;
+			false
+		}
+	};
+	if !verified {
+		debug!("Invalid credentials for {}", email);
+		return Err(AppError::Unauthorized);
+	}
+
+	let user = load_user(memory_store, &email)
+		.await
+		.map_err(store_error)?
+		.ok_or(AppError::Unauthorized)?;
+	start_session(&app_state, &user).await
+}
+
+async fn change_password(
+	State(app_state): State<AppState>,
+	cookies: Option<TypedHeader<headers::Cookie>>,
+	user: User,
+	session_info: SessionInfo,
+	Json(request_body): Json<PasswordChangeRequest>,
+) -> Result<impl IntoResponse, AppError> {
+	ensure_enabled(&app_state)?;
+	session_info.ensure_not_impersonated()?;
+	validate_password(&request_body.new_password, "new_password")?;
+	let memory_store = app_state.memory_store.as_ref();
+
+	let credentials = load_credentials(memory_store, &user.email)
+		.await
+		.map_err(store_error)?
+		.filter(|credentials| credentials.verified)
+		.ok_or(AppError::NotFound {
+			resource: "local account".to_string(),
+		})?;
+	ensure_not_too_long(&request_body.current_password)?;
+	if !verify_password(request_body.current_password, credentials.password_hash).await {
+		return Err(AppError::Unauthorized);
+	}
+
+	let password_hash = hash_password(request_body.new_password)
+		.await
+		.map_err(|e| {
+			error!("Unable to hash password: {}", e);
+			AppError::InternalError
+		})?;
+	save_credentials(
+		memory_store,
+		&user.email,
+		&LocalCredentials {
+			password_hash,
+			verified: true,
+		},
+	)
+	.await
+	.map_err(store_error)?;
+	debug!("Password changed for {}", user.email);
+
+	// Sessions opened with the old password are ended, except the one changing it
+	let epoch = end_sessions(memory_store, &user.email)
+		.await
+		.map_err(store_error)?;
+	let session = match cookies {
+		Some(TypedHeader(cookies)) => load_session_from_cookies(memory_store, &cookies)
+			.await
+			.map_err(store_error)?,
+		None => None,
+	};
+	let headers = match session {
+		Some(mut session) => {
+			session
+				.insert(SESSION_EPOCH_KEY, epoch)
+				.map_err(|e| store_error(StoreError::from(e)))?;
+			store_session_with_cookie(memory_store, &app_state.config.cookies, session)
+				.await
+				.map_err(store_error)?
+		}
+		None => Default::default(),
+	};
+
+	Ok((headers, Json(serde_json::json!({ "status": "OK" }))))
+}
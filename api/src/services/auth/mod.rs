@@ -1,7 +1,22 @@
+mod api_tokens;
 mod auth_dto;
 mod auth_routes;
+mod device_routes;
+mod email_routes;
+mod local_routes;
 mod oauth;
+mod password;
+mod session;
+mod token_routes;
+mod two_factor;
+mod two_factor_routes;
+mod users;
 
+pub use api_tokens::*;
 pub use auth_dto::*;
 pub use auth_routes::*;
 pub use oauth::*;
+pub use password::*;
+pub use session::*;
+pub use two_factor::*;
+pub use users::*;
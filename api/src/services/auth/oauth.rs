@@ -1,10 +1,36 @@
-use oauth2::{basic::BasicClient, AuthUrl, ClientId, ClientSecret, RedirectUrl, TokenUrl};
-
+use derive_more::{Display, Error};
+use oauth2::{
+	basic::BasicClient, reqwest::async_http_client, AccessToken, AuthUrl, ClientId, ClientSecret,
+	RedirectUrl, RefreshToken, RevocationUrl, StandardRevocableToken, TokenUrl,
+};
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Display, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ProviderType {
+	#[display(fmt = "google")]
 	Google,
+	#[display(fmt = "discord")]
 	Discord,
 }
 
+/// Tokens obtained from a provider in an OAuth callback
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProviderToken {
+	pub provider: ProviderType,
+	pub access_token: String,
+	#[serde(default)]
+	pub refresh_token: Option<String>,
+}
+
+#[derive(Debug, Display, Error)]
+pub enum RevocationError {
+	#[display(fmt = "Token revocation is not configured: {}", _0)]
+	Configuration(oauth2::ConfigurationError),
+	#[display(fmt = "Token revocation request failed: {}", _0)]
+	Request(#[error(not(source))] String),
+}
+
 pub trait OAuthProvider {
 	fn client(&self) -> BasicClient;
 }
@@ -22,6 +48,8 @@ impl GoogleOAuthProvider {
 			.expect("Invalid authorization endpoint URL");
 		let token_url = TokenUrl::new("https://oauth2.googleapis.com/token".to_string())
 			.expect("Invalid token endpoint URL");
+		let revocation_url = RevocationUrl::new("https://oauth2.googleapis.com/revoke".to_string())
+			.expect("Invalid revocation endpoint URL");
 
 		let client = BasicClient::new(
 			google_client_id,
@@ -31,7 +59,8 @@ impl GoogleOAuthProvider {
 		)
 		.set_redirect_uri(
 			RedirectUrl::new(config.redirect_url.to_string()).expect("Invalid redirect URL"),
-		);
+		)
+		.set_revocation_uri(revocation_url);
 
 		GoogleOAuthProvider { client }
 	}
@@ -56,6 +85,9 @@ impl DiscordOAuthProvider {
 			.expect("Invalid authorization endpoint URL");
 		let token_url = TokenUrl::new("https://discord.com/api/oauth2/token".to_string())
 			.expect("Invalid token endpoint URL");
+		let revocation_url =
+			RevocationUrl::new("https://discord.com/api/oauth2/token/revoke".to_string())
+				.expect("Invalid revocation endpoint URL");
 
 		let client = BasicClient::new(
 			discord_client_id,
@@ -65,7 +97,8 @@ impl DiscordOAuthProvider {
 		)
 		.set_redirect_uri(
 			RedirectUrl::new(config.redirect_url.to_string()).expect("Invalid redirect URL"),
-		);
+		)
+		.set_revocation_uri(revocation_url);
 
 		DiscordOAuthProvider { client }
 	}
@@ -108,4 +141,38 @@ impl MultiOAuthProvider {
 			ProviderType::Discord => self.discord.client(),
 		}
 	}
+
+	/// Send the revocations of a provider to another endpoint, i.e. a local stub
+	pub fn with_revocation_url(mut self, provider_type: ProviderType, url: RevocationUrl) -> Self {
+		match provider_type {
+			ProviderType::Google => {
+				self.google.client = self.google.client.set_revocation_uri(url);
+			}
+			ProviderType::Discord => {
+				self.discord.client = self.discord.client.set_revocation_uri(url);
+			}
+		}
+		self
+	}
+
+	/// Revoke the grant behind the token at the provider's revocation endpoint.
+	///
+	/// The refresh token is preferred when there is one, as revoking it also
+	/// invalidates the access tokens issued with it
+	pub async fn revoke(&self, token: &ProviderToken) -> Result<(), RevocationError> {
+		let revocable_token = match &token.refresh_token {
+			Some(refresh_token) => {
+				StandardRevocableToken::RefreshToken(RefreshToken::new(refresh_token.clone()))
+			}
+			None => {
+				StandardRevocableToken::AccessToken(AccessToken::new(token.access_token.clone()))
+			}
+		};
+		self.client(token.provider)
+			.revoke_token(revocable_token)
+			.map_err(RevocationError::Configuration)?
+			.request_async(async_http_client)
+			.await
+			.map_err(|e| RevocationError::Request(format!("{:?}", e)))
+	}
 }
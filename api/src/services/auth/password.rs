@@ -0,0 +1,93 @@
+use argon2::{
+	password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+	Argon2,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::memory_store::{user_hash_tag, MemoryStore, StoreResult};
+
+/// Minimum number of characters of a local account password
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+/// Maximum number of characters of a local account password, as hashing time grows with it
+pub const MAX_PASSWORD_LENGTH: usize = 128;
+
+/// Credentials of a local account, kept in the user store next to the user profile
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LocalCredentials {
+	/// Argon2id hash in PHC string format, including its salt and parameters
+	pub password_hash: String,
+	/// Whether the owner of the email confirmed the registration. Credentials cannot be used
+	/// to log in until then. Credentials saved before verification existed count as
+	/// unverified, their owners still log in with a magic link
+	#[serde(default)]
+	pub verified: bool,
+}
+
+fn credentials_key(email: &str) -> String {
+	format!("credentials:{}", user_hash_tag(email))
+}
+
+/// Hash the password with Argon2id and a random salt.
+///
+/// Hashing is deliberately expensive, so it runs on the blocking thread pool
+pub async fn hash_password(password: String) -> Result<String, argon2::password_hash::Error> {
+	tokio::task::spawn_blocking(move || {
+		let salt = SaltString::generate(&mut OsRng);
+		Argon2::default()
+			.hash_password(password.as_bytes(), &salt)
+			.map(|hash| hash.to_string())
+	})
+	.await
+	.expect("Password hashing task panicked")
+}
+
+/// Check the password against a hash produced by `hash_password`
+pub async fn verify_password(password: String, password_hash: String) -> bool {
+	tokio::task::spawn_blocking(move || {
+		PasswordHash::new(&password_hash)
+			.map(|hash| {
+				Argon2::default()
+					.verify_password(password.as_bytes(), &hash)
+					.is_ok()
+			})
+			.unwrap_or(false)
+	})
+	.await
+	.expect("Password verification task panicked")
+}
+
+pub async fn save_credentials(
+	memory_store: &dyn MemoryStore,
+	email: &str,
+	credentials: &LocalCredentials,
+) -> StoreResult {
+	let value = serde_json::to_string(credentials)?;
+	memory_store
+		.set_value(&credentials_key(email), &value, None)
+		.await
+}
+
+/// Save the credentials of a new registration, unless the email already has some.
+/// They expire after `ttl` unless verified in time
+pub async fn create_credentials(
+	memory_store: &dyn MemoryStore,
+	email: &str,
+	credentials: &LocalCredentials,
+	ttl: Duration,
+) -> StoreResult<bool> {
+	let value = serde_json::to_string(credentials)?;
+	memory_store
+		.set_value_if_absent(&credentials_key(email), &value, Some(ttl))
+		.await
+}
+
+pub async fn load_credentials(
+	memory_store: &dyn MemoryStore,
+	email: &str,
+) -> StoreResult<Option<LocalCredentials>> {
+	match memory_store.get_value(&credentials_key(email)).await? {
+		Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+		None => Ok(None),
+	}
+}
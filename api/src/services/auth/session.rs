@@ -0,0 +1,133 @@
+use async_session::Session;
+use axum::http::{header::SET_COOKIE, HeaderMap};
+use tracing::debug;
+
+use crate::{
+	config::{CookieConfig, SameSite},
+	memory_store::{user_hash_tag, MemoryStore, StoreResult},
+};
+
+use super::{
+	load_two_factor, ProviderToken, User, COOKIE_NAME, SESSION_EPOCH_KEY, TWO_FACTOR_PENDING_KEY,
+};
+
+fn provider_token_key(session_id: &str) -> String {
+	format!("oauth_token:{}", session_id)
+}
+
+fn session_epoch_key(email: &str) -> String {
+	format!("session_epoch:{}", user_hash_tag(email))
+}
+
+/// Current session epoch of the user. Sessions created in an earlier epoch are not accepted
+pub async fn session_epoch(memory_store: &dyn MemoryStore, email: &str) -> StoreResult<u64> {
+	Ok(memory_store
+		.get_value(&session_epoch_key(email))
+		.await?
+		.and_then(|epoch| epoch.parse().ok())
+		.unwrap_or(0))
+}
+
+/// Log the user out of every session by starting a new epoch, which is returned
+pub async fn end_sessions(memory_store: &dyn MemoryStore, email: &str) -> StoreResult<u64> {
+	let epoch = memory_store
+		.increment_value(&session_epoch_key(email), 1, None)
+		.await?;
+	Ok(epoch as u64)
+}
+
+/// Load the session referenced by the session cookie, if any
+pub async fn load_session_from_cookies(
+	memory_store: &dyn MemoryStore,
+	cookies: &headers::Cookie,
+) -> StoreResult<Option<Session>> {
+	match cookies.get(COOKIE_NAME) {
+		Some(cookie) => memory_store.load_session(cookie.to_string()).await,
+		None => Ok(None),
+	}
+}
+
+/// Create the session of a user who just proved their identity.
+///
+/// Users enrolled in two factor authentication get a pending session,
+/// which is not accepted until they submit a valid code
+pub async fn login_session(memory_store: &dyn MemoryStore, user: &User) -> StoreResult<Session> {
+	let mut session = Session::new();
+	session.insert("user", user)?;
+	let two_factor = load_two_factor(memory_store, &user.email).await?;
+	if two_factor.map(|record| record.confirmed).unwrap_or(false) {
+		debug!("Two factor step required for {}", user.email);
+		session.insert(TWO_FACTOR_PENDING_KEY, true)?;
+	}
+	Ok(session)
+}
+
+/// Store the session and return the headers that set its cookie. New sessions are tied to the
+/// current session epoch of their user
+pub async fn store_session_with_cookie(
+	memory_store: &dyn MemoryStore,
+	cookies: &CookieConfig,
+	mut session: Session,
+) -> StoreResult<HeaderMap> {
+	debug!("Store session and get corresponding cookie");
+	if session.get::<u64>(SESSION_EPOCH_KEY).is_none() {
+		if let Some(user) = session.get::<User>("user") {
+			session.insert(
+				SESSION_EPOCH_KEY,
+				session_epoch(memory_store, &user.email).await?,
+			)?;
+		}
+	}
+	let mut headers = HeaderMap::new();
+	// No cookie value means the cookie of the client is still valid
+	if let Some(cookie) = memory_store.store_session(session).await? {
+		headers.insert(
+			SET_COOKIE,
+			session_cookie(cookies, &cookie).parse().unwrap(),
+		);
+	}
+	Ok(headers)
+}
+
+/// `Set-Cookie` value of the session cookie
+fn session_cookie(cookies: &CookieConfig, value: &str) -> String {
+	let same_site = match cookies.same_site {
+		SameSite::Lax => "Lax",
+		SameSite::None => "None",
+		SameSite::Strict => "Strict",
+	};
+	let mut cookie = format!("{}={}; SameSite={}; Path=/", COOKIE_NAME, value, same_site);
+	if let Some(domain) = &cookies.domain {
+		cookie.push_str(&format!("; Domain={}", domain));
+	}
+	if cookies.secure {
+		cookie.push_str("; Secure");
+	}
+	cookie
+}
+
+/// Keep the provider tokens of a session server side, so that they can be revoked on logout
+pub async fn save_provider_token(
+	memory_store: &dyn MemoryStore,
+	session: &Session,
+	token: &ProviderToken,
+) -> StoreResult {
+	let value = serde_json::to_string(token)?;
+	memory_store
+		.set_value(&provider_token_key(session.id()), &value, None)
+		.await
+}
+
+/// Remove the provider tokens of a session from the store, returning them
+pub async fn take_provider_token(
+	memory_store: &dyn MemoryStore,
+	session: &Session,
+) -> StoreResult<Option<ProviderToken>> {
+	match memory_store
+		.take_value(&provider_token_key(session.id()))
+		.await?
+	{
+		Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+		None => Ok(None),
+	}
+}
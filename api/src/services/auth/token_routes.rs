@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+use axum::{
+	extract::{Path, State},
+	http::StatusCode,
+	response::IntoResponse,
+	routing::{delete, get},
+	Json, Router,
+};
+use tracing::{error, info};
+
+use crate::{errors::AppError, memory_store::StoreError, AppState};
+
+use super::{
+	issue_api_token, list_api_tokens, revoke_api_token, ApiTokenCreateRequest,
+	ApiTokenCreatedResponse, GrantedScopes, NewApiToken, Scope, SessionInfo, User,
+};
+
+pub fn token_routes() -> Router<AppState> {
+	// /auth/tokens
+	Router::new()
+		.route("/", get(list).post(create))
+		.route("/:id", delete(revoke))
+}
+
+fn store_error(e: StoreError) -> AppError {
+	error!("API token store error: {}", e);
+	AppError::from(e)
+}
+
+/// Scoped tokens must not be able to mint themselves broader tokens
+fn ensure_unrestricted(scopes: &GrantedScopes) -> Result<(), AppError> {
+	if !scopes.is_unrestricted() {
+		return Err(AppError::Forbidden);
+	}
+	Ok(())
+}
+
+async fn create(
+	State(app_state): State<AppState>,
+	scopes: GrantedScopes,
+	user: User,
+	session_info: SessionInfo,
+	Json(request_body): Json<ApiTokenCreateRequest>,
+) -> Result<impl IntoResponse, AppError> {
+	ensure_unrestricted(&scopes)?;
+	session_info.ensure_not_impersonated()?;
+	let name = request_body.name.trim();
+	if name.is_empty() {
+		return Err(AppError::ValidationError {
+			field: "name".to_string(),
+		});
+	}
+	let token_scopes = request_body
+		.scopes
+		.iter()
+		.map(|scope| scope.parse::<Scope>())
+		.collect::<Result<Vec<_>, _>>()
+		.ok()
+		.filter(|token_scopes| !token_scopes.is_empty())
+		.ok_or_else(|| AppError::ValidationError {
+			field: "scopes".to_string(),
+		})?;
+	if request_body.expires_in == Some(0) {
+		return Err(AppError::ValidationError {
+			field: "expires_in".to_string(),
+		});
+	}
+
+	let new_token = NewApiToken {
+		email: &user.email,
+		name,
+		scopes: Some(token_scopes),
+		ttl: request_body.expires_in.map(Duration::from_secs),
+	};
+	let (api_token, token) = issue_api_token(app_state.memory_store.as_ref(), new_token)
+		.await
+		.map_err(store_error)?;
+	info!("API token {} created by {}", api_token.id, user.email);
+	Ok((
+		StatusCode::CREATED,
+		Json(ApiTokenCreatedResponse { api_token, token }),
+	))
+}
+
+async fn list(
+	State(app_state): State<AppState>,
+	scopes: GrantedScopes,
+	user: User,
+) -> Result<impl IntoResponse, AppError> {
+	ensure_unrestricted(&scopes)?;
+	let api_tokens = list_api_tokens(app_state.memory_store.as_ref(), &user.email)
+		.await
+		.map_err(store_error)?;
+	Ok(Json(api_tokens))
+}
+
+async fn revoke(
+	State(app_state): State<AppState>,
+	scopes: GrantedScopes,
+	user: User,
+	Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+	ensure_unrestricted(&scopes)?;
+	let revoked = revoke_api_token(app_state.memory_store.as_ref(), &user.email, &id)
+		.await
+		.map_err(store_error)?;
+	if !revoked {
+		return Err(AppError::NotFound {
+			resource: "api token".to_string(),
+		});
+	}
+	info!("API token {} revoked by {}", id, user.email);
+	Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,207 @@
+use serde_derive::{Deserialize, Serialize};
+use std::time::Duration;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::{
+	crypto::Cipher,
+	memory_store::{user_hash_tag, MemoryStore, StoreResult},
+	rate_limit,
+	tokens::{generate_token, hash_token},
+};
+
+/// Number of recovery codes handed out on enrollment
+pub const RECOVERY_CODES: usize = 10;
+/// How long used time steps are remembered. Codes are only accepted one step around the
+/// current one, so older steps would be refused anyway
+const USED_STEP_TTL: Duration = Duration::from_secs(120);
+
+/// TOTP enrollment of a user, kept in the user store
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TwoFactorRecord {
+	/// TOTP secret, encrypted with the configured two factor key
+	pub encrypted_secret: String,
+	/// Whether the user proved their authenticator works. Logins only require
+	/// a second step once this is true
+	pub confirmed: bool,
+	/// Hashes of the recovery codes handed out on enrollment. Each one is also kept under its
+	/// own key, which using the code removes
+	pub recovery_code_hashes: Vec<String>,
+}
+
+fn two_factor_key(email: &str) -> String {
+	format!("two_factor:{}", user_hash_tag(email))
+}
+
+fn recovery_code_key(email: &str, code_hash: &str) -> String {
+	format!("two_factor_recovery:{}:{}", user_hash_tag(email), code_hash)
+}
+
+fn used_step_key(email: &str, step: u64) -> String {
+	format!("two_factor_step:{}:{}", user_hash_tag(email), step)
+}
+
+fn last_step_key(email: &str) -> String {
+	format!("two_factor_last_step:{}", user_hash_tag(email))
+}
+
+pub async fn save_two_factor(
+	memory_store: &dyn MemoryStore,
+	email: &str,
+	record: &TwoFactorRecord,
+) -> StoreResult {
+	let value = serde_json::to_string(record)?;
+	memory_store
+		.set_value(&two_factor_key(email), &value, None)
+		.await
+}
+
+pub async fn load_two_factor(
+	memory_store: &dyn MemoryStore,
+	email: &str,
+) -> StoreResult<Option<TwoFactorRecord>> {
+	match memory_store.get_value(&two_factor_key(email)).await? {
+		Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+		None => Ok(None),
+	}
+}
+
+/// Replace the recovery codes of a previous enrollment, if any, with the ones of the record
+pub async fn save_recovery_codes(
+	memory_store: &dyn MemoryStore,
+	email: &str,
+	previous: Option<&TwoFactorRecord>,
+	record: &TwoFactorRecord,
+) -> StoreResult {
+	for code_hash in previous
+		.iter()
+		.flat_map(|previous| &previous.recovery_code_hashes)
+	{
+		memory_store
+			.delete_value(&recovery_code_key(email, code_hash))
+			.await?;
+	}
+	for code_hash in &record.recovery_code_hashes {
+		memory_store
+			.set_value(&recovery_code_key(email, code_hash), "", None)
+			.await?;
+	}
+	Ok(())
+}
+
+/// Use a recovery code. Taking its key guarantees that it is only accepted once
+pub async fn use_recovery_code(
+	memory_store: &dyn MemoryStore,
+	email: &str,
+	code: &str,
+) -> StoreResult<bool> {
+	Ok(memory_store
+		.take_value(&recovery_code_key(email, &hash_recovery_code(code)))
+		.await?
+		.is_some())
+}
+
+/// Record the time step of an accepted authenticator code. Codes are single use, as required by
+/// RFC 6238 section 5.2, so steps at or before the last recorded one are refused
+pub async fn use_totp_step(
+	memory_store: &dyn MemoryStore,
+	email: &str,
+	step: u64,
+) -> StoreResult<bool> {
+	// Claiming the step refuses concurrent uses of the same code
+	if !memory_store
+		.set_value_if_absent(&used_step_key(email, step), "", Some(USED_STEP_TTL))
+		.await?
+	{
+		return Ok(false);
+	}
+	let last_step = memory_store
+		.get_value(&last_step_key(email))
+		.await?
+		.and_then(|last_step| last_step.parse::<u64>().ok());
+	if last_step.is_some_and(|last_step| last_step >= step) {
+		return Ok(false);
+	}
+	memory_store
+		.set_value(
+			&last_step_key(email),
+			&step.to_string(),
+			Some(USED_STEP_TTL),
+		)
+		.await?;
+	Ok(true)
+}
+
+/// Build the RFC 6238 generator for a secret: SHA1, 6 digits and 30 seconds steps,
+/// which is what every authenticator app supports
+pub fn totp(secret: Vec<u8>, issuer: &str, email: &str) -> Option<TOTP> {
+	TOTP::new(
+		Algorithm::SHA1,
+		6,
+		1,
+		30,
+		secret,
+		Some(issuer.to_string()),
+		email.to_string(),
+	)
+	.ok()
+}
+
+/// Generate a new TOTP secret, returning its raw bytes
+pub fn generate_secret() -> Vec<u8> {
+	Secret::generate_secret()
+		.to_bytes()
+		.expect("Generated secrets are always valid")
+}
+
+/// Generate recovery codes formatted as `xxxxx-xxxxx`
+pub fn generate_recovery_codes() -> Vec<String> {
+	(0..RECOVERY_CODES)
+		.map(|_| {
+			let token = generate_token();
+			format!("{}-{}", &token[..5], &token[5..10])
+		})
+		.collect()
+}
+
+/// Hash a recovery code, ignoring the formatting users may have changed
+pub fn hash_recovery_code(code: &str) -> String {
+	let normalized: String = code
+		.chars()
+		.filter(|c| c.is_ascii_alphanumeric())
+		.collect::<String>()
+		.to_lowercase();
+	hash_token(&normalized)
+}
+
+impl TwoFactorRecord {
+	pub fn new(cipher: &Cipher, secret: &[u8], recovery_codes: &[String]) -> Self {
+		Self {
+			encrypted_secret: cipher.encrypt(secret),
+			confirmed: false,
+			recovery_code_hashes: recovery_codes
+				.iter()
+				.map(|code| hash_recovery_code(code))
+				.collect(),
+		}
+	}
+
+	/// Check a code from the authenticator app, returning the time step it was generated for.
+	/// It still has to be recorded with `use_totp_step` to be accepted
+	pub fn verify_totp(
+		&self,
+		cipher: &Cipher,
+		issuer: &str,
+		email: &str,
+		code: &str,
+	) -> Option<u64> {
+		let mut totp = cipher
+			.decrypt(&self.encrypted_secret)
+			.ok()
+			.and_then(|secret| totp(secret, issuer, email))?;
+		// Steps are checked one by one, to know which one matched
+		let skew = std::mem::take(&mut totp.skew) as u64;
+		let current = rate_limit::now_ms() / 1000 / totp.step;
+		(current.saturating_sub(skew)..=current + skew)
+			.find(|step| totp.check(code.trim(), step * totp.step))
+	}
+}
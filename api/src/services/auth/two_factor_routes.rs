@@ -0,0 +1,187 @@
+use async_session::Session;
+use axum::{
+	extract::State,
+	response::{IntoResponse, Redirect},
+	routing::post,
+	Json, Router, TypedHeader,
+};
+use totp_rs::Secret;
+use tracing::{debug, error, info};
+
+use crate::{crypto::Cipher, errors::AppError, memory_store::StoreError, AppState};
+
+use super::{
+	generate_recovery_codes, generate_secret, load_session_from_cookies, load_two_factor,
+	save_provider_token, save_recovery_codes, save_two_factor, store_session_with_cookie,
+	take_provider_token, totp, use_recovery_code, use_totp_step, SessionInfo, TwoFactorCodeRequest,
+	TwoFactorEnrollResponse, TwoFactorRecord, User, TWO_FACTOR_PENDING_KEY,
+};
+
+pub fn two_factor_routes() -> Router<AppState> {
+	// /auth/2fa
+	Router::new()
+		.route("/enroll", post(enroll))
+		.route("/confirm", post(confirm))
+		.route("/verify", post(verify))
+}
+
+fn cipher(app_state: &AppState) -> Result<Cipher, AppError> {
+	let key = app_state
+		.config
+		.two_factor
+		.encryption_key
+		.as_ref()
+		.ok_or(AppError::NotFound {
+			resource: "two factor authentication".to_string(),
+		})?;
+	Cipher::from_base64(key).map_err(|e| {
+		error!("Invalid two factor encryption key: {}", e);
+		AppError::InternalError
+	})
+}
+
+fn store_error(e: StoreError) -> AppError {
+	error!("Two factor store error: {}", e);
+	AppError::from(e)
+}
+
+// Start an enrollment. It only becomes effective once confirmed with a valid code
+async fn enroll(
+	State(app_state): State<AppState>,
+	user: User,
+	session_info: SessionInfo,
+) -> Result<impl IntoResponse, AppError> {
+	session_info.ensure_not_impersonated()?;
+	let cipher = cipher(&app_state)?;
+	let memory_store = app_state.memory_store.as_ref();
+	let previous = load_two_factor(memory_store, &user.email)
+		.await
+		.map_err(store_error)?;
+	if previous.as_ref().is_some_and(|previous| previous.confirmed) {
+		return Err(AppError::Conflict {
+			resource: "two factor authentication".to_string(),
+		});
+	}
+
+	let secret = generate_secret();
+	let issuer = app_state.config.two_factor.issuer.as_str();
+	let otpauth_uri = totp(secret.clone(), issuer, &user.email)
+		.ok_or_else(|| {
+			error!("Unable to build a TOTP generator for {}", user.email);
+			AppError::InternalError
+		})?
+		.get_url();
+	let recovery_codes = generate_recovery_codes();
+	let record = TwoFactorRecord::new(&cipher, &secret, &recovery_codes);
+	save_recovery_codes(memory_store, &user.email, previous.as_ref(), &record)
+		.await
+		.map_err(store_error)?;
+	save_two_factor(memory_store, &user.email, &record)
+		.await
+		.map_err(store_error)?;
+
+	debug!("Two factor enrollment started for {}", user.email);
+	Ok(Json(TwoFactorEnrollResponse {
+		secret: Secret::Raw(secret).to_encoded().to_string(),
+		otpauth_uri,
+		recovery_codes,
+	}))
+}
+
+// Finish an enrollment by proving the authenticator app generates valid codes
+async fn confirm(
+	State(app_state): State<AppState>,
+	user: User,
+	session_info: SessionInfo,
+	Json(request_body): Json<TwoFactorCodeRequest>,
+) -> Result<impl IntoResponse, AppError> {
+	session_info.ensure_not_impersonated()?;
+	let cipher = cipher(&app_state)?;
+	let memory_store = app_state.memory_store.as_ref();
+	let mut record = load_two_factor(memory_store, &user.email)
+		.await
+		.map_err(store_error)?
+		.ok_or(AppError::NotFound {
+			resource: "two factor enrollment".to_string(),
+		})?;
+	let issuer = app_state.config.two_factor.issuer.as_str();
+	let step = record
+		.verify_totp(&cipher, issuer, &user.email, &request_body.code)
+		.ok_or(AppError::Unauthorized)?;
+	if !use_totp_step(memory_store, &user.email, step)
+		.await
+		.map_err(store_error)?
+	{
+		return Err(AppError::Unauthorized);
+	}
+
+	record.confirmed = true;
+	save_two_factor(memory_store, &user.email, &record)
+		.await
+		.map_err(store_error)?;
+	info!("Two factor authentication enabled for {}", user.email);
+	Ok(Json(serde_json::json!({ "status": "OK" })))
+}
+
+// Second login step: exchange a pending session and a valid code for a full session
+async fn verify(
+	State(app_state): State<AppState>,
+	TypedHeader(cookies): TypedHeader<headers::Cookie>,
+	Json(request_body): Json<TwoFactorCodeRequest>,
+) -> Result<impl IntoResponse, AppError> {
+	let cipher = cipher(&app_state)?;
+	let memory_store = app_state.memory_store.as_ref();
+	let pending_session = load_session_from_cookies(memory_store, &cookies)
+		.await
+		.map_err(store_error)?
+		.filter(|session| session.get::<bool>(TWO_FACTOR_PENDING_KEY).unwrap_or(false))
+		.ok_or(AppError::Unauthorized)?;
+	let user = pending_session
+		.get::<User>("user")
+		.ok_or(AppError::Unauthorized)?;
+	let record = load_two_factor(memory_store, &user.email)
+		.await
+		.map_err(store_error)?
+		.filter(|record| record.confirmed)
+		.ok_or(AppError::Unauthorized)?;
+
+	let issuer = app_state.config.two_factor.issuer.as_str();
+	let accepted = match record.verify_totp(&cipher, issuer, &user.email, &request_body.code) {
+		Some(step) => use_totp_step(memory_store, &user.email, step)
+			.await
+			.map_err(store_error)?,
+		None => {
+			let used = use_recovery_code(memory_store, &user.email, &request_body.code)
+				.await
+				.map_err(store_error)?;
+			if used {
+				info!("Recovery code used by {}", user.email);
+			}
+			used
+		}
+	};
+	if !accepted {
+		debug!("Invalid or already used two factor code for {}", user.email);
+		return Err(AppError::Unauthorized);
+	}
+
+	debug!("Replace the pending session with a full session");
+	let provider_token = take_provider_token(memory_store, &pending_session)
+		.await
+		.map_err(store_error)?;
+	memory_store
+		.destroy_session(pending_session)
+		.await
+		.map_err(store_error)?;
+	let mut session = Session::new();
+	session.insert("user", &user).unwrap();
+	if let Some(provider_token) = provider_token {
+		save_provider_token(memory_store, &session, &provider_token)
+			.await
+			.map_err(store_error)?;
+	}
+	let headers = store_session_with_cookie(memory_store, &app_state.config.cookies, session)
+		.await
+		.map_err(store_error)?;
+	Ok((headers, Redirect::to("/")))
+}
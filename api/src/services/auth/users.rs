@@ -0,0 +1,22 @@
+use crate::memory_store::{user_hash_tag, MemoryStore, StoreResult};
+
+use super::User;
+
+fn user_key(email: &str) -> String {
+	format!("user:{}", user_hash_tag(email))
+}
+
+/// Keep the latest known profile of a user, so that it can be found by email
+pub async fn save_user(memory_store: &dyn MemoryStore, user: &User) -> StoreResult {
+	let value = serde_json::to_string(user)?;
+	memory_store
+		.set_value(&user_key(&user.email), &value, None)
+		.await
+}
+
+pub async fn load_user(memory_store: &dyn MemoryStore, email: &str) -> StoreResult<Option<User>> {
+	match memory_store.get_value(&user_key(email)).await? {
+		Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+		None => Ok(None),
+	}
+}
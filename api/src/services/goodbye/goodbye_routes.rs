@@ -1,6 +1,10 @@
 use axum::{http, routing::post, Json, Router};
 
-use crate::{errors::AppError, AppState};
+use crate::{
+	errors::AppError,
+	services::auth::{GrantedScopes, Scope},
+	AppState,
+};
 
 use super::goodbye_dto::{GoodbyeRequest, GoodbyeResponse};
 
@@ -18,7 +22,14 @@ async fn goodbye_world() -> Result<Json<GoodbyeResponse>, http::StatusCode> {
 	Ok(Json(response))
 }
 
-async fn goodbye_reason(body: Json<GoodbyeRequest>) -> Result<Json<GoodbyeResponse>, AppError> {
+async fn goodbye_reason(
+	scopes: Option<GrantedScopes>,
+	body: Json<GoodbyeRequest>,
+) -> Result<Json<GoodbyeResponse>, AppError> {
+	// Anonymous calls are allowed, but API tokens must carry the scope
+	if let Some(scopes) = scopes {
+		scopes.require(Scope::GoodbyeWrite)?;
+	}
 	let reason = &body.0.reason;
 	if reason.is_empty() {
 		return Err(AppError::ValidationError {
@@ -6,6 +6,7 @@ use axum::{
 };
 
 use crate::errors::AppError;
+use crate::services::auth::{GrantedScopes, Scope};
 use crate::AppState;
 
 use super::{HelloRequest, HelloResponse};
@@ -29,8 +30,13 @@ async fn hello_world(
 
 async fn hello_with_params(
 	State(app_state): State<AppState>,
+	scopes: Option<GrantedScopes>,
 	Json(request_body): Json<HelloRequest>,
 ) -> Result<Json<HelloResponse>, AppError> {
+	// Anonymous calls are allowed, but API tokens must carry the scope
+	if let Some(scopes) = scopes {
+		scopes.require(Scope::HelloWrite)?;
+	}
 	let name = &request_body.name;
 	if name.is_empty() {
 		return Err(AppError::ValidationError {
@@ -0,0 +1,311 @@
+use async_session::{Session, SessionStore};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::{debug, warn};
+
+use crate::{memory_store::StoreResult, services::auth::User};
+
+/// Session data key holding the version of the layout the session was stored with
+pub const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// Data of a session in the current layout, which the upgrades have to end with.
+///
+/// Its fields follow the session keys the handlers use. Keys it does not know are kept as they
+/// are
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SessionData {
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub user: Option<User>,
+	/// Admin impersonating the user, see `IMPERSONATOR_KEY`
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub impersonator: Option<User>,
+	/// See `TWO_FACTOR_PENDING_KEY`
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub two_factor_pending: Option<bool>,
+	/// See `SESSION_EPOCH_KEY`
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub session_epoch: Option<u64>,
+	#[serde(flatten)]
+	pub other: Map<String, Value>,
+}
+
+/// Rewrite the data of a session from one layout version to the next.
+///
+/// The data values are given decoded, i.e. `data["user"]["email"]`, and unknown keys must be left
+/// untouched. Older layouts have no types left to decode them with, the last upgrade has to
+/// produce data decoding to [SessionData]. Errors describe why the session cannot be upgraded,
+/// which then counts as logged out
+pub type Upgrade = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+/// Upgrades of the session layout, the one at index `n` going from version `n` to `n + 1`.
+///
+/// Changing how `User`, `DiscordUser` or `GoogleUser` deserialize, or what the session holds,
+/// requires appending an upgrade here and updating [SessionData], so that the sessions stored
+/// before the deploy keep working. Sessions stored before the layout was versioned are version 0
+pub const UPGRADES: &[Upgrade] = &[];
+
+/// A stored session, in the current layout
+pub struct DecodedSession {
+	pub session: Session,
+	/// Whether the session was stored with an older layout
+	pub upgraded: bool,
+}
+
+impl DecodedSession {
+	/// Get the session, storing it back to `store` when it was upgraded so that its upgrade
+	/// only runs once
+	pub async fn write_back(self, store: &impl SessionStore) -> async_session::Result<Session> {
+		if self.upgraded {
+			debug!(
+				"Storing session {} in the current layout",
+				self.session.id()
+			);
+			store.store_session(self.session.clone()).await?;
+		}
+		Ok(self.session)
+	}
+}
+
+/// Version stamped on the sessions being stored
+pub fn current_version() -> u64 {
+	UPGRADES.len() as u64
+}
+
+/// Serialize a session to be stored, stamped with the current layout version
+pub fn encode_session(session: &Session) -> StoreResult<String> {
+	let mut json = serde_json::to_value(session)?;
+	if let Some(data) = json.get_mut("data").and_then(Value::as_object_mut) {
+		data.insert(
+			SCHEMA_VERSION_KEY.to_string(),
+			Value::String(current_version().to_string()),
+		);
+	}
+	Ok(json.to_string())
+}
+
+/// Deserialize a stored session, upgrading it to the current layout on the way.
+/// Sessions that expired or cannot be upgraded are no session at all
+pub fn decode_session(json: &str) -> StoreResult<Option<DecodedSession>> {
+	Ok(decode_with(json, UPGRADES)?.filter(|decoded| !decoded.session.is_expired()))
+}
+
+fn decode_with(json: &str, upgrades: &[Upgrade]) -> StoreResult<Option<DecodedSession>> {
+	let mut json: Value = serde_json::from_str(json)?;
+	let mut upgraded = false;
+	if let Some(data) = json.get_mut("data").and_then(Value::as_object_mut) {
+		match upgrade(data, upgrades) {
+			Ok(applied) => upgraded = applied,
+			Err(e) => {
+				warn!("Ignoring session that cannot be upgraded: {}", e);
+				return Ok(None);
+			}
+		}
+	}
+	Ok(Some(DecodedSession {
+		session: serde_json::from_value(json)?,
+		upgraded,
+	}))
+}
+
+/// Apply the upgrades the session data is missing, the data values being JSON encoded strings.
+/// Returns whether there were any to apply
+fn upgrade(data: &mut Map<String, Value>, upgrades: &[Upgrade]) -> Result<bool, String> {
+	let version = data
+		.get(SCHEMA_VERSION_KEY)
+		.and_then(Value::as_str)
+		.and_then(|version| version.parse::<usize>().ok())
+		.unwrap_or(0);
+	// Sessions from a newer release, i.e. after a rollback, are read as they are
+	if version >= upgrades.len() {
+		return Ok(false);
+	}
+
+	let mut decoded: Map<String, Value> = data
+		.iter()
+		.map(|(key, value)| {
+			let value = value
+				.as_str()
+				.and_then(|json| serde_json::from_str(json).ok())
+				.unwrap_or_else(|| value.clone());
+			(key.clone(), value)
+		})
+		.collect();
+	for (from, upgrade) in upgrades.iter().enumerate().skip(version) {
+		upgrade(&mut decoded).map_err(|e| format!("from version {}: {}", from, e))?;
+	}
+	let current: SessionData = serde_json::from_value(Value::Object(decoded))
+		.map_err(|e| format!("upgraded data is not the current layout: {}", e))?;
+	let Ok(Value::Object(mut decoded)) = serde_json::to_value(current) else {
+		return Err("upgraded data is not an object".to_string());
+	};
+	decoded.insert(
+		SCHEMA_VERSION_KEY.to_string(),
+		Value::from(upgrades.len() as u64),
+	);
+	*data = decoded
+		.into_iter()
+		.map(|(key, value)| (key, Value::String(value.to_string())))
+		.collect();
+	Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		in_memory_store::InMemoryStore,
+		memory_store::{stored_session_key, MemoryStore},
+		services::{
+			admin::IMPERSONATOR_KEY,
+			auth::{SESSION_EPOCH_KEY, TWO_FACTOR_PENDING_KEY},
+		},
+	};
+	use serde_json::json;
+
+	/// Version 1 moved the `name` of users to their local account
+	fn move_name(data: &mut Map<String, Value>) -> Result<(), String> {
+		let user = data
+			.get_mut("user")
+			.and_then(Value::as_object_mut)
+			.ok_or("no user")?;
+		if let Some(name) = user.remove("name") {
+			user.insert("local".to_string(), json!({ "username": name }));
+		}
+		Ok(())
+	}
+
+	/// Version 2 lowercased the emails of users
+	fn lowercase_email(data: &mut Map<String, Value>) -> Result<(), String> {
+		let email = data
+			.get_mut("user")
+			.and_then(|user| user.get_mut("email"))
+			.ok_or("no email")?;
+		if let Some(lowercase) = email.as_str().map(str::to_lowercase) {
+			*email = Value::String(lowercase);
+		}
+		Ok(())
+	}
+
+	const TEST_UPGRADES: &[Upgrade] = &[move_name, lowercase_email];
+
+	fn stored(version: Option<&str>, user: Value) -> String {
+		let mut session = Session::new();
+		session.insert("user", user).unwrap();
+		session.insert("pending", true).unwrap();
+		if let Some(version) = version {
+			session.insert_raw(SCHEMA_VERSION_KEY, version.to_string());
+		}
+		serde_json::to_string(&session).unwrap()
+	}
+
+	#[test]
+	fn test_encode_stamps_the_current_version() {
+		let mut session = Session::new();
+		session
+			.insert("user", json!({ "email": "user@example.com" }))
+			.unwrap();
+		let json = encode_session(&session).unwrap();
+		let decoded = decode_session(&json).unwrap().unwrap();
+		assert!(!decoded.upgraded);
+		assert_eq!(decoded.session.id(), session.id());
+		assert_eq!(
+			decoded.session.get::<u64>(SCHEMA_VERSION_KEY),
+			Some(current_version())
+		);
+		// The session being stored is left as it is
+		assert_eq!(session.get_raw(SCHEMA_VERSION_KEY), None);
+	}
+
+	#[test]
+	fn test_session_data_follows_the_session_keys() {
+		let data = SessionData {
+			user: None,
+			impersonator: Some(User {
+				email: "admin@example.com".to_string(),
+				discord: None,
+				google: None,
+				local: None,
+			}),
+			two_factor_pending: Some(true),
+			session_epoch: Some(1),
+			other: Map::new(),
+		};
+		let value = serde_json::to_value(&data).unwrap();
+		for key in [IMPERSONATOR_KEY, TWO_FACTOR_PENDING_KEY, SESSION_EPOCH_KEY] {
+			assert!(value.get(key).is_some(), "{}", key);
+		}
+	}
+
+	#[test]
+	fn test_unversioned_sessions_get_every_upgrade() {
+		let json = stored(None, json!({ "email": "User@Example.com", "name": "user" }));
+		let decoded = decode_with(&json, TEST_UPGRADES).unwrap().unwrap();
+		assert!(decoded.upgraded);
+		let user = decoded.session.get::<User>("user").unwrap();
+		assert_eq!(user.email, "user@example.com");
+		assert_eq!(user.local.unwrap().username, "user");
+		assert_eq!(decoded.session.get::<bool>("pending"), Some(true));
+		assert_eq!(decoded.session.get::<u64>(SCHEMA_VERSION_KEY), Some(2));
+	}
+
+	#[test]
+	fn test_only_missing_upgrades_apply() {
+		// A version 1 session whose `name` must not be moved again
+		let json = stored(
+			Some("1"),
+			json!({ "email": "User@Example.com", "name": "kept" }),
+		);
+		let decoded = decode_with(&json, TEST_UPGRADES).unwrap().unwrap();
+		assert!(decoded.upgraded);
+		let user = decoded.session.get::<User>("user").unwrap();
+		assert_eq!(user.email, "user@example.com");
+		assert!(user.local.is_none());
+
+		// Sessions from newer releases are read untouched
+		let json = stored(Some("5"), json!({ "email": "User@Example.com" }));
+		let decoded = decode_with(&json, TEST_UPGRADES).unwrap().unwrap();
+		assert!(!decoded.upgraded);
+		assert_eq!(
+			decoded.session.get::<Value>("user"),
+			Some(json!({ "email": "User@Example.com" }))
+		);
+	}
+
+	#[test]
+	fn test_expired_sessions_mean_no_session() {
+		let mut json = serde_json::to_value(Session::new()).unwrap();
+		json["expiry"] = json!("2000-01-01T00:00:00Z");
+		assert!(decode_session(&json.to_string()).unwrap().is_none());
+	}
+
+	#[test]
+	fn test_failed_upgrades_mean_no_session() {
+		let mut session = Session::new();
+		session.insert("other", 1).unwrap();
+		let json = serde_json::to_string(&session).unwrap();
+		assert!(decode_with(&json, TEST_UPGRADES).unwrap().is_none());
+		assert!(decode_with("not json", TEST_UPGRADES).is_err());
+
+		// Upgrades must end with the current layout
+		let json = stored(None, json!({ "email": 1 }));
+		assert!(decode_with(&json, &[move_name]).unwrap().is_none());
+	}
+
+	#[tokio::test]
+	async fn test_upgraded_sessions_are_written_back() {
+		let store = InMemoryStore::new();
+		let json = stored(None, json!({ "email": "user@example.com", "name": "user" }));
+		let decoded = decode_with(&json, TEST_UPGRADES).unwrap().unwrap();
+		let session = decoded.write_back(&store).await.unwrap();
+
+		let key = stored_session_key(&session).unwrap();
+		let stored = store.get_value(&key).await.unwrap().unwrap();
+		let user = decode_session(&stored)
+			.unwrap()
+			.unwrap()
+			.session
+			.get::<User>("user")
+			.unwrap();
+		assert_eq!(user.local.unwrap().username, "user");
+	}
+}
@@ -0,0 +1,41 @@
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+/// Generate a random token with 256 bits of entropy, hex encoded
+pub fn generate_token() -> String {
+	let mut bytes = [0u8; 32];
+	OsRng.fill_bytes(&mut bytes);
+	to_hex(&bytes)
+}
+
+/// Hash a token before it is stored, so that the store never holds usable secrets.
+///
+/// Tokens are random and long enough that an unsalted, fast hash is sufficient
+pub fn hash_token(token: &str) -> String {
+	to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_generate_token() {
+		let token = generate_token();
+		assert_eq!(token.len(), 64);
+		assert!(token.chars().all(|c| c.is_ascii_hexdigit()));
+		assert_ne!(token, generate_token());
+	}
+
+	#[test]
+	fn test_hash_token() {
+		assert_eq!(
+			hash_token("test"),
+			"9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+		);
+	}
+}
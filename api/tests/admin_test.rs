@@ -0,0 +1,250 @@
+use std::sync::Arc;
+
+use axum::{middleware::from_fn, routing::get, Router};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::{
+	header::{CONTENT_TYPE, COOKIE, SET_COOKIE},
+	Body, Request, Response, StatusCode,
+};
+use sabi_api::{
+	audit::{self, AuditAction},
+	middleware::impersonation_banner,
+	services::{
+		admin::{routes, ImpersonationResponse, IMPERSONATION_HEADER},
+		auth::{self, DeviceAuthorizationResponse, User},
+	},
+	AppState,
+};
+use serde_json::json;
+use tower::ServiceExt;
+
+mod common;
+
+fn create_state() -> AppState {
+	let mut state = common::create_state();
+	let mut config = (*state.config).clone();
+	config.admin_emails = Arc::new(vec!["admin@example.com".to_string()]);
+	config.local_auth_enabled = true;
+	config.two_factor.encryption_key = Some(Arc::new(STANDARD.encode([3; 32])));
+	state.config = Arc::new(config);
+	state
+}
+
+fn create_router(state: AppState) -> Router {
+	Router::new()
+		.route("/whoami", get(|user: User| async move { user.email }))
+		.nest("/admin", routes())
+		.nest("/auth", auth::routes())
+		.layer(from_fn(impersonation_banner))
+		.with_state(state)
+}
+
+async fn send(app: &Router, request: Request<Body>) -> Response<axum::body::BoxBody> {
+	app.clone().oneshot(request).await.unwrap()
+}
+
+fn impersonate(cookie: &str, email: &str) -> Request<Body> {
+	Request::builder()
+		.method("POST")
+		.uri("/admin/impersonate")
+		.header(COOKIE, cookie)
+		.header(CONTENT_TYPE, "application/json")
+		.body(Body::from(json!({ "email": email }).to_string()))
+		.unwrap()
+}
+
+fn post(cookie: &str, uri: &str, body: serde_json::Value) -> Request<Body> {
+	Request::builder()
+		.method("POST")
+		.uri(uri)
+		.header(COOKIE, cookie)
+		.header(CONTENT_TYPE, "application/json")
+		.body(Body::from(body.to_string()))
+		.unwrap()
+}
+
+fn whoami(cookie: &str) -> Request<Body> {
+	Request::builder()
+		.uri("/whoami")
+		.header(COOKIE, cookie)
+		.body(Body::empty())
+		.unwrap()
+}
+
+fn session_cookie(response: &Response<axum::body::BoxBody>) -> String {
+	let set_cookie = response.headers()[SET_COOKIE].to_str().unwrap();
+	set_cookie.split(';').next().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn test_impersonation_requires_admin() {
+	let state = create_state();
+	let app = create_router(state.clone());
+	let cookie = common::login(&state, &common::create_user("user@example.com")).await;
+	common::login(&state, &common::create_user("other@example.com")).await;
+
+	let response = send(&app, impersonate(&cookie, "other@example.com")).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+	assert!(audit::events(state.memory_store.as_ref())
+		.await
+		.unwrap()
+		.is_empty());
+}
+
+#[tokio::test]
+async fn test_impersonation_unknown_user() {
+	let state = create_state();
+	let app = create_router(state.clone());
+	let cookie = common::login(&state, &common::create_user("admin@example.com")).await;
+
+	let response = send(&app, impersonate(&cookie, "nobody@example.com")).await;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_impersonation_start_and_stop() {
+	let state = create_state();
+	let app = create_router(state.clone());
+	let admin_cookie = common::login(&state, &common::create_user("admin@example.com")).await;
+	common::login(&state, &common::create_user("user@example.com")).await;
+
+	let response = send(&app, impersonate(&admin_cookie, "user@example.com")).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let cookie = session_cookie(&response);
+	let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+	assert_eq!(
+		body,
+		serde_json::to_string(&ImpersonationResponse {
+			user: "user@example.com".to_string(),
+			impersonator: Some("admin@example.com".to_string()),
+		})
+		.unwrap()
+	);
+
+	// The previous admin session is gone
+	let response = send(&app, whoami(&admin_cookie)).await;
+	assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+
+	// The session resolves to the target user and responses carry the banner
+	let response = send(&app, whoami(&cookie)).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(
+		response.headers()[IMPERSONATION_HEADER],
+		"admin@example.com"
+	);
+	let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+	assert_eq!(body, "user@example.com");
+
+	// Impersonations cannot be chained
+	let response = send(&app, impersonate(&cookie, "admin@example.com")).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+	let request = Request::builder()
+		.method("POST")
+		.uri("/admin/impersonate/stop")
+		.header(COOKIE, &cookie)
+		.body(Body::empty())
+		.unwrap();
+	let response = send(&app, request).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let admin_cookie = session_cookie(&response);
+
+	let response = send(&app, whoami(&admin_cookie)).await;
+	assert!(response.headers().get(IMPERSONATION_HEADER).is_none());
+	let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+	assert_eq!(body, "admin@example.com");
+
+	let events = audit::events(state.memory_store.as_ref()).await.unwrap();
+	assert_eq!(
+		events
+			.iter()
+			.map(|event| (&event.action, event.actor.as_str(), event.target.as_str()))
+			.collect::<Vec<_>>(),
+		vec![
+			(
+				&AuditAction::ImpersonationStarted,
+				"admin@example.com",
+				"user@example.com"
+			),
+			(
+				&AuditAction::ImpersonationStopped,
+				"admin@example.com",
+				"user@example.com"
+			),
+		]
+	);
+}
+
+#[tokio::test]
+async fn test_impersonation_cannot_create_credentials() {
+	let state = create_state();
+	let app = create_router(state.clone());
+	let admin_cookie = common::login(&state, &common::create_user("admin@example.com")).await;
+	common::login(&state, &common::create_user("user@example.com")).await;
+	let response = send(&app, impersonate(&admin_cookie, "user@example.com")).await;
+	let cookie = session_cookie(&response);
+
+	let response = send(&app, post("", "/auth/device", json!({}))).await;
+	let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+	let authorization: DeviceAuthorizationResponse = serde_json::from_slice(&body).unwrap();
+	let user_code = json!({ "user_code": authorization.user_code });
+	let response = send(&app, post(&cookie, "/auth/device/approve", user_code)).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+	let token = json!({ "name": "cli", "scopes": ["hello:write"] });
+	let response = send(&app, post(&cookie, "/auth/tokens", token)).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+	let change = json!({ "current_password": "correct horse", "new_password": "battery staple" });
+	let response = send(&app, post(&cookie, "/auth/local/password", change)).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+	let response = send(&app, post(&cookie, "/auth/2fa/enroll", json!({}))).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+	let code = json!({ "code": "000000" });
+	let response = send(&app, post(&cookie, "/auth/2fa/confirm", code)).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_stop_without_impersonation() {
+	let state = create_state();
+	let app = create_router(state.clone());
+	let cookie = common::login(&state, &common::create_user("admin@example.com")).await;
+
+	let request = Request::builder()
+		.method("POST")
+		.uri("/admin/impersonate/stop")
+		.header(COOKIE, &cookie)
+		.body(Body::empty())
+		.unwrap();
+	let response = send(&app, request).await;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_metrics_requires_admin() {
+	let state = create_state();
+	let app = create_router(state.clone());
+	let metrics = |cookie: &str| {
+		Request::builder()
+			.uri("/admin/metrics")
+			.header(COOKIE, cookie)
+			.body(Body::empty())
+			.unwrap()
+	};
+
+	let user_cookie = common::login(&state, &common::create_user("user@example.com")).await;
+	let response = send(&app, metrics(&user_cookie)).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+	let admin_cookie = common::login(&state, &common::create_user("admin@example.com")).await;
+	let response = send(&app, metrics(&admin_cookie)).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+	// The in-memory test store has no connection to report on
+	assert_eq!(
+		serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+		json!({ "memory_store": null })
+	);
+}
@@ -0,0 +1,203 @@
+use std::sync::Arc;
+
+use axum::Router;
+use hyper::{
+	header::{AUTHORIZATION, CONTENT_TYPE, COOKIE},
+	Body, Request, Response, StatusCode,
+};
+use sabi_api::{
+	services::{
+		admin,
+		auth::{self, issue_api_token, list_api_tokens, NewApiToken},
+		goodbye, hello,
+	},
+	AppState,
+};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+
+mod common;
+
+fn create_router(state: AppState) -> Router {
+	Router::new()
+		.nest("/admin", admin::routes())
+		.nest("/auth", auth::routes())
+		.nest("/goodbye", goodbye::routes())
+		.nest("/hello", hello::routes())
+		.with_state(state)
+}
+
+async fn send(
+	app: &Router,
+	method: &str,
+	uri: &str,
+	credentials: (hyper::header::HeaderName, &str),
+	body: Value,
+) -> Response<axum::body::BoxBody> {
+	let request = Request::builder()
+		.method(method)
+		.uri(uri)
+		.header(credentials.0, credentials.1)
+		.header(CONTENT_TYPE, "application/json")
+		.body(Body::from(body.to_string()))
+		.unwrap();
+	app.clone().oneshot(request).await.unwrap()
+}
+
+async fn json_body(response: Response<axum::body::BoxBody>) -> Value {
+	let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+	serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn test_api_token_lifecycle() {
+	let state = common::create_state();
+	let app = create_router(state.clone());
+	let cookie = common::login(&state, &common::create_user("user@example.com")).await;
+	let session = (COOKIE, cookie.as_str());
+
+	let request_body = json!({ "name": "ci", "scopes": ["hello:write"], "expires_in": 3600 });
+	let response = send(&app, "POST", "/auth/tokens", session.clone(), request_body).await;
+	assert_eq!(response.status(), StatusCode::CREATED);
+	let created = json_body(response).await;
+	assert_eq!(created["name"], "ci");
+	assert_eq!(created["scopes"], json!(["hello:write"]));
+	assert!(created["expires_at"].is_u64());
+	let id = created["id"].as_str().unwrap().to_string();
+	let bearer = format!("Bearer {}", created["token"].as_str().unwrap());
+	let token = (AUTHORIZATION, bearer.as_str());
+
+	// Listing never shows the secret again
+	let response = send(&app, "GET", "/auth/tokens", session.clone(), json!({})).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let listed = json_body(response).await;
+	assert_eq!(listed.as_array().unwrap().len(), 1);
+	assert_eq!(listed[0]["id"], json!(id));
+	assert!(listed[0].get("token").is_none());
+
+	// The token is limited to its scopes
+	let hello = json!({ "name": "ci" });
+	let response = send(&app, "POST", "/hello", token.clone(), hello.clone()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let reason = json!({ "reason": "ci" });
+	let response = send(&app, "POST", "/goodbye/reason", token.clone(), reason).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+	// Scoped tokens cannot manage tokens
+	let response = send(&app, "GET", "/auth/tokens", token.clone(), json!({})).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+	let uri = format!("/auth/tokens/{}", id);
+	let response = send(&app, "DELETE", &uri, session.clone(), json!({})).await;
+	assert_eq!(response.status(), StatusCode::NO_CONTENT);
+	let response = send(&app, "DELETE", &uri, session.clone(), json!({})).await;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+	// Revoked tokens are rejected, and do not fall back to anonymous access
+	let response = send(&app, "GET", "/auth/tokens", token.clone(), json!({})).await;
+	assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+	let response = send(&app, "GET", "/auth/tokens", session, json!({})).await;
+	assert_eq!(json_body(response).await, json!([]));
+}
+
+#[tokio::test]
+async fn test_api_token_validation() {
+	let state = common::create_state();
+	let app = create_router(state.clone());
+	let cookie = common::login(&state, &common::create_user("user@example.com")).await;
+	let session = (COOKIE, cookie.as_str());
+
+	for request_body in [
+		json!({ "name": " ", "scopes": ["hello:write"] }),
+		json!({ "name": "ci", "scopes": [] }),
+		json!({ "name": "ci", "scopes": ["admin"] }),
+		json!({ "name": "ci", "scopes": ["hello:write"], "expires_in": 0 }),
+	] {
+		let response = send(&app, "POST", "/auth/tokens", session.clone(), request_body).await;
+		assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+	}
+
+	// Tokens without expiry are valid until revoked
+	let request_body = json!({ "name": "ci", "scopes": ["goodbye:write", "hello:write"] });
+	let response = send(&app, "POST", "/auth/tokens", session, request_body).await;
+	assert_eq!(response.status(), StatusCode::CREATED);
+	assert!(json_body(response).await["expires_at"].is_null());
+
+	// Anonymous calls are still allowed
+	let response = send(
+		&app,
+		"POST",
+		"/goodbye/reason",
+		(COOKIE, ""),
+		json!({ "reason": "ci" }),
+	)
+	.await;
+	assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_api_tokens_are_denied_by_default() {
+	let mut state = common::create_state();
+	let mut config = (*state.config).clone();
+	config.admin_emails = Arc::new(vec!["admin@example.com".to_string()]);
+	config.local_auth_enabled = true;
+	state.config = Arc::new(config);
+	let app = create_router(state.clone());
+	let user = common::create_user("admin@example.com");
+	common::login(&state, &user).await;
+	let new_token = NewApiToken {
+		email: &user.email,
+		name: "device",
+		scopes: None,
+		ttl: None,
+	};
+	let (_, token) = issue_api_token(state.memory_store.as_ref(), new_token)
+		.await
+		.unwrap();
+	let bearer = format!("Bearer {}", token);
+	let token = (AUTHORIZATION, bearer.as_str());
+
+	let response = send(&app, "GET", "/auth/tokens", token.clone(), json!({})).await;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	// Even unscoped tokens only reach the routes accepting them
+	for (method, uri) in [
+		("GET", "/admin/metrics"),
+		("POST", "/admin/impersonate"),
+		("POST", "/auth/2fa/enroll"),
+		("POST", "/auth/local/password"),
+		("POST", "/auth/device/approve"),
+		("POST", "/auth/device/deny"),
+	] {
+		let response = send(&app, method, uri, token.clone(), json!({})).await;
+		assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT, "{}", uri);
+	}
+}
+
+#[tokio::test]
+async fn test_api_tokens_issued_concurrently() {
+	let state = common::create_state();
+	let tasks: Vec<_> = (0..16)
+		.map(|_| {
+			let state = state.clone();
+			tokio::spawn(async move {
+				let new_token = NewApiToken {
+					email: "user@example.com",
+					name: "ci",
+					scopes: None,
+					ttl: None,
+				};
+				issue_api_token(state.memory_store.as_ref(), new_token)
+					.await
+					.unwrap()
+			})
+		})
+		.collect();
+	for task in tasks {
+		task.await.unwrap();
+	}
+
+	let api_tokens = list_api_tokens(state.memory_store.as_ref(), "user@example.com")
+		.await
+		.unwrap();
+	assert_eq!(api_tokens.len(), 16);
+}
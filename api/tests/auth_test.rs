@@ -0,0 +1,158 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{routing::get, Router};
+use hyper::{header::COOKIE, Body, Request, StatusCode};
+use oauth2::RevocationUrl;
+use sabi_api::{
+	services::auth::{routes, save_provider_token, ProviderToken, ProviderType, User},
+	AppState,
+};
+use tokio::{io::AsyncReadExt, net::TcpListener, sync::mpsc};
+use tower::ServiceExt;
+
+mod common;
+
+fn create_router(state: AppState) -> Router {
+	Router::new()
+		.route("/whoami", get(|user: User| async move { user.email }))
+		.nest("/auth", routes())
+		.with_state(state)
+}
+
+/// Listen for the revocations on a local port, sending every connection to the returned channel.
+/// The connections are closed right away, which fails the revocation, or kept open without an
+/// answer when the provider should `hang`
+async fn revocation_stub(hang: bool) -> (RevocationUrl, mpsc::UnboundedReceiver<()>) {
+	let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+	// Revocation URLs must use https, so the TLS handshake is what the stub receives
+	let url = format!("https://{}/revoke", listener.local_addr().unwrap());
+	let (sender, receiver) = mpsc::unbounded_channel();
+	tokio::spawn(async move {
+		let mut connections = vec![];
+		while let Ok((mut stream, _)) = listener.accept().await {
+			let _ = stream.read(&mut [0; 1024]).await;
+			let _ = sender.send(());
+			if hang {
+				connections.push(stream);
+			}
+		}
+	});
+	(RevocationUrl::new(url).unwrap(), receiver)
+}
+
+/// Log a Discord user in with provider tokens revoked at `url`, returning the state, the
+/// `Cookie` header value and the key of the tokens
+async fn login_with_provider_token(url: RevocationUrl) -> (AppState, String, String) {
+	let mut state = common::create_state();
+	state.oauth_providers = Arc::new(
+		(*state.oauth_providers)
+			.clone()
+			.with_revocation_url(ProviderType::Discord, url),
+	);
+	let cookie = common::login(&state, &common::create_user("user@example.com")).await;
+	let session = state
+		.memory_store
+		.load_session(cookie.trim_start_matches("SESSION=").to_string())
+		.await
+		.unwrap()
+		.unwrap();
+	let token = ProviderToken {
+		provider: ProviderType::Discord,
+		access_token: "access".to_string(),
+		refresh_token: Some("refresh".to_string()),
+	};
+	save_provider_token(state.memory_store.as_ref(), &session, &token)
+		.await
+		.unwrap();
+	let key = format!("oauth_token:{}", session.id());
+	(state, cookie, key)
+}
+
+async fn logout(state: &AppState, cookie: &str) -> StatusCode {
+	let request = Request::builder()
+		.uri("/auth/logout")
+		.header(COOKIE, cookie)
+		.body(Body::empty())
+		.unwrap();
+	create_router(state.clone())
+		.oneshot(request)
+		.await
+		.unwrap()
+		.status()
+}
+
+#[tokio::test]
+async fn test_logout() {
+	let state = common::create_state();
+	let app = create_router(state.clone());
+	let cookie = common::login(&state, &common::create_user("user@example.com")).await;
+
+	let request = Request::builder()
+		.uri("/auth/logout")
+		.header(COOKIE, &cookie)
+		.body(Body::empty())
+		.unwrap();
+	let response = app.clone().oneshot(request).await.unwrap();
+	assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+	let request = Request::builder()
+		.uri("/whoami")
+		.header(COOKIE, &cookie)
+		.body(Body::empty())
+		.unwrap();
+	let response = app.oneshot(request).await.unwrap();
+	assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+}
+
+#[tokio::test]
+async fn test_logout_without_session() {
+	let app = create_router(common::create_state());
+
+	let request = Request::builder()
+		.uri("/auth/logout")
+		.header(COOKIE, "SESSION=unknown")
+		.body(Body::empty())
+		.unwrap();
+	let response = app.oneshot(request).await.unwrap();
+	assert_eq!(response.status(), StatusCode::SEE_OTHER);
+}
+
+#[tokio::test]
+async fn test_logout_removes_provider_tokens() {
+	let (url, mut revocations) = revocation_stub(false).await;
+	let (state, cookie, key) = login_with_provider_token(url).await;
+	assert!(state.memory_store.get_value(&key).await.unwrap().is_some());
+
+	assert_eq!(logout(&state, &cookie).await, StatusCode::SEE_OTHER);
+	assert_eq!(state.memory_store.get_value(&key).await.unwrap(), None);
+	assert!(revocations.recv().await.is_some());
+}
+
+#[tokio::test]
+async fn test_logout_when_revocation_fails() {
+	let (url, mut revocations) = revocation_stub(false).await;
+	let (state, cookie, _) = login_with_provider_token(url).await;
+
+	assert_eq!(logout(&state, &cookie).await, StatusCode::SEE_OTHER);
+	assert!(revocations.recv().await.is_some());
+	let request = Request::builder()
+		.uri("/whoami")
+		.header(COOKIE, &cookie)
+		.body(Body::empty())
+		.unwrap();
+	let response = create_router(state).oneshot(request).await.unwrap();
+	assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+}
+
+#[tokio::test]
+async fn test_logout_does_not_wait_for_the_provider() {
+	let (url, mut revocations) = revocation_stub(true).await;
+	let (state, cookie, key) = login_with_provider_token(url).await;
+
+	let status = tokio::time::timeout(Duration::from_secs(1), logout(&state, &cookie))
+		.await
+		.expect("logging out does not wait for the revocation");
+	assert_eq!(status, StatusCode::SEE_OTHER);
+	assert_eq!(state.memory_store.get_value(&key).await.unwrap(), None);
+	assert!(revocations.recv().await.is_some());
+}
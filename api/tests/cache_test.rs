@@ -0,0 +1,176 @@
+use std::{
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc,
+	},
+	time::Duration,
+};
+
+use sabi_api::{
+	cache::Cache,
+	in_memory_store::InMemoryStore,
+	memory_store::{SharedMemoryStore, StoreError},
+};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+const TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct Greeting {
+	name: String,
+	count: u32,
+}
+
+fn create_cache() -> (SharedMemoryStore, Cache) {
+	let store = SharedMemoryStore::new(InMemoryStore::new());
+	(store.clone(), Cache::new(store))
+}
+
+#[tokio::test]
+async fn test_typed_values() {
+	let (store, cache) = create_cache();
+	let greeting = Greeting {
+		name: "world".to_string(),
+		count: 2,
+	};
+	assert_eq!(cache.get::<Greeting>("greeting").await.unwrap(), None);
+	cache.set("greeting", &greeting, TTL).await.unwrap();
+	assert_eq!(
+		cache.get::<Greeting>("greeting").await.unwrap(),
+		Some(greeting)
+	);
+	assert!(store.get_value("cache:greeting").await.unwrap().is_some());
+	assert!(matches!(
+		cache.get::<u32>("greeting").await,
+		Err(StoreError::Serialization(_))
+	));
+
+	cache.delete("greeting").await.unwrap();
+	assert_eq!(cache.get::<Greeting>("greeting").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_values_expire() {
+	let (_, cache) = create_cache();
+	cache
+		.set("short", &"value", Duration::from_millis(20))
+		.await
+		.unwrap();
+	assert!(cache.get::<String>("short").await.unwrap().is_some());
+	tokio::time::sleep(Duration::from_millis(30)).await;
+	assert!(cache.get::<String>("short").await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_namespaces_are_isolated() {
+	let (store, cache) = create_cache();
+	let hello = cache.namespace("hello");
+	let goodbye = cache.namespace("goodbye");
+	hello.set("name", &"hello", TTL).await.unwrap();
+	goodbye.set("name", &"goodbye", TTL).await.unwrap();
+	assert_eq!(
+		hello.get::<String>("name").await.unwrap().as_deref(),
+		Some("hello")
+	);
+	assert_eq!(
+		goodbye.get::<String>("name").await.unwrap().as_deref(),
+		Some("goodbye")
+	);
+	assert!(cache.get::<String>("name").await.unwrap().is_none());
+	assert!(store.get_value("cache:hello:name").await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn test_increment() {
+	let (_, cache) = create_cache();
+	assert_eq!(cache.increment("visits", 1, TTL).await.unwrap(), 1);
+	assert_eq!(cache.increment("visits", 4, TTL).await.unwrap(), 5);
+	assert_eq!(cache.get::<i64>("visits").await.unwrap(), Some(5));
+
+	cache.set("name", &"text", TTL).await.unwrap();
+	assert!(cache.increment("name", 1, TTL).await.is_err());
+}
+
+#[tokio::test]
+async fn test_get_or_compute_single_flight() {
+	let (_, cache) = create_cache();
+	let computations = Arc::new(AtomicUsize::new(0));
+	let calls = (0..10).map(|_| {
+		let cache = cache.clone();
+		let computations = computations.clone();
+		tokio::spawn(async move {
+			cache
+				.get_or_compute("slow", TTL, || async move {
+					computations.fetch_add(1, Ordering::SeqCst);
+					tokio::time::sleep(Duration::from_millis(20)).await;
+					Ok::<_, StoreError>(42)
+				})
+				.await
+				.unwrap()
+		})
+	});
+	for call in calls {
+		assert_eq!(call.await.unwrap(), 42);
+	}
+	assert_eq!(computations.load(Ordering::SeqCst), 1);
+	assert_eq!(cache.get::<i32>("slow").await.unwrap(), Some(42));
+}
+
+#[tokio::test]
+async fn test_get_or_compute_cancelled() {
+	let (_, cache) = create_cache();
+	let cancelled = tokio::time::timeout(
+		Duration::from_millis(10),
+		cache.get_or_compute("cancelled", TTL, || async {
+			std::future::pending::<Result<u32, StoreError>>().await
+		}),
+	)
+	.await;
+	assert!(cancelled.is_err());
+
+	// The dropped caller does not hold the key anymore
+	let value = cache
+		.get_or_compute("cancelled", TTL, || async { Ok::<_, StoreError>(3u32) })
+		.await
+		.unwrap();
+	assert_eq!(value, 3);
+}
+
+#[tokio::test]
+async fn test_get_or_compute_errors_are_not_cached() {
+	let (_, cache) = create_cache();
+	let result = cache
+		.get_or_compute("failing", TTL, || async {
+			Err::<u32, _>(StoreError::Command("boom".to_string()))
+		})
+		.await;
+	assert!(result.is_err());
+	assert!(cache.get::<u32>("failing").await.unwrap().is_none());
+
+	// Values of another type are computed again
+	cache.set("typed", &"text", TTL).await.unwrap();
+	let value = cache
+		.get_or_compute("typed", TTL, || async { Ok::<_, StoreError>(7u32) })
+		.await
+		.unwrap();
+	assert_eq!(value, 7);
+}
+
+#[tokio::test]
+async fn test_cache_shares_the_session_store() {
+	let state = common::create_state();
+	state
+		.cache
+		.namespace("hello")
+		.set("name", &"world", TTL)
+		.await
+		.unwrap();
+	assert!(state
+		.memory_store
+		.get_value("cache:hello:name")
+		.await
+		.unwrap()
+		.is_some());
+}
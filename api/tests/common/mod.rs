@@ -2,46 +2,48 @@ use std::sync::Arc;
 
 use async_session::Session;
 use sabi_api::{
+	cache::Cache,
 	config::Config,
-	memory_store::MemoryStore,
-	services::auth::{MultiOAuthConfig, MultiOAuthProvider, OAuthConfig},
+	in_memory_store::InMemoryStore,
+	mailer::{Mailer, StdoutMailer},
+	memory_store::SharedMemoryStore,
+	services::auth::{save_user, MultiOAuthConfig, MultiOAuthProvider, OAuthConfig, User},
 	AppState,
 };
 
-#[derive(Clone)]
-pub struct MockRedisStore {}
+pub mod store_conformance;
 
-impl MockRedisStore {
-	pub fn new() -> Self {
-		Self {}
+/// Build a user only known by their email
+#[allow(dead_code)]
+pub fn create_user(email: &str) -> User {
+	User {
+		email: email.to_string(),
+		discord: None,
+		google: None,
+		local: None,
 	}
 }
 
-#[async_trait::async_trait]
-impl MemoryStore for MockRedisStore {
-	async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
-		let mut session = Session::new();
-		session.insert("user", cookie_value).unwrap();
-		return Ok(Some(session));
-	}
-
-	async fn store_session(&self, _: Session) -> async_session::Result<Option<String>> {
-		let value = "test".to_string();
-		Ok(Some(value))
-	}
-
-	async fn destroy_session(&self, _: Session) -> async_session::Result {
-		Ok(())
-	}
-
-	async fn clear_store(&self) -> async_session::Result {
-		Ok(())
-	}
+/// Save the user in the user store and log them in, returning the `Cookie` header value
+#[allow(dead_code)]
+pub async fn login(state: &AppState, user: &User) -> String {
+	save_user(state.memory_store.as_ref(), user).await.unwrap();
+	let mut session = Session::new();
+	session.insert("user", user).unwrap();
+	let cookie = state
+		.memory_store
+		.store_session(session)
+		.await
+		.unwrap()
+		.unwrap();
+	format!("SESSION={}", cookie)
 }
 
+#[allow(dead_code)]
 pub fn create_state() -> AppState {
 	let config = Arc::new(Config::from_params("test".to_string()));
-	let memory_store: Arc<dyn MemoryStore> = Arc::new(MockRedisStore::new());
+	let mailer: Arc<dyn Mailer> = Arc::new(StdoutMailer);
+	let memory_store = SharedMemoryStore::new(InMemoryStore::new());
 	let oauth_providers = Arc::new(MultiOAuthProvider::new(MultiOAuthConfig {
 		discord: OAuthConfig {
 			client_id: "secret".to_string(),
@@ -55,7 +57,9 @@ pub fn create_state() -> AppState {
 		},
 	}));
 	return AppState {
+		cache: Cache::new(memory_store.clone()),
 		config,
+		mailer,
 		memory_store,
 		oauth_providers,
 	};
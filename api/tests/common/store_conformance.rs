@@ -0,0 +1,428 @@
+//! Behaviour every [MemoryStore] implementation must have, whatever its backend.
+//!
+//! A backend passes the suite when `run` succeeds on a store of its own. The checks only touch
+//! sessions and keys they create, but `clear_store` removes every session of the store
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+use async_session::Session;
+use sabi_api::{
+	config::RateLimit,
+	memory_store::{user_hash_tag, MemoryStore, SharedMemoryStore, StoreError, StoreResult},
+};
+
+use super::create_user;
+
+/// Tasks started at once by the concurrency checks
+const CONCURRENCY: usize = 20;
+
+/// Run every check against the store
+pub async fn run(store: SharedMemoryStore) {
+	session_round_trip(store.as_ref()).await;
+	destroy_session(store.as_ref()).await;
+	clear_store(store.as_ref()).await;
+	expiry(store.as_ref()).await;
+	session_expiry(store.as_ref()).await;
+	values(store.as_ref()).await;
+	wrong_type(store.as_ref()).await;
+	per_user_listing(store.as_ref()).await;
+	set_if_absent(store.as_ref()).await;
+	rate_limits(store.as_ref()).await;
+	concurrent_access(&store).await;
+	concurrent_rate_limits(&store).await;
+}
+
+fn user_session(email: &str) -> Session {
+	let mut session = Session::new();
+	session.insert("user", create_user(email)).unwrap();
+	session
+}
+
+async fn store(store: &dyn MemoryStore, session: &Session) -> String {
+	store
+		.store_session(session.clone())
+		.await
+		.unwrap()
+		.expect("stores return the cookie value of the session")
+}
+
+async fn load(store: &dyn MemoryStore, cookie_value: &str) -> Option<Session> {
+	store.load_session(cookie_value.to_string()).await.unwrap()
+}
+
+/// Sessions load back as they were stored, and storing them again replaces them
+pub async fn session_round_trip(store: &dyn MemoryStore) {
+	let mut session = user_session("Round.Trip@Example.com");
+	session.insert("visits", 1).unwrap();
+	let cookie_value = self::store(store, &session).await;
+
+	let loaded = load(store, &cookie_value).await.expect("stored session");
+	assert_eq!(loaded.id(), session.id());
+	assert_eq!(loaded.get::<u32>("visits"), Some(1));
+	assert_eq!(
+		loaded.get::<serde_json::Value>("user"),
+		session.get::<serde_json::Value>("user")
+	);
+
+	session.insert("visits", 2).unwrap();
+	self::store(store, &session).await;
+	let loaded = load(store, &cookie_value).await.expect("stored session");
+	assert_eq!(loaded.get::<u32>("visits"), Some(2));
+
+	assert!(matches!(
+		store.store_session(Session::new()).await,
+		Err(StoreError::MissingUser)
+	));
+	assert!(load(store, "not a session").await.is_none());
+	// A well formed cookie of a session that was never stored
+	let unknown = serde_json::to_string(&user_session("round.trip@example.com")).unwrap();
+	assert!(load(store, &unknown).await.is_none());
+}
+
+/// Destroying a session leaves the other sessions of the user alone
+pub async fn destroy_session(store: &dyn MemoryStore) {
+	let first = user_session("destroy@example.com");
+	let second = user_session("destroy@example.com");
+	let first_cookie = self::store(store, &first).await;
+	let second_cookie = self::store(store, &second).await;
+
+	store.destroy_session(first.clone()).await.unwrap();
+	assert!(load(store, &first_cookie).await.is_none());
+	assert!(load(store, &second_cookie).await.is_some());
+
+	// Destroying sessions that are not stored, or cannot be, is not an error
+	store.destroy_session(first).await.unwrap();
+	store.destroy_session(Session::new()).await.unwrap();
+}
+
+/// Clearing the store removes the sessions of every user, and only them
+pub async fn clear_store(store: &dyn MemoryStore) {
+	let cookies = [
+		self::store(store, &user_session("clear-1@example.com")).await,
+		self::store(store, &user_session("clear-2@example.com")).await,
+	];
+	store
+		.set_value("conformance:kept", "value", None)
+		.await
+		.unwrap();
+
+	store.clear_store().await.unwrap();
+	for cookie_value in &cookies {
+		assert!(load(store, cookie_value).await.is_none());
+	}
+	assert_eq!(
+		store.get_value("conformance:kept").await.unwrap(),
+		Some("value".to_string())
+	);
+	store.delete_value("conformance:kept").await.unwrap();
+}
+
+/// Values are gone once their expiry passed
+pub async fn expiry(store: &dyn MemoryStore) {
+	let ttl = Duration::from_millis(100);
+	store
+		.set_value("conformance:expiring", "value", Some(ttl))
+		.await
+		.unwrap();
+	store
+		.set_value("conformance:taken", "value", Some(ttl))
+		.await
+		.unwrap();
+	store
+		.increment_value("conformance:counter", 1, Some(ttl))
+		.await
+		.unwrap();
+	assert_eq!(
+		store.get_value("conformance:expiring").await.unwrap(),
+		Some("value".to_string())
+	);
+
+	tokio::time::sleep(ttl * 2).await;
+	assert_eq!(store.get_value("conformance:expiring").await.unwrap(), None);
+	assert_eq!(store.take_value("conformance:taken").await.unwrap(), None);
+	// Expired counters start over
+	assert_eq!(
+		store
+			.increment_value("conformance:counter", 1, None)
+			.await
+			.unwrap(),
+		1
+	);
+	store.delete_value("conformance:counter").await.unwrap();
+}
+
+/// Sessions are gone from the backend once their expiry passed
+pub async fn session_expiry(store: &dyn MemoryStore) {
+	let ttl = Duration::from_millis(100);
+	let email = "expiry@example.com";
+	let mut session = user_session(email);
+	session.expire_in(ttl);
+	let cookie_value = self::store(store, &session).await;
+	let never_expiring = self::store(store, &user_session(email)).await;
+	assert!(load(store, &cookie_value).await.is_some());
+
+	tokio::time::sleep(ttl * 2).await;
+	assert!(load(store, &cookie_value).await.is_none());
+	let key = format!("session:{}:{}", user_hash_tag(email), session.id());
+	assert_eq!(store.get_value(&key).await.unwrap(), None);
+	assert!(load(store, &never_expiring).await.is_some());
+}
+
+/// Values can be replaced, taken once and counted with
+pub async fn values(store: &dyn MemoryStore) {
+	let ttl = Duration::from_millis(100);
+	let (value, counter) = ("conformance:value", "conformance:increment");
+	store.set_value(value, "first", Some(ttl)).await.unwrap();
+	store.set_value(value, "second", None).await.unwrap();
+	assert!(store
+		.set_value(value, "third", Some(Duration::ZERO))
+		.await
+		.is_err());
+	assert_eq!(
+		store.increment_value(counter, 2, Some(ttl)).await.unwrap(),
+		2
+	);
+	assert_eq!(store.increment_value(counter, -5, None).await.unwrap(), -3);
+	assert_eq!(
+		store.get_value(counter).await.unwrap(),
+		Some("-3".to_string())
+	);
+
+	tokio::time::sleep(ttl * 2).await;
+	// Setting a value replaced its expiry, while increments kept the first one
+	assert_eq!(
+		store.get_value(value).await.unwrap(),
+		Some("second".to_string())
+	);
+	assert_eq!(store.increment_value(counter, 1, None).await.unwrap(), 1);
+
+	assert_eq!(
+		store.take_value(value).await.unwrap(),
+		Some("second".to_string())
+	);
+	assert_eq!(store.take_value(value).await.unwrap(), None);
+	assert_eq!(store.get_value(value).await.unwrap(), None);
+
+	// Only integers are incremented, as long as the result fits
+	for (text, delta) in [
+		("abc", 1),
+		("12abc", 1),
+		("1.5", 1),
+		("", 1),
+		(&*i64::MAX.to_string(), 1),
+		(&*i64::MIN.to_string(), -1),
+	] {
+		store.set_value(value, text, None).await.unwrap();
+		assert!(
+			failed(store.increment_value(value, delta, None).await),
+			"{}",
+			text
+		);
+		assert_eq!(
+			store.get_value(value).await.unwrap(),
+			Some(text.to_string())
+		);
+	}
+
+	for key in [value, counter] {
+		store.delete_value(key).await.unwrap();
+	}
+}
+
+fn failed<T>(result: StoreResult<T>) -> bool {
+	matches!(result, Err(StoreError::Command(_)))
+}
+
+/// Commands fail on keys holding another kind of value, like with Redis
+pub async fn wrong_type(store: &dyn MemoryStore) {
+	let (value, list) = (
+		"conformance:wrong-type:value",
+		"conformance:wrong-type:list",
+	);
+	store.set_value(value, "1", None).await.unwrap();
+	store.push_value(list, "value").await.unwrap();
+
+	assert!(failed(store.push_value(value, "value").await));
+	assert!(failed(store.list_values(value).await));
+	assert!(failed(store.get_value(list).await));
+	assert!(failed(store.take_value(list).await));
+	assert!(failed(store.increment_value(list, 1, None).await));
+	assert!(!store
+		.set_value_if_absent(list, "value", None)
+		.await
+		.unwrap());
+	// Failed commands leave the keys untouched
+	assert_eq!(store.get_value(value).await.unwrap(), Some("1".to_string()));
+	assert_eq!(store.list_values(list).await.unwrap(), vec!["value"]);
+
+	// Setting a value replaces the list
+	store.set_value(list, "replaced", None).await.unwrap();
+	assert_eq!(
+		store.get_value(list).await.unwrap(),
+		Some("replaced".to_string())
+	);
+	assert!(failed(store.push_value(list, "value").await));
+
+	for key in [value, list] {
+		store.delete_value(key).await.unwrap();
+	}
+}
+
+/// Lists keyed by user keep each user's values apart, in insertion order
+pub async fn per_user_listing(store: &dyn MemoryStore) {
+	let key = |email: &str| format!("conformance:list:{}", user_hash_tag(email));
+	let (alice, bob) = (key("alice@example.com"), key("bob@example.com"));
+	assert!(store.list_values(&alice).await.unwrap().is_empty());
+
+	for value in ["first", "second", "third"] {
+		store.push_value(&alice, value).await.unwrap();
+	}
+	store.push_value(&bob, "other").await.unwrap();
+	assert_eq!(
+		store.list_values(&alice).await.unwrap(),
+		vec!["first", "second", "third"]
+	);
+	assert_eq!(store.list_values(&bob).await.unwrap(), vec!["other"]);
+	// Hash tags are case insensitive, like emails
+	assert_eq!(
+		store.list_values(&key("Alice@Example.com")).await.unwrap(),
+		vec!["first", "second", "third"]
+	);
+
+	store.delete_value(&alice).await.unwrap();
+	assert!(store.list_values(&alice).await.unwrap().is_empty());
+	assert_eq!(store.list_values(&bob).await.unwrap(), vec!["other"]);
+	store.delete_value(&bob).await.unwrap();
+}
+
+/// Values are only set if absent when the key holds no live value
+pub async fn set_if_absent(store: &dyn MemoryStore) {
+	let key = "conformance:absent";
+	let ttl = Duration::from_millis(100);
+	assert!(store
+		.set_value_if_absent(key, "first", Some(ttl))
+		.await
+		.unwrap());
+	assert!(!store
+		.set_value_if_absent(key, "second", None)
+		.await
+		.unwrap());
+	assert_eq!(
+		store.get_value(key).await.unwrap(),
+		Some("first".to_string())
+	);
+
+	tokio::time::sleep(ttl * 2).await;
+	assert!(store.set_value_if_absent(key, "third", None).await.unwrap());
+	assert_eq!(
+		store.get_value(key).await.unwrap(),
+		Some("third".to_string())
+	);
+	store.delete_value(key).await.unwrap();
+}
+
+/// Rate limit buckets allow their burst, then refuse until refilled, each key on its own
+pub async fn rate_limits(store: &dyn MemoryStore) {
+	let limit = RateLimit {
+		burst: 2,
+		per_minute: 1,
+	};
+	let key = "conformance:rate-limit";
+	for _ in 0..limit.burst {
+		let decision = store.take_rate_limit_token(key, limit).await.unwrap();
+		assert!(decision.allowed);
+	}
+	let decision = store.take_rate_limit_token(key, limit).await.unwrap();
+	assert!(!decision.allowed);
+	assert!(decision.retry_after > Duration::ZERO);
+
+	let decision = store
+		.take_rate_limit_token("conformance:rate-limit:other", limit)
+		.await
+		.unwrap();
+	assert!(decision.allowed);
+}
+
+/// Concurrent writers neither lose updates nor take a value twice
+pub async fn concurrent_access(store: &SharedMemoryStore) {
+	let counter = "conformance:concurrent:counter";
+	let list = "conformance:concurrent:list";
+	let taken = "conformance:concurrent:taken";
+	let claimed = "conformance:concurrent:claimed";
+	store.delete_value(claimed).await.unwrap();
+	store.set_value(taken, "once", None).await.unwrap();
+
+	let tasks: Vec<_> = (0..CONCURRENCY)
+		.map(|i| {
+			let store = store.clone();
+			tokio::spawn(async move {
+				store.increment_value(counter, 1, None).await.unwrap();
+				store.push_value(list, &i.to_string()).await.unwrap();
+				let session = user_session("concurrent@example.com");
+				let cookie_value = self::store(store.as_ref(), &session).await;
+				let taken = store.take_value(taken).await.unwrap();
+				let claimed = store
+					.set_value_if_absent(claimed, &i.to_string(), None)
+					.await
+					.unwrap();
+				(cookie_value, taken, claimed)
+			})
+		})
+		.collect();
+	let mut results = vec![];
+	for task in tasks {
+		results.push(task.await.unwrap());
+	}
+
+	assert_eq!(
+		store.increment_value(counter, 0, None).await.unwrap(),
+		CONCURRENCY as i64
+	);
+	let mut values = store.list_values(list).await.unwrap();
+	values.sort_by_key(|value| value.parse::<usize>().unwrap());
+	assert_eq!(
+		values,
+		(0..CONCURRENCY).map(|i| i.to_string()).collect::<Vec<_>>()
+	);
+	let takers = results
+		.iter()
+		.filter(|(_, taken, _)| taken.is_some())
+		.count();
+	assert_eq!(takers, 1, "a value can only be taken once");
+	let claimers = results.iter().filter(|(_, _, claimed)| *claimed).count();
+	assert_eq!(claimers, 1, "a value can only be set once if absent");
+	for (cookie_value, _, _) in &results {
+		assert!(load(store.as_ref(), cookie_value).await.is_some());
+	}
+
+	for key in [counter, list, claimed] {
+		store.delete_value(key).await.unwrap();
+	}
+}
+
+/// Concurrent requests never take more tokens than a bucket holds
+pub async fn concurrent_rate_limits(store: &SharedMemoryStore) {
+	let limit = RateLimit {
+		burst: 5,
+		per_minute: 0,
+	};
+	let tasks: Vec<_> = (0..CONCURRENCY)
+		.map(|_| {
+			let store = store.clone();
+			tokio::spawn(async move {
+				store
+					.take_rate_limit_token("conformance:concurrent:rate-limit", limit)
+					.await
+					.unwrap()
+			})
+		})
+		.collect();
+	let mut allowed = 0;
+	for task in tasks {
+		if task.await.unwrap().allowed {
+			allowed += 1;
+		}
+	}
+	assert_eq!(allowed, limit.burst as usize);
+}
@@ -0,0 +1,254 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{routing::get, Router};
+use hyper::{
+	header::{AUTHORIZATION, CONTENT_TYPE, COOKIE},
+	Body, Request, Response, StatusCode,
+};
+use sabi_api::{
+	services::auth::{
+		routes, DeviceAuthorizationResponse, DeviceTokenResponse, GrantedScopes, User,
+	},
+	AppState,
+};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+
+mod common;
+
+fn create_state(poll_interval: Duration) -> AppState {
+	let mut state = common::create_state();
+	let mut config = (*state.config).clone();
+	config.device_flow.poll_interval = poll_interval;
+	state.config = Arc::new(config);
+	state
+}
+
+fn create_router(state: AppState) -> Router {
+	Router::new()
+		.route(
+			"/whoami",
+			get(|_: GrantedScopes, user: User| async move { user.email }),
+		)
+		.nest("/auth", routes())
+		.with_state(state)
+}
+
+async fn post(app: &Router, uri: &str, cookie: &str, body: Value) -> Response<axum::body::BoxBody> {
+	let request = Request::builder()
+		.method("POST")
+		.uri(uri)
+		.header(COOKIE, cookie)
+		.header(CONTENT_TYPE, "application/json")
+		.body(Body::from(body.to_string()))
+		.unwrap();
+	app.clone().oneshot(request).await.unwrap()
+}
+
+async fn json_body(response: Response<axum::body::BoxBody>) -> Value {
+	let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+	serde_json::from_slice(&body).unwrap()
+}
+
+async fn start(app: &Router) -> DeviceAuthorizationResponse {
+	let response = post(app, "/auth/device", "", json!({})).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+	serde_json::from_slice(&body).unwrap()
+}
+
+async fn poll(app: &Router, device_code: &str) -> Response<axum::body::BoxBody> {
+	post(
+		app,
+		"/auth/device/token",
+		"",
+		json!({ "device_code": device_code }),
+	)
+	.await
+}
+
+async fn assert_poll_error(app: &Router, device_code: &str, error: &str) {
+	let response = poll(app, device_code).await;
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+	assert_eq!(json_body(response).await, json!({ "error": error }));
+}
+
+#[tokio::test]
+async fn test_device_flow_approved() {
+	let state = create_state(Duration::ZERO);
+	let app = create_router(state.clone());
+	let authorization = start(&app).await;
+	assert_eq!(authorization.user_code.len(), 9);
+	assert_eq!(
+		authorization.verification_uri,
+		"http://127.0.0.1:3030/auth/device/verify"
+	);
+	assert_eq!(
+		authorization.verification_uri_complete,
+		format!(
+			"{}?user_code={}",
+			authorization.verification_uri, authorization.user_code
+		)
+	);
+
+	assert_poll_error(&app, &authorization.device_code, "authorization_pending").await;
+
+	// Approving requires a logged in user
+	let user_code = json!({ "user_code": authorization.user_code.to_lowercase() });
+	let response = post(&app, "/auth/device/approve", "", user_code.clone()).await;
+	assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+
+	let cookie = common::login(&state, &common::create_user("user@example.com")).await;
+	let request = Request::builder()
+		.uri(format!(
+			"/auth/device/verify?user_code={}",
+			authorization.user_code
+		))
+		.header(COOKIE, &cookie)
+		.body(Body::empty())
+		.unwrap();
+	let response = app.clone().oneshot(request).await.unwrap();
+	assert_eq!(response.status(), StatusCode::OK);
+	let body = json_body(response).await;
+	assert_eq!(body["user_code"], json!(authorization.user_code));
+	assert_eq!(body["status"], "pending");
+
+	let response = post(&app, "/auth/device/approve", &cookie, user_code.clone()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	// The user code cannot be used again
+	let response = post(&app, "/auth/device/approve", &cookie, user_code).await;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+	let response = poll(&app, &authorization.device_code).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+	let token: DeviceTokenResponse = serde_json::from_slice(&body).unwrap();
+	assert_eq!(token.token_type, "Bearer");
+	assert_eq!(token.expires_in, state.config.api_token_ttl.as_secs());
+
+	// The token authenticates requests without a session
+	let request = Request::builder()
+		.uri("/whoami")
+		.header(AUTHORIZATION, format!("Bearer {}", token.access_token))
+		.body(Body::empty())
+		.unwrap();
+	let response = app.clone().oneshot(request).await.unwrap();
+	assert_eq!(response.status(), StatusCode::OK);
+	let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+	assert_eq!(&body[..], b"user@example.com");
+
+	// A device code only ever yields a single token
+	assert_poll_error(&app, &authorization.device_code, "expired_token").await;
+
+	// Devices cannot approve other devices with their token
+	let other = start(&app).await;
+	let request = Request::builder()
+		.method("POST")
+		.uri("/auth/device/approve")
+		.header(AUTHORIZATION, format!("Bearer {}", token.access_token))
+		.header(CONTENT_TYPE, "application/json")
+		.body(Body::from(
+			json!({ "user_code": other.user_code }).to_string(),
+		))
+		.unwrap();
+	let response = app.clone().oneshot(request).await.unwrap();
+	assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+	assert_poll_error(&app, &other.device_code, "authorization_pending").await;
+}
+
+#[tokio::test]
+async fn test_device_flow_denied() {
+	let state = create_state(Duration::ZERO);
+	let app = create_router(state.clone());
+	let authorization = start(&app).await;
+	let cookie = common::login(&state, &common::create_user("user@example.com")).await;
+
+	let user_code = json!({ "user_code": authorization.user_code });
+	let response = post(&app, "/auth/device/deny", &cookie, user_code).await;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	assert_poll_error(&app, &authorization.device_code, "access_denied").await;
+	assert_poll_error(&app, &authorization.device_code, "expired_token").await;
+}
+
+#[tokio::test]
+async fn test_device_flow_slow_down() {
+	let state = create_state(Duration::from_secs(5));
+	let app = create_router(state);
+	let authorization = start(&app).await;
+	assert_eq!(authorization.interval, 5);
+
+	assert_poll_error(&app, &authorization.device_code, "authorization_pending").await;
+	assert_poll_error(&app, &authorization.device_code, "slow_down").await;
+}
+
+#[tokio::test]
+async fn test_device_flow_unknown_codes() {
+	let state = create_state(Duration::ZERO);
+	let app = create_router(state.clone());
+	assert_poll_error(&app, "unknown", "expired_token").await;
+
+	let cookie = common::login(&state, &common::create_user("user@example.com")).await;
+	let user_code = json!({ "user_code": "BCDF-GHJK" });
+	let response = post(&app, "/auth/device/approve", &cookie, user_code).await;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+	let request = Request::builder()
+		.uri("/whoami")
+		.header(AUTHORIZATION, "Bearer unknown")
+		.body(Body::empty())
+		.unwrap();
+	let response = app.clone().oneshot(request).await.unwrap();
+	assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+}
+
+#[tokio::test]
+async fn test_device_flow_decided_once() {
+	let state = create_state(Duration::ZERO);
+	let app = create_router(state.clone());
+	let authorization = start(&app).await;
+	let cookie = common::login(&state, &common::create_user("user@example.com")).await;
+
+	let user_code = json!({ "user_code": authorization.user_code });
+	let (approved, denied) = tokio::join!(
+		post(&app, "/auth/device/approve", &cookie, user_code.clone()),
+		post(&app, "/auth/device/deny", &cookie, user_code),
+	);
+	// The losing decision either sees the claimed authorization or the deleted user code
+	let (winner, loser) = if approved.status() == StatusCode::OK {
+		(approved.status(), denied.status())
+	} else {
+		(denied.status(), approved.status())
+	};
+	assert_eq!(winner, StatusCode::OK);
+	assert!([StatusCode::CONFLICT, StatusCode::NOT_FOUND].contains(&loser));
+
+	let response = poll(&app, &authorization.device_code).await;
+	if approved.status() == StatusCode::OK {
+		assert_eq!(response.status(), StatusCode::OK);
+	} else {
+		assert_eq!(
+			json_body(response).await,
+			json!({ "error": "access_denied" })
+		);
+	}
+}
+
+#[tokio::test]
+async fn test_device_flow_polls_keep_approval() {
+	let state = create_state(Duration::ZERO);
+	let app = create_router(state.clone());
+	let authorization = start(&app).await;
+	let cookie = common::login(&state, &common::create_user("user@example.com")).await;
+
+	let user_code = json!({ "user_code": authorization.user_code });
+	let (_, _, approved) = tokio::join!(
+		poll(&app, &authorization.device_code),
+		poll(&app, &authorization.device_code),
+		post(&app, "/auth/device/approve", &cookie, user_code),
+	);
+	assert_eq!(approved.status(), StatusCode::OK);
+
+	let response = poll(&app, &authorization.device_code).await;
+	assert_eq!(response.status(), StatusCode::OK);
+}
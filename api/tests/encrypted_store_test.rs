@@ -0,0 +1,188 @@
+use async_session::{Session, SessionStore};
+use axum::Router;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::{header::COOKIE, Body, Request, StatusCode};
+use sabi_api::{
+	crypto::Keyring,
+	encrypted_store::EncryptedStore,
+	in_memory_store::InMemoryStore,
+	memory_store::{MemoryStore, SharedMemoryStore},
+	services::auth::{
+		load_user, routes, save_provider_token, save_user, take_provider_token, ProviderToken,
+		ProviderType,
+	},
+};
+use tower::ServiceExt;
+
+mod common;
+
+fn keyring(entries: &[(&str, u8)]) -> Keyring {
+	let entries: Vec<String> = entries
+		.iter()
+		.map(|(id, key)| format!("{}:{}", id, STANDARD.encode([*key; 32])))
+		.collect();
+	Keyring::from_entries(&entries).unwrap()
+}
+
+fn user_session() -> Session {
+	let mut session = Session::new();
+	session
+		.insert("user", common::create_user("User@Example.com"))
+		.unwrap();
+	session
+}
+
+fn stored_key(session: &Session) -> String {
+	format!("session:{{user@example.com}}:{}", session.id())
+}
+
+#[tokio::test]
+async fn test_sessions_are_encrypted_at_rest() {
+	let backend = SharedMemoryStore::new(InMemoryStore::new());
+	let store = EncryptedStore::new(backend.clone(), keyring(&[("current", 1)]));
+	let session = user_session();
+	let cookie_value = store.store_session(session.clone()).await.unwrap().unwrap();
+
+	let stored = backend
+		.get_value(&stored_key(&session))
+		.await
+		.unwrap()
+		.unwrap();
+	assert!(stored.starts_with("current:"));
+	assert!(!stored.contains("example.com"));
+
+	let loaded = store.load_session(cookie_value.clone()).await.unwrap();
+	assert_eq!(
+		loaded.map(|s| s.id().to_string()),
+		Some(session.id().to_string())
+	);
+
+	store.destroy_session(session).await.unwrap();
+	assert!(store.load_session(cookie_value).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_sessions_survive_key_rotation() {
+	let backend = SharedMemoryStore::new(InMemoryStore::new());
+	let old = EncryptedStore::new(backend.clone(), keyring(&[("old", 1)]));
+	let session = user_session();
+	let cookie_value = old.store_session(session.clone()).await.unwrap().unwrap();
+
+	let rotated = EncryptedStore::new(backend.clone(), keyring(&[("new", 2), ("old", 1)]));
+	let loaded = rotated.load_session(cookie_value.clone()).await.unwrap();
+	assert!(loaded.is_some());
+
+	// Storing the session again moves it to the current key
+	rotated.store_session(session.clone()).await.unwrap();
+	let stored = backend
+		.get_value(&stored_key(&session))
+		.await
+		.unwrap()
+		.unwrap();
+	assert!(stored.starts_with("new:"));
+
+	// Once the old key is retired, its sessions are logged out
+	let retired = EncryptedStore::new(backend.clone(), keyring(&[("new", 2)]));
+	old.store_session(session).await.unwrap();
+	assert!(retired.load_session(cookie_value).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_plaintext_sessions_are_ignored() {
+	let backend = SharedMemoryStore::new(InMemoryStore::new());
+	let session = user_session();
+	let cookie_value = backend.store_session(session).await.unwrap().unwrap();
+
+	let store = EncryptedStore::new(backend, keyring(&[("current", 1)]));
+	assert!(store.load_session(cookie_value).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_profiles_and_provider_tokens_are_encrypted_at_rest() {
+	let backend = SharedMemoryStore::new(InMemoryStore::new());
+	let store = EncryptedStore::new(backend.clone(), keyring(&[("current", 1)]));
+	let user = common::create_user("user@example.com");
+	save_user(&store, &user).await.unwrap();
+	let provider_token = ProviderToken {
+		provider: ProviderType::Google,
+		access_token: "secret-access-token".to_string(),
+		refresh_token: None,
+	};
+	let session = user_session();
+	save_provider_token(&store, &session, &provider_token)
+		.await
+		.unwrap();
+
+	let token_key = format!("oauth_token:{}", session.id());
+	for key in ["user:{user@example.com}", &token_key] {
+		let stored = backend.get_value(key).await.unwrap().unwrap();
+		assert!(stored.starts_with("current:"), "{}", key);
+		assert!(!stored.contains("example.com"), "{}", key);
+		assert!(!stored.contains("secret-access-token"), "{}", key);
+	}
+
+	let loaded = load_user(&store, "user@example.com")
+		.await
+		.unwrap()
+		.unwrap();
+	assert_eq!(loaded.email, "user@example.com");
+	let taken = take_provider_token(&store, &session)
+		.await
+		.unwrap()
+		.unwrap();
+	assert_eq!(taken.access_token, "secret-access-token");
+	assert!(take_provider_token(&store, &session)
+		.await
+		.unwrap()
+		.is_none());
+
+	// Other keys hold no personal data and are left readable
+	store
+		.set_value("login_token:hash", "value", None)
+		.await
+		.unwrap();
+	assert_eq!(
+		backend.get_value("login_token:hash").await.unwrap(),
+		Some("value".to_string())
+	);
+}
+
+#[tokio::test]
+async fn test_plaintext_profiles_are_ignored() {
+	let backend = SharedMemoryStore::new(InMemoryStore::new());
+	save_user(backend.as_ref(), &common::create_user("user@example.com"))
+		.await
+		.unwrap();
+
+	let store = EncryptedStore::new(backend, keyring(&[("current", 1)]));
+	assert!(load_user(&store, "user@example.com")
+		.await
+		.unwrap()
+		.is_none());
+}
+
+#[tokio::test]
+async fn test_login_with_encrypted_sessions() {
+	let mut state = common::create_state();
+	state.memory_store = SharedMemoryStore::new(EncryptedStore::new(
+		state.memory_store.clone(),
+		keyring(&[("current", 1)]),
+	));
+	let cookie = common::login(&state, &common::create_user("user@example.com")).await;
+
+	let app = Router::new().nest("/auth", routes()).with_state(state);
+	let request = Request::builder()
+		.uri("/auth/me")
+		.header(COOKIE, cookie)
+		.body(Body::empty())
+		.unwrap();
+	let response = app.oneshot(request).await.unwrap();
+	assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_encrypted_store_conformance() {
+	let backend = SharedMemoryStore::new(InMemoryStore::new());
+	let store = EncryptedStore::new(backend, keyring(&[("current", 1)]));
+	common::store_conformance::run(SharedMemoryStore::new(store)).await;
+}
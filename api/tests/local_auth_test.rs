@@ -0,0 +1,344 @@
+use std::{
+	path::{Path, PathBuf},
+	sync::Arc,
+};
+
+use axum::{routing::get, Router};
+use hyper::{
+	header::{CONTENT_TYPE, COOKIE, SET_COOKIE},
+	Body, Request, Response, StatusCode,
+};
+use sabi_api::{
+	config::SameSite,
+	mailer::FileMailer,
+	memory_store::user_hash_tag,
+	services::auth::{hash_password, routes, User},
+	tokens::generate_token,
+	AppState,
+};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+
+mod common;
+
+fn create_state(local_auth_enabled: bool, drop_dir: &Path) -> AppState {
+	let mut state = common::create_state();
+	let mut config = (*state.config).clone();
+	config.local_auth_enabled = local_auth_enabled;
+	state.config = Arc::new(config);
+	state.mailer = Arc::new(FileMailer::new(drop_dir));
+	state
+}
+
+fn drop_dir() -> PathBuf {
+	std::env::temp_dir().join(format!("sabi-mail-{}", &generate_token()[..16]))
+}
+
+fn create_router(state: AppState) -> Router {
+	Router::new()
+		.route("/whoami", get(|user: User| async move { user.email }))
+		.nest("/auth", routes())
+		.with_state(state)
+}
+
+async fn post(
+	app: &Router,
+	uri: &str,
+	cookie: Option<&str>,
+	body: Value,
+) -> Response<axum::body::BoxBody> {
+	let mut request = Request::builder()
+		.method("POST")
+		.uri(uri)
+		.header(CONTENT_TYPE, "application/json");
+	if let Some(cookie) = cookie {
+		request = request.header(COOKIE, cookie);
+	}
+	let request = request.body(Body::from(body.to_string())).unwrap();
+	app.clone().oneshot(request).await.unwrap()
+}
+
+fn session_cookie(response: &Response<axum::body::BoxBody>) -> String {
+	let set_cookie = response.headers()[SET_COOKIE].to_str().unwrap();
+	set_cookie.split(';').next().unwrap().to_string()
+}
+
+async fn whoami(app: &Router, cookie: &str) -> String {
+	let request = Request::builder()
+		.uri("/whoami")
+		.header(COOKIE, cookie)
+		.body(Body::empty())
+		.unwrap();
+	let response = app.clone().oneshot(request).await.unwrap();
+	let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+	String::from_utf8(body.to_vec()).unwrap()
+}
+
+async fn get_uri(app: &Router, uri: &str) -> Response<axum::body::BoxBody> {
+	let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+	app.clone().oneshot(request).await.unwrap()
+}
+
+/// Return the path and query of the verification links sent to the drop directory
+fn read_links(drop_dir: &Path) -> Vec<String> {
+	let Ok(entries) = std::fs::read_dir(drop_dir) else {
+		return Vec::new();
+	};
+	entries
+		.map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+		.filter_map(|content| {
+			content
+				.lines()
+				.find(|line| line.starts_with("http://127.0.0.1:3030/auth/local/verify?"))
+				.map(|link| link.trim_start_matches("http://127.0.0.1:3030").to_string())
+		})
+		.collect()
+}
+
+/// Return the subjects of the emails sent to the drop directory
+fn read_subjects(drop_dir: &Path) -> Vec<String> {
+	let Ok(entries) = std::fs::read_dir(drop_dir) else {
+		return Vec::new();
+	};
+	entries
+		.map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+		.filter_map(|content| {
+			content
+				.lines()
+				.find_map(|line| line.strip_prefix("Subject: ").map(str::to_string))
+		})
+		.collect()
+}
+
+/// Register the default user and follow its verification link
+async fn register(app: &Router, drop_dir: &Path) -> Response<axum::body::BoxBody> {
+	let response = post(app, "/auth/local/register", None, registration()).await;
+	assert_eq!(response.status(), StatusCode::ACCEPTED);
+	let links = read_links(drop_dir);
+	assert_eq!(links.len(), 1);
+	get_uri(app, &links[0]).await
+}
+
+fn registration() -> Value {
+	json!({
+		"email": "User@Example.com",
+		"username": "user",
+		"password": "correct horse",
+	})
+}
+
+#[tokio::test]
+async fn test_local_auth_disabled() {
+	let app = create_router(create_state(false, &drop_dir()));
+
+	let response = post(&app, "/auth/local/register", None, registration()).await;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_local_register_and_login() {
+	let drop_dir = drop_dir();
+	let app = create_router(create_state(true, &drop_dir));
+
+	let response = post(&app, "/auth/local/register", None, registration()).await;
+	assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+	let credentials = json!({ "email": "user@example.com", "password": "correct horse" });
+	let response = post(&app, "/auth/local/login", None, credentials).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	let link = read_links(&drop_dir).pop().unwrap();
+	let response = get_uri(&app, &link).await;
+	assert_eq!(response.status(), StatusCode::SEE_OTHER);
+	assert_eq!(
+		whoami(&app, &session_cookie(&response)).await,
+		"user@example.com"
+	);
+
+	let response = get_uri(&app, &link).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	// Registering again looks the same, the owner of the email is told instead
+	let response = post(&app, "/auth/local/register", None, registration()).await;
+	assert_eq!(response.status(), StatusCode::ACCEPTED);
+	assert_eq!(read_links(&drop_dir).len(), 1);
+	assert!(read_subjects(&drop_dir).contains(&"Your sabi account".to_string()));
+
+	let credentials = json!({ "email": "user@example.com", "password": "wrong password" });
+	let response = post(&app, "/auth/local/login", None, credentials).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	let credentials = json!({ "email": "nobody@example.com", "password": "correct horse" });
+	let response = post(&app, "/auth/local/login", None, credentials).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	let credentials = json!({ "email": "user@example.com", "password": "a".repeat(129) });
+	let response = post(&app, "/auth/local/login", None, credentials).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	let credentials = json!({ "email": "user@example.com", "password": "correct horse" });
+	let response = post(&app, "/auth/local/login", None, credentials).await;
+	assert_eq!(response.status(), StatusCode::SEE_OTHER);
+	assert_eq!(
+		whoami(&app, &session_cookie(&response)).await,
+		"user@example.com"
+	);
+}
+
+#[tokio::test]
+async fn test_session_cookie_attributes() {
+	let drop_dir = drop_dir();
+	let app = create_router(create_state(true, &drop_dir));
+	let response = register(&app, &drop_dir).await;
+	assert!(response.headers()[SET_COOKIE]
+		.to_str()
+		.unwrap()
+		.ends_with("; SameSite=Lax; Path=/"));
+
+	let drop_dir = self::drop_dir();
+	let mut state = create_state(true, &drop_dir);
+	let mut config = (*state.config).clone();
+	config.cookies.domain = Some(Arc::new("example.com".to_string()));
+	config.cookies.same_site = SameSite::Strict;
+	config.cookies.secure = true;
+	state.config = Arc::new(config);
+	let app = create_router(state);
+	let response = register(&app, &drop_dir).await;
+	assert!(response.headers()[SET_COOKIE]
+		.to_str()
+		.unwrap()
+		.ends_with("; SameSite=Strict; Path=/; Domain=example.com; Secure"));
+}
+
+#[tokio::test]
+async fn test_local_register_validation() {
+	let drop_dir = drop_dir();
+	let app = create_router(create_state(true, &drop_dir));
+
+	let mut body = registration();
+	body["password"] = json!("short");
+	let response = post(&app, "/auth/local/register", None, body).await;
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+	let mut body = registration();
+	body["password"] = json!("a".repeat(129));
+	let response = post(&app, "/auth/local/register", None, body).await;
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+	let mut body = registration();
+	body["email"] = json!("not an email");
+	let response = post(&app, "/auth/local/register", None, body).await;
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+	assert!(read_links(&drop_dir).is_empty());
+}
+
+#[tokio::test]
+async fn test_local_register_existing_oauth_user() {
+	let drop_dir = drop_dir();
+	let state = create_state(true, &drop_dir);
+	let app = create_router(state.clone());
+	common::login(&state, &common::create_user("user@example.com")).await;
+
+	let response = post(&app, "/auth/local/register", None, registration()).await;
+	assert_eq!(response.status(), StatusCode::ACCEPTED);
+	assert!(read_links(&drop_dir).is_empty());
+	assert_eq!(read_subjects(&drop_dir), ["Your sabi account"]);
+}
+
+#[tokio::test]
+async fn test_local_register_unowned_email() {
+	let drop_dir = drop_dir();
+	let state = create_state(true, &drop_dir);
+	let app = create_router(state.clone());
+
+	// Someone registers an email they do not own, then its owner signs in with a provider
+	let response = post(&app, "/auth/local/register", None, registration()).await;
+	assert_eq!(response.status(), StatusCode::ACCEPTED);
+	common::login(&state, &common::create_user("user@example.com")).await;
+
+	let credentials = json!({ "email": "user@example.com", "password": "correct horse" });
+	let response = post(&app, "/auth/local/login", None, credentials).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	let response = post(&app, "/auth/local/register", None, registration()).await;
+	assert_eq!(response.status(), StatusCode::ACCEPTED);
+	assert_eq!(read_links(&drop_dir).len(), 1);
+}
+
+#[tokio::test]
+async fn test_local_register_concurrently() {
+	let drop_dir = drop_dir();
+	let app = create_router(create_state(true, &drop_dir));
+
+	let (first, second) = tokio::join!(
+		post(&app, "/auth/local/register", None, registration()),
+		post(&app, "/auth/local/register", None, registration()),
+	);
+	assert_eq!(first.status(), StatusCode::ACCEPTED);
+	assert_eq!(second.status(), StatusCode::ACCEPTED);
+	assert_eq!(read_links(&drop_dir).len(), 1);
+	assert_eq!(read_subjects(&drop_dir).len(), 2);
+}
+
+#[tokio::test]
+async fn test_credentials_without_verification_are_refused() {
+	let state = create_state(true, &drop_dir());
+	let app = create_router(state.clone());
+	common::login(&state, &common::create_user("user@example.com")).await;
+
+	// Credentials saved without the verified flag
+	let password_hash = hash_password("correct horse".to_string()).await.unwrap();
+	state
+		.memory_store
+		.set_value(
+			&format!("credentials:{}", user_hash_tag("user@example.com")),
+			&json!({ "password_hash": password_hash }).to_string(),
+			None,
+		)
+		.await
+		.unwrap();
+
+	let credentials = json!({ "email": "user@example.com", "password": "correct horse" });
+	let response = post(&app, "/auth/local/login", None, credentials).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_local_change_password() {
+	let drop_dir = drop_dir();
+	let app = create_router(create_state(true, &drop_dir));
+	let response = register(&app, &drop_dir).await;
+	let cookie = session_cookie(&response);
+	let credentials = json!({ "email": "user@example.com", "password": "correct horse" });
+	let response = post(&app, "/auth/local/login", None, credentials).await;
+	let other_cookie = session_cookie(&response);
+
+	let change = json!({ "current_password": "wrong password", "new_password": "battery staple" });
+	let response = post(&app, "/auth/local/password", Some(&cookie), change).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	let change = json!({ "current_password": "correct horse", "new_password": "a".repeat(129) });
+	let response = post(&app, "/auth/local/password", Some(&cookie), change).await;
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+	let change = json!({ "current_password": "correct horse", "new_password": "battery staple" });
+	let response = post(&app, "/auth/local/password", Some(&cookie), change).await;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	// Only the session which changed the password stays open
+	assert_eq!(whoami(&app, &cookie).await, "user@example.com");
+	assert_ne!(whoami(&app, &other_cookie).await, "user@example.com");
+
+	let credentials = json!({ "email": "user@example.com", "password": "correct horse" });
+	let response = post(&app, "/auth/local/login", None, credentials).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	let credentials = json!({ "email": "user@example.com", "password": "battery staple" });
+	let response = post(&app, "/auth/local/login", None, credentials).await;
+	assert_eq!(response.status(), StatusCode::SEE_OTHER);
+	assert_eq!(
+		whoami(&app, &session_cookie(&response)).await,
+		"user@example.com"
+	);
+}
@@ -0,0 +1,135 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use axum::{routing::get, Router};
+use hyper::{
+	header::{CONTENT_TYPE, COOKIE, SET_COOKIE},
+	Body, Request, Response, StatusCode,
+};
+use sabi_api::{
+	mailer::{FileMailer, SmtpMailer},
+	services::auth::{routes, User},
+	tokens::generate_token,
+	AppState,
+};
+use serde_json::json;
+use tower::ServiceExt;
+
+mod common;
+
+fn create_state(drop_dir: &PathBuf, magic_link_ttl: Duration) -> AppState {
+	let mut state = common::create_state();
+	let mut config = (*state.config).clone();
+	config.magic_link_ttl = magic_link_ttl;
+	state.config = Arc::new(config);
+	state.mailer = Arc::new(FileMailer::new(drop_dir));
+	state
+}
+
+fn create_router(state: AppState) -> Router {
+	Router::new()
+		.route("/whoami", get(|user: User| async move { user.email }))
+		.nest("/auth", routes())
+		.with_state(state)
+}
+
+fn drop_dir() -> PathBuf {
+	std::env::temp_dir().join(format!("sabi-mail-{}", &generate_token()[..16]))
+}
+
+async fn request_link(app: &Router, email: &str) -> Response<axum::body::BoxBody> {
+	let request = Request::builder()
+		.method("POST")
+		.uri("/auth/email")
+		.header(CONTENT_TYPE, "application/json")
+		.body(Body::from(json!({ "email": email }).to_string()))
+		.unwrap();
+	app.clone().oneshot(request).await.unwrap()
+}
+
+/// Read the only email of the drop directory and return the path and query of its link
+fn read_link(drop_dir: &PathBuf) -> String {
+	let entries = std::fs::read_dir(drop_dir)
+		.unwrap()
+		.map(|entry| entry.unwrap().path())
+		.collect::<Vec<_>>();
+	assert_eq!(entries.len(), 1);
+	let content = std::fs::read_to_string(&entries[0]).unwrap();
+	assert!(content.starts_with("To: user@example.com\n"));
+	let link = content
+		.lines()
+		.find(|line| line.starts_with("http://127.0.0.1:3030/"))
+		.unwrap();
+	link.trim_start_matches("http://127.0.0.1:3030").to_string()
+}
+
+async fn get_with_cookie(app: &Router, uri: &str, cookie: &str) -> Response<axum::body::BoxBody> {
+	let request = Request::builder()
+		.uri(uri)
+		.header(COOKIE, cookie)
+		.body(Body::empty())
+		.unwrap();
+	app.clone().oneshot(request).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_magic_link_login() {
+	let drop_dir = drop_dir();
+	let app = create_router(create_state(&drop_dir, Duration::from_secs(60)));
+
+	let response = request_link(&app, "User@Example.com").await;
+	assert_eq!(response.status(), StatusCode::ACCEPTED);
+	let link = read_link(&drop_dir);
+
+	let response = get_with_cookie(&app, &link, "").await;
+	assert_eq!(response.status(), StatusCode::SEE_OTHER);
+	let set_cookie = response.headers()[SET_COOKIE].to_str().unwrap();
+	let cookie = set_cookie.split(';').next().unwrap();
+	let response = get_with_cookie(&app, "/whoami", cookie).await;
+	let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+	assert_eq!(body, "user@example.com");
+
+	// Links can only be used once
+	let response = get_with_cookie(&app, &link, "").await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	std::fs::remove_dir_all(drop_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_magic_link_expired() {
+	let drop_dir = drop_dir();
+	let app = create_router(create_state(&drop_dir, Duration::from_millis(1)));
+
+	let response = request_link(&app, "user@example.com").await;
+	assert_eq!(response.status(), StatusCode::ACCEPTED);
+	let link = read_link(&drop_dir);
+	tokio::time::sleep(Duration::from_millis(10)).await;
+
+	let response = get_with_cookie(&app, &link, "").await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	std::fs::remove_dir_all(drop_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_magic_link_invalid() {
+	let drop_dir = drop_dir();
+	let app = create_router(create_state(&drop_dir, Duration::from_secs(60)));
+
+	let response = request_link(&app, "not an email").await;
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+	let response = get_with_cookie(&app, "/auth/email/verify?token=unknown", "").await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_magic_link_rejected_address() {
+	let mut state = common::create_state();
+	// The address is refused before any connection to the relay
+	state.mailer = Arc::new(SmtpMailer::new("smtp://127.0.0.1:25", "sabi@localhost").unwrap());
+	let app = create_router(state);
+
+	let response = request_link(&app, "user@@example.com").await;
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
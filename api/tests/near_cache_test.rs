@@ -0,0 +1,302 @@
+use std::{
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	time::Duration,
+};
+
+use async_session::{Session, SessionStore};
+use sabi_api::{
+	config::RateLimit,
+	in_memory_store::InMemoryStore,
+	memory_store::{MemoryStore, SharedMemoryStore, StoreError, StoreResult, Subscription},
+	near_cache::{NearCacheMetrics, NearCacheStore},
+	rate_limit::RateLimitDecision,
+	sql_store::SqlStore,
+};
+
+mod common;
+
+const TTL: Duration = Duration::from_secs(60);
+
+fn user_session(email: &str) -> Session {
+	let mut session = Session::new();
+	session.insert("user", common::create_user(email)).unwrap();
+	session
+}
+
+async fn near_cache(backend: &SharedMemoryStore, capacity: usize, ttl: Duration) -> NearCacheStore {
+	NearCacheStore::new(backend.clone(), capacity, ttl)
+		.await
+		.unwrap()
+}
+
+fn near_cache_metrics(store: &NearCacheStore) -> NearCacheMetrics {
+	store.metrics().unwrap().near_cache.unwrap()
+}
+
+/// A store whose publish fails once `failing` is set
+#[derive(Clone, Debug)]
+struct FailingPublishStore {
+	store: SharedMemoryStore,
+	failing: Arc<AtomicBool>,
+}
+
+#[async_trait::async_trait]
+impl SessionStore for FailingPublishStore {
+	async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+		Ok(self.store.load_session(cookie_value).await?)
+	}
+
+	async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+		Ok(self.store.store_session(session).await?)
+	}
+
+	async fn destroy_session(&self, session: Session) -> async_session::Result {
+		Ok(self.store.destroy_session(session).await?)
+	}
+
+	async fn clear_store(&self) -> async_session::Result {
+		Ok(self.store.clear_store().await?)
+	}
+}
+
+#[async_trait::async_trait]
+impl MemoryStore for FailingPublishStore {
+	async fn take_rate_limit_token(
+		&self,
+		key: &str,
+		limit: RateLimit,
+	) -> StoreResult<RateLimitDecision> {
+		self.store.take_rate_limit_token(key, limit).await
+	}
+
+	async fn get_value(&self, key: &str) -> StoreResult<Option<String>> {
+		self.store.get_value(key).await
+	}
+
+	async fn set_value(&self, key: &str, value: &str, ttl: Option<Duration>) -> StoreResult {
+		self.store.set_value(key, value, ttl).await
+	}
+
+	async fn set_value_if_absent(
+		&self,
+		key: &str,
+		value: &str,
+		ttl: Option<Duration>,
+	) -> StoreResult<bool> {
+		self.store.set_value_if_absent(key, value, ttl).await
+	}
+
+	async fn delete_value(&self, key: &str) -> StoreResult {
+		self.store.delete_value(key).await
+	}
+
+	async fn increment_value(
+		&self,
+		key: &str,
+		delta: i64,
+		ttl: Option<Duration>,
+	) -> StoreResult<i64> {
+		self.store.increment_value(key, delta, ttl).await
+	}
+
+	async fn take_value(&self, key: &str) -> StoreResult<Option<String>> {
+		self.store.take_value(key).await
+	}
+
+	async fn push_value(&self, key: &str, value: &str) -> StoreResult {
+		self.store.push_value(key, value).await
+	}
+
+	async fn list_values(&self, key: &str) -> StoreResult<Vec<String>> {
+		self.store.list_values(key).await
+	}
+
+	async fn publish(&self, channel: &str, message: &str) -> StoreResult {
+		if self.failing.load(Ordering::Relaxed) {
+			return Err(StoreError::Unavailable("connection refused".to_string()));
+		}
+		self.store.publish(channel, message).await
+	}
+
+	async fn subscribe(&self, channel: &str) -> StoreResult<Subscription> {
+		self.store.subscribe(channel).await
+	}
+}
+
+/// Wait for the invalidations published by other instances to be applied
+async fn until(condition: impl Fn() -> bool) {
+	tokio::time::timeout(Duration::from_secs(1), async {
+		while !condition() {
+			tokio::time::sleep(Duration::from_millis(5)).await;
+		}
+	})
+	.await
+	.expect("The condition should be met in time");
+}
+
+#[tokio::test]
+async fn test_sessions_are_served_from_memory() {
+	let backend = SharedMemoryStore::new(InMemoryStore::new());
+	let store = near_cache(&backend, 10, TTL).await;
+	let cookie_value = store
+		.store_session(user_session("user@example.com"))
+		.await
+		.unwrap()
+		.unwrap();
+
+	for _ in 0..3 {
+		assert!(store
+			.load_session(cookie_value.clone())
+			.await
+			.unwrap()
+			.is_some());
+	}
+	let metrics = near_cache_metrics(&store);
+	assert_eq!((metrics.hits, metrics.misses, metrics.entries), (2, 1, 1));
+
+	// Sessions missing from the store are not cached
+	let unknown = serde_json::to_string(&user_session("user@example.com")).unwrap();
+	assert!(store.load_session(unknown.clone()).await.unwrap().is_none());
+	assert!(store.load_session(unknown).await.unwrap().is_none());
+	assert_eq!(near_cache_metrics(&store).misses, 3);
+}
+
+#[tokio::test]
+async fn test_logout_evicts_the_session_on_every_instance() {
+	let backend = SharedMemoryStore::new(InMemoryStore::new());
+	let first = near_cache(&backend, 10, TTL).await;
+	let second = near_cache(&backend, 10, TTL).await;
+	let mut session = user_session("user@example.com");
+	let cookie_value = first.store_session(session.clone()).await.unwrap().unwrap();
+	assert!(second
+		.load_session(cookie_value.clone())
+		.await
+		.unwrap()
+		.is_some());
+	assert_eq!(near_cache_metrics(&second).entries, 1);
+
+	// Changes made on one instance are seen by the other
+	session.insert("visits", 2).unwrap();
+	first.store_session(session.clone()).await.unwrap();
+	until(|| near_cache_metrics(&second).entries == 0).await;
+	let loaded = second.load_session(cookie_value.clone()).await.unwrap();
+	assert_eq!(loaded.unwrap().get::<u32>("visits"), Some(2));
+
+	first.destroy_session(session).await.unwrap();
+	until(|| near_cache_metrics(&second).entries == 0).await;
+	assert!(second.load_session(cookie_value).await.unwrap().is_none());
+	assert!(near_cache_metrics(&second).invalidations >= 2);
+}
+
+#[tokio::test]
+async fn test_session_epochs_are_cached_and_invalidated() {
+	let backend = SharedMemoryStore::new(InMemoryStore::new());
+	let first = near_cache(&backend, 10, TTL).await;
+	let second = near_cache(&backend, 10, TTL).await;
+	let key = "session_epoch:{user}";
+	assert_eq!(second.get_value(key).await.unwrap(), None);
+	assert_eq!(second.get_value(key).await.unwrap(), None);
+	let metrics = near_cache_metrics(&second);
+	assert_eq!((metrics.hits, metrics.misses), (1, 1));
+
+	first.increment_value(key, 1, None).await.unwrap();
+	until(|| near_cache_metrics(&second).entries == 0).await;
+	assert_eq!(second.get_value(key).await.unwrap(), Some("1".to_string()));
+
+	// Other values are not cached
+	second.get_value("other").await.unwrap();
+	assert_eq!(near_cache_metrics(&second).entries, 1);
+}
+
+#[tokio::test]
+async fn test_cached_sessions_expire() {
+	let backend = SharedMemoryStore::new(InMemoryStore::new());
+	let store = near_cache(&backend, 10, Duration::from_millis(50)).await;
+	let session = user_session("user@example.com");
+	let cookie_value = store.store_session(session.clone()).await.unwrap().unwrap();
+	assert!(store
+		.load_session(cookie_value.clone())
+		.await
+		.unwrap()
+		.is_some());
+
+	// Removed behind the back of the near cache, which serves it until its TTL
+	backend.destroy_session(session).await.unwrap();
+	assert!(store
+		.load_session(cookie_value.clone())
+		.await
+		.unwrap()
+		.is_some());
+	tokio::time::sleep(Duration::from_millis(60)).await;
+	assert!(store.load_session(cookie_value).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_least_recently_used_sessions_are_evicted() {
+	let backend = SharedMemoryStore::new(InMemoryStore::new());
+	let store = near_cache(&backend, 2, TTL).await;
+	let mut cookies = vec![];
+	for email in ["a@example.com", "b@example.com", "c@example.com"] {
+		let cookie_value = store
+			.store_session(user_session(email))
+			.await
+			.unwrap()
+			.unwrap();
+		store.load_session(cookie_value.clone()).await.unwrap();
+		cookies.push(cookie_value);
+	}
+	assert_eq!(near_cache_metrics(&store).entries, 2);
+
+	store.load_session(cookies[0].clone()).await.unwrap();
+	let metrics = near_cache_metrics(&store);
+	assert_eq!((metrics.hits, metrics.misses), (0, 4));
+}
+
+#[tokio::test]
+async fn test_failed_invalidations_do_not_fail_writes() {
+	let failing = Arc::new(AtomicBool::new(false));
+	let backend = SharedMemoryStore::new(FailingPublishStore {
+		store: SharedMemoryStore::new(InMemoryStore::new()),
+		failing: failing.clone(),
+	});
+	let store = near_cache(&backend, 10, TTL).await;
+	let mut session = user_session("user@example.com");
+	let cookie_value = store.store_session(session.clone()).await.unwrap().unwrap();
+	assert!(store
+		.load_session(cookie_value.clone())
+		.await
+		.unwrap()
+		.is_some());
+
+	failing.store(true, Ordering::Relaxed);
+	session.insert("visits", 2).unwrap();
+	store.store_session(session.clone()).await.unwrap();
+	// The local copy is evicted all the same
+	let loaded = store.load_session(cookie_value.clone()).await.unwrap();
+	assert_eq!(loaded.unwrap().get::<u32>("visits"), Some(2));
+
+	store.destroy_session(session).await.unwrap();
+	assert!(store.load_session(cookie_value).await.unwrap().is_none());
+	store.clear_store().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_stores_without_publish_are_refused() {
+	let path = std::env::temp_dir().join(format!("sabi-near-cache-{}.db", std::process::id()));
+	let url = format!("sqlite://{}?mode=rwc", path.display());
+	let backend = SharedMemoryStore::new(SqlStore::new(&url).await.unwrap());
+	assert!(NearCacheStore::new(backend, 10, TTL).await.is_err());
+	for suffix in ["", "-shm", "-wal"] {
+		let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+	}
+}
+
+#[tokio::test]
+async fn test_near_cache_conformance() {
+	let backend = SharedMemoryStore::new(InMemoryStore::new());
+	let store = near_cache(&backend, 100, TTL).await;
+	common::store_conformance::run(SharedMemoryStore::new(store)).await;
+}
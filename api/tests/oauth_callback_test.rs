@@ -0,0 +1,49 @@
+use axum::Router;
+use hyper::{Body, Request, StatusCode};
+use sabi_api::services::auth::routes;
+use serde_json::{json, Value};
+use tower::ServiceExt;
+
+mod common;
+
+fn create_router() -> Router {
+	Router::new()
+		.nest("/auth", routes())
+		.with_state(common::create_state())
+}
+
+async fn callback(uri: &str) -> (StatusCode, Value) {
+	let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+	let response = create_router().oneshot(request).await.unwrap();
+	let status = response.status();
+	let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+	(status, serde_json::from_slice(&body).unwrap())
+}
+
+#[tokio::test]
+async fn test_callback_cancelled_by_user() {
+	for provider in ["discord", "google"] {
+		let uri = format!(
+			"/auth/{}/authorized?error=access_denied&state=xyz",
+			provider
+		);
+		let (status, body) = callback(&uri).await;
+		assert_eq!(status, StatusCode::UNAUTHORIZED);
+		assert_eq!(body, json!({ "error": "login cancelled" }));
+	}
+}
+
+#[tokio::test]
+async fn test_callback_provider_error() {
+	let uri = "/auth/discord/authorized?error=temporarily_unavailable&error_description=Try+later";
+	let (status, body) = callback(uri).await;
+	assert_eq!(status, StatusCode::BAD_GATEWAY);
+	assert_eq!(body, json!({ "error": "login failed" }));
+}
+
+#[tokio::test]
+async fn test_callback_without_code() {
+	let (status, body) = callback("/auth/google/authorized?state=xyz").await;
+	assert_eq!(status, StatusCode::BAD_REQUEST);
+	assert_eq!(body, json!({ "error": "invalid request" }));
+}
@@ -0,0 +1,149 @@
+use axum::Router;
+use hyper::{
+	header::{HeaderName, AUTHORIZATION, COOKIE},
+	Body, Request, StatusCode,
+};
+use sabi_api::{
+	services::auth::{
+		issue_api_token, routes, DiscordUser, GoogleUser, NewApiToken, ProfileResponse, SessionInfo,
+	},
+	AppState,
+};
+use serde_json::json;
+use tower::ServiceExt;
+
+mod common;
+
+fn create_router(state: AppState) -> Router {
+	Router::new().nest("/auth", routes()).with_state(state)
+}
+
+async fn me(app: &Router, credentials: Option<(HeaderName, &str)>) -> (StatusCode, Vec<u8>) {
+	let mut request = Request::builder().uri("/auth/me");
+	if let Some((name, value)) = credentials {
+		request = request.header(name, value);
+	}
+	let response = app
+		.clone()
+		.oneshot(request.body(Body::empty()).unwrap())
+		.await
+		.unwrap();
+	let status = response.status();
+	let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+	(status, body.to_vec())
+}
+
+fn discord_user(avatar: Option<&str>, discriminator: &str) -> DiscordUser {
+	DiscordUser {
+		id: "80351110224678912".to_string(),
+		avatar: avatar.map(str::to_string),
+		username: "nelly".to_string(),
+		discriminator: discriminator.to_string(),
+		email: "user@example.com".to_string(),
+	}
+}
+
+#[tokio::test]
+async fn test_me_requires_login() {
+	let app = create_router(common::create_state());
+	let (status, body) = me(&app, None).await;
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	assert_eq!(
+		serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+		json!({ "error": "unauthorized" })
+	);
+	let (status, _) = me(&app, Some((AUTHORIZATION, "Bearer unknown"))).await;
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_me_with_session() {
+	let state = common::create_state();
+	let app = create_router(state.clone());
+	let mut user = common::create_user("user@example.com");
+	user.discord = Some(discord_user(
+		Some("a_1269e74af4df7417b13759eae50c83dc"),
+		"0",
+	));
+	user.google = Some(GoogleUser {
+		id: Some("1234".to_string()),
+		email: "user@example.com".to_string(),
+		name: "Nelly".to_string(),
+		picture: Some("https://lh3.googleusercontent.com/a/picture".to_string()),
+	});
+	let cookie = common::login(&state, &user).await;
+
+	let (status, body) = me(&app, Some((COOKIE, &cookie))).await;
+	assert_eq!(status, StatusCode::OK);
+	let profile: ProfileResponse = serde_json::from_slice(&body).unwrap();
+	assert_eq!(profile.id.len(), 32);
+	assert_eq!(profile.id, user.id());
+	assert_eq!(profile.email, "user@example.com");
+	assert_eq!(profile.display_name, "Nelly");
+	assert_eq!(
+		profile.avatar_url.as_deref(),
+		Some("https://cdn.discordapp.com/avatars/80351110224678912/a_1269e74af4df7417b13759eae50c83dc.gif")
+	);
+	assert_eq!(profile.providers, vec!["discord", "google"]);
+	assert_eq!(
+		profile.session,
+		SessionInfo::Cookie {
+			expires_at: None,
+			impersonated_by: None,
+		}
+	);
+	// The shape of the session info is part of the documented response
+	let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+	assert_eq!(body["session"]["kind"], "cookie");
+}
+
+#[tokio::test]
+async fn test_me_with_api_token() {
+	let state = common::create_state();
+	let app = create_router(state.clone());
+	let user = common::create_user("user@example.com");
+	common::login(&state, &user).await;
+	let new_token = NewApiToken {
+		email: &user.email,
+		name: "ci",
+		scopes: None,
+		ttl: None,
+	};
+	let (api_token, token) = issue_api_token(state.memory_store.as_ref(), new_token)
+		.await
+		.unwrap();
+
+	let bearer = format!("Bearer {}", token);
+	let (status, body) = me(&app, Some((AUTHORIZATION, &bearer))).await;
+	assert_eq!(status, StatusCode::OK);
+	let profile: ProfileResponse = serde_json::from_slice(&body).unwrap();
+	assert_eq!(profile.display_name, "user@example.com");
+	assert_eq!(profile.avatar_url, None);
+	assert!(profile.providers.is_empty());
+	assert_eq!(
+		profile.session,
+		SessionInfo::ApiToken {
+			token_id: api_token.id,
+			name: "ci".to_string(),
+			scopes: None,
+			expires_at: None,
+		}
+	);
+}
+
+#[test]
+fn test_discord_default_avatars() {
+	assert_eq!(
+		discord_user(None, "1337").avatar_url(),
+		"https://cdn.discordapp.com/embed/avatars/2.png"
+	);
+	// (80351110224678912 >> 22) % 6
+	assert_eq!(
+		discord_user(None, "0").avatar_url(),
+		"https://cdn.discordapp.com/embed/avatars/5.png"
+	);
+	assert_eq!(
+		discord_user(Some("1269e74af4df7417b13759eae50c83dc"), "0").avatar_url(),
+		"https://cdn.discordapp.com/avatars/80351110224678912/1269e74af4df7417b13759eae50c83dc.png"
+	);
+}
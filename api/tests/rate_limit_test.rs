@@ -0,0 +1,115 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{extract::ConnectInfo, middleware::from_fn_with_state, Router};
+use hyper::{header::RETRY_AFTER, Body, Request, StatusCode};
+use sabi_api::{config::RateLimit, rate_limit, services::auth::routes};
+use tower::ServiceExt;
+
+mod common;
+
+fn create_router() -> Router {
+	let mut state = common::create_state();
+	let mut config = (*state.config).clone();
+	config.rate_limit.auth_login = RateLimit {
+		burst: 2,
+		per_minute: 1,
+	};
+	// Callbacks would reach the providers, so never let them through
+	config.rate_limit.auth_callback = RateLimit {
+		burst: 0,
+		per_minute: 1,
+	};
+	state.config = Arc::new(config);
+	Router::new()
+		.nest(
+			"/auth",
+			routes().layer(from_fn_with_state(state.clone(), rate_limit::auth)),
+		)
+		.with_state(state)
+}
+
+async fn get_from(
+	app: &Router,
+	uri: &str,
+	client: [u8; 4],
+) -> hyper::Response<axum::body::BoxBody> {
+	let request = Request::builder()
+		.method("GET")
+		.uri(uri)
+		.extension(ConnectInfo(SocketAddr::from((client, 40000))))
+		.body(Body::empty())
+		.unwrap();
+	app.clone().oneshot(request).await.unwrap()
+}
+
+async fn get(app: &Router, uri: &str) -> hyper::Response<axum::body::BoxBody> {
+	get_from(app, uri, [127, 0, 0, 1]).await
+}
+
+#[tokio::test]
+async fn test_rate_limit_auth_login() {
+	let app = create_router();
+
+	for _ in 0..2 {
+		assert_eq!(
+			get(&app, "/auth/discord").await.status(),
+			StatusCode::SEE_OTHER
+		);
+	}
+	let response = get(&app, "/auth/discord").await;
+	assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+	assert_eq!(response.headers()[RETRY_AFTER], "60");
+	let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+	assert_eq!(body, "{\"error\":\"too many requests\"}");
+}
+
+#[tokio::test]
+async fn test_rate_limit_buckets_are_per_route_and_client() {
+	let app = create_router();
+
+	for _ in 0..2 {
+		get(&app, "/auth/discord").await;
+	}
+	assert_eq!(
+		get(&app, "/auth/discord").await.status(),
+		StatusCode::TOO_MANY_REQUESTS
+	);
+	assert_eq!(
+		get(&app, "/auth/google").await.status(),
+		StatusCode::SEE_OTHER
+	);
+	assert_eq!(
+		get_from(&app, "/auth/discord", [10, 0, 0, 2])
+			.await
+			.status(),
+		StatusCode::SEE_OTHER
+	);
+}
+
+#[tokio::test]
+async fn test_rate_limit_requires_the_client_address() {
+	let app = create_router();
+
+	let request = Request::builder()
+		.uri("/auth/google")
+		.body(Body::empty())
+		.unwrap();
+	let response = app.clone().oneshot(request).await.unwrap();
+	assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[tokio::test]
+async fn test_rate_limit_groups_are_independent() {
+	let app = create_router();
+
+	assert_eq!(
+		get(&app, "/auth/discord/authorized?code=a&state=b")
+			.await
+			.status(),
+		StatusCode::TOO_MANY_REQUESTS
+	);
+	assert_eq!(
+		get(&app, "/auth/google").await.status(),
+		StatusCode::SEE_OTHER
+	);
+}
@@ -0,0 +1,100 @@
+use std::{path::PathBuf, time::Duration};
+
+use axum::Router;
+use hyper::{header::COOKIE, Body, Request, StatusCode};
+use sabi_api::{
+	memory_store::{MemoryStore, SharedMemoryStore},
+	services::auth::routes,
+	sql_store::SqlStore,
+};
+use tower::ServiceExt;
+
+mod common;
+
+/// SQLite database file, deleted once the test is over
+struct TestDatabase {
+	path: PathBuf,
+}
+
+impl TestDatabase {
+	fn new(name: &str) -> Self {
+		let path = std::env::temp_dir().join(format!("sabi-{}-{}.db", name, std::process::id()));
+		let database = Self { path };
+		database.remove();
+		database
+	}
+
+	fn url(&self) -> String {
+		format!("sqlite://{}?mode=rwc", self.path.display())
+	}
+
+	async fn open(&self) -> SqlStore {
+		SqlStore::new(&self.url()).await.unwrap()
+	}
+
+	fn remove(&self) {
+		for suffix in ["", "-shm", "-wal"] {
+			let _ = std::fs::remove_file(format!("{}{}", self.path.display(), suffix));
+		}
+	}
+}
+
+impl Drop for TestDatabase {
+	fn drop(&mut self) {
+		self.remove();
+	}
+}
+
+#[tokio::test]
+async fn test_remove_expired_rows() {
+	let database = TestDatabase::new("sweep");
+	let store = database.open().await;
+	store
+		.set_value("short", "value", Some(Duration::from_millis(10)))
+		.await
+		.unwrap();
+	store.set_value("long", "value", None).await.unwrap();
+	tokio::time::sleep(Duration::from_millis(20)).await;
+
+	assert_eq!(store.remove_expired().await.unwrap(), 1);
+	assert_eq!(store.remove_expired().await.unwrap(), 0);
+	assert!(store.get_value("long").await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn test_data_survives_reopening() {
+	let database = TestDatabase::new("reopen");
+	let store = database.open().await;
+	store.set_value("key", "value", None).await.unwrap();
+	store.close().await;
+
+	// Migrations that already ran are skipped
+	let store = database.open().await;
+	assert_eq!(
+		store.get_value("key").await.unwrap(),
+		Some("value".to_string())
+	);
+}
+
+#[tokio::test]
+async fn test_login_with_sql_store() {
+	let database = TestDatabase::new("login");
+	let mut state = common::create_state();
+	state.memory_store = SharedMemoryStore::new(database.open().await);
+	let cookie = common::login(&state, &common::create_user("user@example.com")).await;
+
+	let app = Router::new().nest("/auth", routes()).with_state(state);
+	let request = Request::builder()
+		.uri("/auth/me")
+		.header(COOKIE, cookie)
+		.body(Body::empty())
+		.unwrap();
+	let response = app.oneshot(request).await.unwrap();
+	assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_sql_store_conformance() {
+	let database = TestDatabase::new("conformance");
+	common::store_conformance::run(SharedMemoryStore::new(database.open().await)).await;
+}
@@ -0,0 +1,47 @@
+use std::time::Duration;
+
+use sabi_api::{
+	config::Config,
+	in_memory_store::InMemoryStore,
+	memory_store::{RedisStore, SharedMemoryStore},
+};
+
+mod common;
+
+use common::store_conformance;
+
+/// Server the Redis checks run against, skipped when it cannot be reached
+const TEST_REDIS_URL: &str = "redis://127.0.0.1:6379/";
+
+#[tokio::test]
+async fn test_in_memory_store_conformance() {
+	store_conformance::run(SharedMemoryStore::new(InMemoryStore::new())).await;
+}
+
+#[tokio::test]
+async fn test_redis_store_conformance() {
+	let url = std::env::var("TEST_REDIS_URL").unwrap_or_else(|_| TEST_REDIS_URL.to_string());
+	let mut config = Config::from_params("test".to_string());
+	config.redis_pool.connect_timeout = Duration::from_millis(200);
+	config.redis_pool.startup_timeout = Duration::from_millis(500);
+	// A prefix of its own keeps the checks, and `clear_store`, away from any other data
+	let prefix = format!("sabi:conformance:{}:", std::process::id());
+	let store = match RedisStore::new(
+		url.clone(),
+		&prefix,
+		&config.redis_topology,
+		&config.redis_pool,
+	)
+	.await
+	{
+		Ok(store) => store,
+		Err(e) => {
+			eprintln!(
+				"Skipping the Redis conformance checks, {} is unavailable: {}",
+				url, e
+			);
+			return;
+		}
+	};
+	store_conformance::run(SharedMemoryStore::new(store)).await;
+}
@@ -0,0 +1,166 @@
+use std::time::Duration;
+
+use async_session::{Session, SessionStore};
+use axum::{routing::get, Router};
+use hyper::{
+	header::{AUTHORIZATION, CONTENT_TYPE, COOKIE},
+	Body, Request, StatusCode,
+};
+use sabi_api::{
+	config::RateLimit,
+	memory_store::{MemoryStore, SharedMemoryStore, StoreError, StoreResult},
+	rate_limit::RateLimitDecision,
+	services::auth::{routes, User},
+	AppState,
+};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+
+mod common;
+
+/// A store whose backend cannot be reached
+#[derive(Clone, Debug)]
+struct UnavailableStore;
+
+fn unavailable<T>() -> StoreResult<T> {
+	Err(StoreError::Unavailable("connection refused".to_string()))
+}
+
+#[async_trait::async_trait]
+impl SessionStore for UnavailableStore {
+	async fn load_session(&self, _cookie_value: String) -> async_session::Result<Option<Session>> {
+		Ok(unavailable()?)
+	}
+
+	async fn store_session(&self, _session: Session) -> async_session::Result<Option<String>> {
+		Ok(unavailable()?)
+	}
+
+	async fn destroy_session(&self, _session: Session) -> async_session::Result {
+		Ok(unavailable()?)
+	}
+
+	async fn clear_store(&self) -> async_session::Result {
+		Ok(unavailable()?)
+	}
+}
+
+#[async_trait::async_trait]
+impl MemoryStore for UnavailableStore {
+	async fn take_rate_limit_token(
+		&self,
+		_key: &str,
+		_limit: RateLimit,
+	) -> StoreResult<RateLimitDecision> {
+		unavailable()
+	}
+
+	async fn get_value(&self, _key: &str) -> StoreResult<Option<String>> {
+		unavailable()
+	}
+
+	async fn set_value(&self, _key: &str, _value: &str, _ttl: Option<Duration>) -> StoreResult {
+		unavailable()
+	}
+
+	async fn set_value_if_absent(
+		&self,
+		_key: &str,
+		_value: &str,
+		_ttl: Option<Duration>,
+	) -> StoreResult<bool> {
+		unavailable()
+	}
+
+	async fn delete_value(&self, _key: &str) -> StoreResult {
+		unavailable()
+	}
+
+	async fn increment_value(
+		&self,
+		_key: &str,
+		_delta: i64,
+		_ttl: Option<Duration>,
+	) -> StoreResult<i64> {
+		unavailable()
+	}
+
+	async fn take_value(&self, _key: &str) -> StoreResult<Option<String>> {
+		unavailable()
+	}
+
+	async fn push_value(&self, _key: &str, _value: &str) -> StoreResult {
+		unavailable()
+	}
+
+	async fn list_values(&self, _key: &str) -> StoreResult<Vec<String>> {
+		unavailable()
+	}
+}
+
+fn create_router(state: AppState) -> Router {
+	Router::new()
+		.route("/whoami", get(|user: User| async move { user.email }))
+		.nest("/auth", routes())
+		.with_state(state)
+}
+
+fn unavailable_state() -> AppState {
+	let mut state = common::create_state();
+	state.memory_store = SharedMemoryStore::new(UnavailableStore);
+	state
+}
+
+async fn get_with(app: &Router, uri: &str, header: (&str, &str)) -> (StatusCode, Vec<u8>) {
+	let request = Request::builder()
+		.uri(uri)
+		.header(header.0, header.1)
+		.body(Body::empty())
+		.unwrap();
+	let response = app.clone().oneshot(request).await.unwrap();
+	let status = response.status();
+	let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+	(status, body.to_vec())
+}
+
+#[tokio::test]
+async fn test_unavailable_store_is_reported() {
+	let app = create_router(unavailable_state());
+	for (uri, header) in [
+		("/whoami", (COOKIE.as_str(), "SESSION=abc")),
+		("/auth/tokens", (AUTHORIZATION.as_str(), "Bearer abc")),
+		("/auth/me", (COOKIE.as_str(), "SESSION=abc")),
+		("/auth/logout", (COOKIE.as_str(), "SESSION=abc")),
+	] {
+		let (status, body) = get_with(&app, uri, header).await;
+		assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}", uri);
+		let body: Value = serde_json::from_slice(&body).unwrap();
+		assert_eq!(body, json!({ "error": "service unavailable" }));
+	}
+}
+
+#[tokio::test]
+async fn test_unavailable_store_is_reported_for_login_links() {
+	let app = create_router(unavailable_state());
+	let request = Request::builder()
+		.method("POST")
+		.uri("/auth/email")
+		.header(CONTENT_TYPE, "application/json")
+		.body(Body::from(
+			json!({ "email": "user@example.com" }).to_string(),
+		))
+		.unwrap();
+	let response = app.oneshot(request).await.unwrap();
+	assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn test_bad_cookies_are_no_session() {
+	let app = create_router(common::create_state());
+	for cookie in ["SESSION=garbage", "SESSION={\"id\":1}", "SESSION", "=;=;"] {
+		let (status, _) = get_with(&app, "/whoami", (COOKIE.as_str(), cookie)).await;
+		assert_eq!(status, StatusCode::TEMPORARY_REDIRECT, "{}", cookie);
+		let (status, _) = get_with(&app, "/auth/me", (COOKIE.as_str(), cookie)).await;
+		assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", cookie);
+	}
+}
@@ -0,0 +1,248 @@
+use std::sync::Arc;
+
+use axum::{routing::get, Router};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::{
+	header::{CONTENT_TYPE, COOKIE, SET_COOKIE},
+	Body, Request, Response, StatusCode,
+};
+use sabi_api::{
+	services::auth::{load_two_factor, login_session, routes, TwoFactorEnrollResponse, User},
+	AppState,
+};
+use serde_json::{json, Value};
+use totp_rs::{Secret, TOTP};
+use tower::ServiceExt;
+
+mod common;
+
+fn create_state(encryption_key: Option<String>) -> AppState {
+	let mut state = common::create_state();
+	let mut config = (*state.config).clone();
+	config.two_factor.encryption_key = encryption_key.map(Arc::new);
+	state.config = Arc::new(config);
+	state
+}
+
+fn create_router(state: AppState) -> Router {
+	Router::new()
+		.route("/whoami", get(|user: User| async move { user.email }))
+		.nest("/auth", routes())
+		.with_state(state)
+}
+
+async fn post(app: &Router, uri: &str, cookie: &str, body: Value) -> Response<axum::body::BoxBody> {
+	let request = Request::builder()
+		.method("POST")
+		.uri(uri)
+		.header(COOKIE, cookie)
+		.header(CONTENT_TYPE, "application/json")
+		.body(Body::from(body.to_string()))
+		.unwrap();
+	app.clone().oneshot(request).await.unwrap()
+}
+
+async fn whoami(app: &Router, cookie: &str) -> StatusCode {
+	let request = Request::builder()
+		.uri("/whoami")
+		.header(COOKIE, cookie)
+		.body(Body::empty())
+		.unwrap();
+	app.clone().oneshot(request).await.unwrap().status()
+}
+
+async fn enroll(app: &Router, cookie: &str) -> TwoFactorEnrollResponse {
+	let response = post(app, "/auth/2fa/enroll", cookie, json!({})).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+	serde_json::from_slice(&body).unwrap()
+}
+
+/// Code of the authenticator app, `steps` time steps from now
+fn code(enrollment: &TwoFactorEnrollResponse, steps: i64) -> String {
+	let secret = Secret::Encoded(enrollment.secret.clone())
+		.to_bytes()
+		.unwrap();
+	let totp = TOTP::new(
+		totp_rs::Algorithm::SHA1,
+		6,
+		1,
+		30,
+		secret,
+		Some("sabi".to_string()),
+		"user@example.com".to_string(),
+	)
+	.unwrap();
+	let now = std::time::SystemTime::now()
+		.duration_since(std::time::UNIX_EPOCH)
+		.unwrap()
+		.as_secs();
+	totp.generate(now.saturating_add_signed(steps * 30))
+}
+
+/// Log in through the same path as the login handlers, returning the `Cookie` header value
+async fn pending_login(state: &AppState, user: &User) -> String {
+	let session = login_session(state.memory_store.as_ref(), user)
+		.await
+		.unwrap();
+	let cookie = state
+		.memory_store
+		.store_session(session)
+		.await
+		.unwrap()
+		.unwrap();
+	format!("SESSION={}", cookie)
+}
+
+#[tokio::test]
+async fn test_two_factor_disabled_without_key() {
+	let state = create_state(None);
+	let app = create_router(state.clone());
+	let cookie = common::login(&state, &common::create_user("user@example.com")).await;
+
+	let response = post(&app, "/auth/2fa/enroll", &cookie, json!({})).await;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_two_factor_enrollment_and_login() {
+	let state = create_state(Some(STANDARD.encode([3; 32])));
+	let app = create_router(state.clone());
+	let user = common::create_user("user@example.com");
+	let cookie = common::login(&state, &user).await;
+
+	let enrollment = enroll(&app, &cookie).await;
+	assert!(enrollment
+		.otpauth_uri
+		.starts_with("otpauth://totp/sabi:user%40example.com?"));
+	assert_eq!(enrollment.recovery_codes.len(), 10);
+
+	// The secret is stored encrypted
+	let record = load_two_factor(state.memory_store.as_ref(), "user@example.com")
+		.await
+		.unwrap()
+		.unwrap();
+	assert!(!record.confirmed);
+	assert!(!record.encrypted_secret.contains(&enrollment.secret));
+
+	// Logins do not require the second step until the enrollment is confirmed
+	let unconfirmed_cookie = pending_login(&state, &user).await;
+	assert_eq!(whoami(&app, &unconfirmed_cookie).await, StatusCode::OK);
+
+	let response = post(
+		&app,
+		"/auth/2fa/confirm",
+		&cookie,
+		json!({ "code": "000000" }),
+	)
+	.await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	let response = post(
+		&app,
+		"/auth/2fa/confirm",
+		&cookie,
+		json!({ "code": code(&enrollment, 0) }),
+	)
+	.await;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	// Enrolling again is not allowed once confirmed
+	let response = post(&app, "/auth/2fa/enroll", &cookie, json!({})).await;
+	assert_eq!(response.status(), StatusCode::CONFLICT);
+
+	let pending_cookie = pending_login(&state, &user).await;
+	assert_eq!(
+		whoami(&app, &pending_cookie).await,
+		StatusCode::TEMPORARY_REDIRECT
+	);
+
+	let wrong_code = json!({ "code": "000000" });
+	let response = post(&app, "/auth/2fa/verify", &pending_cookie, wrong_code).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	// The code used to confirm the enrollment cannot be used again
+	let used_code = json!({ "code": code(&enrollment, 0) });
+	let response = post(&app, "/auth/2fa/verify", &pending_cookie, used_code).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	let next_code = json!({ "code": code(&enrollment, 1) });
+	let response = post(&app, "/auth/2fa/verify", &pending_cookie, next_code.clone()).await;
+	assert_eq!(response.status(), StatusCode::SEE_OTHER);
+	let set_cookie = response.headers()[SET_COOKIE].to_str().unwrap();
+	let full_cookie = set_cookie.split(';').next().unwrap();
+	assert_eq!(whoami(&app, full_cookie).await, StatusCode::OK);
+
+	// The pending session is gone
+	let response = post(&app, "/auth/2fa/verify", &pending_cookie, next_code.clone()).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	// Codes are single use, and earlier ones are refused once a later one was used
+	let pending_cookie = pending_login(&state, &user).await;
+	let response = post(&app, "/auth/2fa/verify", &pending_cookie, next_code).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	let previous_code = json!({ "code": code(&enrollment, -1) });
+	let response = post(&app, "/auth/2fa/verify", &pending_cookie, previous_code).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_two_factor_recovery_codes() {
+	let state = create_state(Some(STANDARD.encode([3; 32])));
+	let app = create_router(state.clone());
+	let user = common::create_user("user@example.com");
+	let cookie = common::login(&state, &user).await;
+	let enrollment = enroll(&app, &cookie).await;
+	let code = json!({ "code": code(&enrollment, 0) });
+	post(&app, "/auth/2fa/confirm", &cookie, code).await;
+
+	// Recovery codes are accepted regardless of their case
+	let recovery_code = json!({ "code": enrollment.recovery_codes[0].to_uppercase() });
+	let pending_cookie = pending_login(&state, &user).await;
+	let response = post(
+		&app,
+		"/auth/2fa/verify",
+		&pending_cookie,
+		recovery_code.clone(),
+	)
+	.await;
+	assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+	// Recovery codes can only be used once
+	let pending_cookie = pending_login(&state, &user).await;
+	let response = post(&app, "/auth/2fa/verify", &pending_cookie, recovery_code).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	// Even by concurrent requests
+	let recovery_code = json!({ "code": enrollment.recovery_codes[1] });
+	let first_cookie = pending_login(&state, &user).await;
+	let second_cookie = pending_login(&state, &user).await;
+	let (first, second) = tokio::join!(
+		post(
+			&app,
+			"/auth/2fa/verify",
+			&first_cookie,
+			recovery_code.clone()
+		),
+		post(&app, "/auth/2fa/verify", &second_cookie, recovery_code),
+	);
+	let mut statuses = [first.status(), second.status()];
+	statuses.sort();
+	assert_eq!(statuses, [StatusCode::SEE_OTHER, StatusCode::UNAUTHORIZED]);
+}
+
+#[tokio::test]
+async fn test_two_factor_reenrollment_replaces_recovery_codes() {
+	let state = create_state(Some(STANDARD.encode([3; 32])));
+	let app = create_router(state.clone());
+	let user = common::create_user("user@example.com");
+	let cookie = common::login(&state, &user).await;
+	let abandoned = enroll(&app, &cookie).await;
+	let enrollment = enroll(&app, &cookie).await;
+	let code = json!({ "code": code(&enrollment, 0) });
+	post(&app, "/auth/2fa/confirm", &cookie, code).await;
+
+	let pending_cookie = pending_login(&state, &user).await;
+	let recovery_code = json!({ "code": abandoned.recovery_codes[0] });
+	let response = post(&app, "/auth/2fa/verify", &pending_cookie, recovery_code).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}